@@ -0,0 +1,80 @@
+//! Measures how many connections a second a server accepts under churn,
+//! first with one accept thread and then with several. Every connection
+//! sends one byte, reads its echo and closes, so the server's accepting and
+//! dispatching is what limits the rate.
+//!
+//! Run it in release mode, optionally with the number of accept threads to
+//! compare against one, the number of connecting threads and the number of
+//! connections:
+//!
+//!     cargo run --release --example accept_rate -- 4 8 10000
+//!
+//! Each closed connection leaves a local port in `TIME_WAIT`, so keep the
+//! connection count well below the ephemeral port range.
+
+use echo_server_rs::{Server, ServerConfig};
+use std::env;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Starts a server with `accept_threads` accept threads and returns how long
+// `connections` connections, made from `clients` threads, took to be served.
+fn measure(accept_threads: usize, clients: usize, connections: usize) -> Duration {
+    let config = ServerConfig::builder()
+        .addr(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .accept_threads(accept_threads)
+        .pool_size(clients)
+        .build();
+    let server = Server::bind(config).expect("could not start the server");
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown();
+    let serving = thread::spawn(move || server.run());
+
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for _ in 0..clients {
+            scope.spawn(move || {
+                for _ in 0..connections / clients {
+                    let mut stream = TcpStream::connect(addr).expect("could not connect");
+                    stream.write_all(b"x").expect("sending failed");
+                    stream
+                        .read_exact(&mut [0])
+                        .expect("reading the echo failed");
+                }
+            });
+        }
+    });
+    let elapsed = start.elapsed();
+
+    shutdown.request();
+    serving.join().unwrap().expect("the server failed");
+    elapsed
+}
+
+fn main() {
+    let args: Vec<usize> = env::args()
+        .skip(1)
+        .map(|arg| arg.parse().expect("arguments must be numbers"))
+        .collect();
+    let accept_threads = args.first().copied().unwrap_or(4).max(1);
+    let clients = args.get(1).copied().unwrap_or(8).max(1);
+    let connections = args.get(2).copied().unwrap_or(10_000) / clients * clients;
+    println!(
+        "{} connecting threads, {} connections, {} cores",
+        clients,
+        connections,
+        thread::available_parallelism().map_or(1, |cores| cores.get())
+    );
+
+    for &threads in &[1, accept_threads] {
+        let elapsed = measure(threads, clients, connections);
+        println!(
+            "{} accept threads: {:.0} connections/s, {:?} in total",
+            threads,
+            connections as f64 / elapsed.as_secs_f64(),
+            elapsed
+        );
+    }
+}
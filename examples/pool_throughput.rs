@@ -0,0 +1,66 @@
+//! Measures how many tiny tasks a second `ThreadPool` gets through, each
+//! doing next to nothing, so what is measured is the cost of queueing and
+//! handing out tasks. Run it on two revisions to compare pool designs.
+//!
+//! Run it in release mode, optionally with the worker count, the number of
+//! submitting threads and the number of tasks:
+//!
+//!     cargo run --release --example pool_throughput -- 8 4 1000000
+
+use echo_server_rs::ThreadPool;
+use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Submits `tasks` tasks that each bump a counter to `pool` from `submitters`
+// threads, and returns how long it took until all of them ran.
+fn measure(pool: &ThreadPool, submitters: usize, tasks: usize) -> Duration {
+    let done = Arc::new(AtomicUsize::new(0));
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for _ in 0..submitters {
+            let done = &done;
+            scope.spawn(move || {
+                for _ in 0..tasks / submitters {
+                    let done = Arc::clone(done);
+                    pool.execute(move || {
+                        done.fetch_add(1, Ordering::Relaxed);
+                    })
+                    .expect("the pool stopped accepting tasks");
+                }
+            });
+        }
+    });
+    let total = tasks / submitters * submitters;
+    while done.load(Ordering::Relaxed) < total {
+        thread::yield_now();
+    }
+    start.elapsed()
+}
+
+fn main() {
+    let args: Vec<usize> = env::args()
+        .skip(1)
+        .map(|arg| arg.parse().expect("arguments must be numbers"))
+        .collect();
+    let workers = args.first().copied().unwrap_or(8);
+    let submitters = args.get(1).copied().unwrap_or(1).max(1);
+    let tasks = args.get(2).copied().unwrap_or(1_000_000);
+    println!(
+        "{} workers, {} submitting threads, {} tasks, {} cores",
+        workers,
+        submitters,
+        tasks,
+        thread::available_parallelism().map_or(1, |cores| cores.get())
+    );
+
+    let pool = ThreadPool::new(workers).expect("could not start the pool");
+    let elapsed = measure(&pool, submitters, tasks);
+    println!(
+        "{:.0} tasks/s, {:?} in total",
+        tasks as f64 / elapsed.as_secs_f64(),
+        elapsed
+    );
+}
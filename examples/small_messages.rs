@@ -0,0 +1,64 @@
+//! Measures how many tiny messages a second a running echo server gets
+//! through when a client pipelines them, which is where the syscalls spent
+//! per read and per echo show. Point it at servers built from two revisions
+//! to compare them.
+//!
+//! Run it in release mode against a server's address, optionally with the
+//! number of messages and their size in bytes:
+//!
+//!     cargo run --release --example small_messages -- 127.0.0.1:7878 400000 5
+
+use std::env;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::thread;
+use std::time::Instant;
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let addr = args
+        .next()
+        .unwrap_or_else(|| String::from("127.0.0.1:7878"));
+    let number = |arg: Option<String>, default| {
+        arg.map_or(default, |arg| {
+            arg.parse()
+                .expect("arguments after the address must be numbers")
+        })
+    };
+    let messages: usize = number(args.next(), 400_000);
+    let size: usize = number(args.next(), 5);
+
+    let mut stream = TcpStream::connect(&addr).expect("could not connect to the server");
+    stream.set_nodelay(true).unwrap();
+    let mut reader = stream.try_clone().unwrap();
+    let total = messages * size;
+
+    let start = Instant::now();
+    let reading = thread::spawn(move || {
+        let mut buffer = vec![0; 1 << 16];
+        let mut received = 0;
+        while received < total {
+            match reader.read(&mut buffer).expect("reading an echo failed") {
+                0 => panic!("the server closed the connection early"),
+                read => received += read,
+            }
+        }
+    });
+    // Sent a few kilobytes at a time, so the client is not what limits the
+    // rate, while the server still sees a stream of tiny messages.
+    let payload: Vec<u8> = (0..total).map(|i| b'a' + (i % size) as u8).collect();
+    for chunk in payload.chunks(4096) {
+        stream.write_all(chunk).expect("sending failed");
+    }
+    reading.join().unwrap();
+    let elapsed = start.elapsed();
+    let _ = stream.shutdown(Shutdown::Both);
+
+    println!(
+        "{} messages of {} bytes, {:.2}M messages/s, {:?} in total",
+        messages,
+        size,
+        messages as f64 / elapsed.as_secs_f64() / 1e6,
+        elapsed
+    );
+}
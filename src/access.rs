@@ -0,0 +1,38 @@
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+/// Decides which peers may connect based on the ranges they fall into.
+///
+/// A peer in any denied range is always refused. Otherwise it is admitted
+/// if the allowlist is empty or it falls into an allowed range.
+#[derive(Debug, Clone, Default)]
+pub struct AccessList {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl AccessList {
+    pub fn allow(&mut self, net: IpNet) {
+        self.allow.push(net);
+    }
+
+    pub fn deny(&mut self, net: IpNet) {
+        self.deny.push(net);
+    }
+
+    /// Whether no ranges were configured, so every peer is admitted.
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        // Dual-stack listeners report IPv4 clients as IPv4-mapped IPv6
+        // addresses, which should still match IPv4 ranges.
+        let ip = ip.to_canonical();
+
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
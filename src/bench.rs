@@ -0,0 +1,170 @@
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How long a client waits for an echo before giving up on the server.
+const ECHO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Load generated by [`run`].
+#[derive(Debug, Clone)]
+pub struct BenchConfig {
+    /// Clients connected at the same time.
+    pub connections: usize,
+    /// Bytes every client sends before waiting for them to come back.
+    pub payload_size: usize,
+    /// How long the clients keep sending.
+    pub duration: Duration,
+}
+
+impl Default for BenchConfig {
+    fn default() -> BenchConfig {
+        BenchConfig {
+            connections: 8,
+            payload_size: 1024,
+            duration: Duration::from_secs(5),
+        }
+    }
+}
+
+/// What a benchmark run measured across all of its clients.
+#[derive(Debug, Clone)]
+pub struct BenchReport {
+    pub connections: usize,
+    pub round_trips: u64,
+    /// Payload bytes that came back, counted once per round trip.
+    pub bytes: u64,
+    pub elapsed: Duration,
+    /// Median and 99th percentile of the round trip times.
+    pub p50: Duration,
+    pub p99: Duration,
+}
+
+impl BenchReport {
+    pub fn megabytes_per_second(&self) -> f64 {
+        self.bytes as f64 / 1_000_000.0 / self.elapsed.as_secs_f64()
+    }
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} connections, {} round trips, {} bytes echoed in {:.2?}, {:.2} MB/s, p50 {:.2?}, p99 {:.2?}",
+            self.connections,
+            self.round_trips,
+            self.bytes,
+            self.elapsed,
+            self.megabytes_per_second(),
+            self.p50,
+            self.p99
+        )
+    }
+}
+
+/// Connects `config.connections` clients to the echo server at `addr`, each
+/// of which sends a payload, waits until it has been echoed back and sends
+/// it again until `config.duration` is over.
+///
+/// Fails if a client cannot connect, times out waiting for an echo or reads
+/// back anything but its payload, so the server has to echo bytes
+/// unchanged, as raw mode without a transform does.
+pub fn run(addr: SocketAddr, config: &BenchConfig) -> io::Result<BenchReport> {
+    assert!(config.connections > 0 && config.payload_size > 0);
+
+    let payload: Arc<[u8]> = (0..config.payload_size)
+        .map(|i| b'a' + (i % 26) as u8)
+        .collect();
+    let streams = (0..config.connections)
+        .map(|_| connect(addr))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    // The clients start together once all of them are connected, so the
+    // connection setup is not measured.
+    let start = Arc::new(Barrier::new(config.connections + 1));
+    let clients: Vec<_> = streams
+        .into_iter()
+        .map(|stream| {
+            let (start, payload, duration) =
+                (Arc::clone(&start), Arc::clone(&payload), config.duration);
+            thread::spawn(move || {
+                start.wait();
+                echo_until(stream, &payload, Instant::now() + duration)
+            })
+        })
+        .collect();
+    start.wait();
+    let started = Instant::now();
+
+    let mut latencies = Vec::new();
+    let mut failure = None;
+    for client in clients {
+        match client.join() {
+            Ok(Ok(measured)) => latencies.extend(measured),
+            Ok(Err(e)) => failure = failure.or(Some(e)),
+            Err(_) => {
+                failure = failure.or_else(|| Some(io::Error::other("a benchmark client panicked")))
+            }
+        }
+    }
+    let elapsed = started.elapsed();
+    if let Some(e) = failure {
+        return Err(e);
+    }
+
+    latencies.sort_unstable();
+    let percentile = |p: usize| match latencies.len() {
+        0 => Duration::ZERO,
+        len => latencies[(len - 1) * p / 100],
+    };
+    let round_trips = latencies.len() as u64;
+    Ok(BenchReport {
+        connections: config.connections,
+        round_trips,
+        bytes: round_trips * config.payload_size as u64,
+        elapsed,
+        p50: percentile(50),
+        p99: percentile(99),
+    })
+}
+
+fn connect(addr: SocketAddr) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect(addr)?;
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(ECHO_TIMEOUT))?;
+    stream.set_write_timeout(Some(ECHO_TIMEOUT))?;
+    Ok(stream)
+}
+
+// Echoes `payload` over `stream` until `deadline`, returning the time every
+// round trip took.
+fn echo_until(
+    mut stream: TcpStream,
+    payload: &[u8],
+    deadline: Instant,
+) -> io::Result<Vec<Duration>> {
+    let mut echoed = vec![0u8; payload.len()];
+    let mut latencies = Vec::new();
+    while Instant::now() < deadline {
+        let sent = Instant::now();
+        stream.write_all(payload)?;
+        stream.read_exact(&mut echoed).map_err(|e| match e.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => io::Error::new(
+                ErrorKind::TimedOut,
+                format!("no echo within {:?}", ECHO_TIMEOUT),
+            ),
+            _ => e,
+        })?;
+        latencies.push(sent.elapsed());
+
+        if echoed != payload {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "the server did not echo the payload back unchanged",
+            ));
+        }
+    }
+    Ok(latencies)
+}
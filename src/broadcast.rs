@@ -0,0 +1,135 @@
+use crate::connection::{delay_echo, retry_interrupted, EchoError};
+use crate::span;
+use crate::transform::Transform;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+/// Chunks a subscriber may fall behind by before it is disconnected.
+pub const SUBSCRIBER_QUEUE_SIZE: usize = 64;
+
+type Closer = Box<dyn Fn() + Send>;
+
+/// The connections of a `Mode::Broadcast` server, each of which receives
+/// every chunk any of them sends, its own included.
+///
+/// Every subscriber is written to by a thread of its own, fed through a
+/// bounded queue, so a client that reads slowly never holds up the sender
+/// or the other subscribers. One that falls `SUBSCRIBER_QUEUE_SIZE` chunks
+/// behind is disconnected rather than silently missing chunks, and one
+/// whose writes fail is dropped from the room.
+#[derive(Default)]
+pub struct Room {
+    subscribers: Mutex<HashMap<u64, Subscriber>>,
+}
+
+struct Subscriber {
+    queue: SyncSender<Arc<[u8]>>,
+    close: Closer,
+}
+
+impl Room {
+    /// Adds connection `id`, writing whatever is broadcast to `writer`, a
+    /// second handle to its socket, until the returned guard is dropped.
+    /// `close` ends the connection once it falls too far behind.
+    pub fn subscribe<W, F>(
+        self: &Arc<Self>,
+        id: u64,
+        mut writer: W,
+        close: F,
+    ) -> io::Result<Subscription>
+    where
+        W: Write + Send + 'static,
+        F: Fn() + Send + 'static,
+    {
+        let (queue, chunks) = mpsc::sync_channel::<Arc<[u8]>>(SUBSCRIBER_QUEUE_SIZE);
+        thread::Builder::new()
+            .name(format!("broadcast-{}", id))
+            .spawn(move || {
+                let _span = span::enter(id);
+                for chunk in chunks {
+                    if let Err(e) = writer.write_all(&chunk) {
+                        debug!("Stopped broadcasting to the connection due to: {:?}", e);
+                        break;
+                    }
+                }
+            })?;
+
+        self.lock_subscribers().insert(
+            id,
+            Subscriber {
+                queue,
+                close: Box::new(close),
+            },
+        );
+        Ok(Subscription {
+            room: Arc::clone(self),
+            id,
+        })
+    }
+
+    /// Queues `chunk` for every subscriber and returns how many are left
+    /// in the room.
+    pub fn broadcast(&self, chunk: &[u8]) -> usize {
+        let chunk: Arc<[u8]> = Arc::from(chunk);
+        let mut subscribers = self.lock_subscribers();
+        subscribers.retain(
+            |id, subscriber| match subscriber.queue.try_send(Arc::clone(&chunk)) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!(
+                        "Disconnecting connection {}, it fell {} chunks behind the broadcast",
+                        id, SUBSCRIBER_QUEUE_SIZE
+                    );
+                    (subscriber.close)();
+                    false
+                }
+                // Its writer gave up after a failed write.
+                Err(TrySendError::Disconnected(_)) => false,
+            },
+        );
+        subscribers.len()
+    }
+
+    fn lock_subscribers(&self) -> MutexGuard<'_, HashMap<u64, Subscriber>> {
+        self.subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Keeps a connection subscribed to a `Room` while alive.
+pub struct Subscription {
+    room: Arc<Room>,
+    id: u64,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.room.lock_subscribers().remove(&self.id);
+    }
+}
+
+/// Reads one chunk from `stream` and broadcasts `transform`'s output for it
+/// to every subscriber of `room` after sleeping for `delay`, returning the
+/// number of bytes read.
+pub fn publish<S: Read>(
+    stream: &mut S,
+    buffer: &mut [u8],
+    room: &Room,
+    transform: &Transform,
+    delay: Duration,
+) -> Result<usize, EchoError> {
+    let read_bytes = retry_interrupted(|| stream.read(buffer)).map_err(EchoError::Read)?;
+    if read_bytes == 0 {
+        return Ok(0);
+    }
+
+    delay_echo(delay);
+    room.broadcast(&transform(&buffer[..read_bytes]));
+    Ok(read_bytes)
+}
@@ -0,0 +1,72 @@
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+
+/// Buffers both directions of a duplex stream.
+///
+/// Reads are served from an internal read buffer and writes are collected
+/// in a write buffer. Pending writes are flushed as soon as no buffered input
+/// is left, and before any read that has to go to the underlying stream, so a
+/// client waiting for its echo never waits on the server's own buffering.
+pub struct BufStream<S: Read + Write> {
+    inner: BufReader<FlushOnRead<S>>,
+}
+
+impl<S: Read + Write> BufStream<S> {
+    pub fn new(stream: S) -> BufStream<S> {
+        BufStream {
+            inner: BufReader::new(FlushOnRead(BufWriter::new(stream))),
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        self.inner.get_ref().0.get_ref()
+    }
+
+    /// Reading from or writing to the returned stream directly bypasses the
+    /// buffers and can reorder data.
+    pub fn get_mut(&mut self) -> &mut S {
+        self.writer().get_mut()
+    }
+
+    fn writer(&mut self) -> &mut BufWriter<S> {
+        &mut self.inner.get_mut().0
+    }
+}
+
+impl<S: Read + Write> Read for BufStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<S: Read + Write> BufRead for BufStream<S> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.inner.consume(amount)
+    }
+}
+
+impl<S: Read + Write> Write for BufStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer().write(buf)?;
+        if self.inner.buffer().is_empty() {
+            self.writer().flush()?;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer().flush()
+    }
+}
+
+struct FlushOnRead<S: Write>(BufWriter<S>);
+
+impl<S: Read + Write> Read for FlushOnRead<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.flush()?;
+        self.0.get_mut().read(buf)
+    }
+}
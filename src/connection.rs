@@ -0,0 +1,982 @@
+use crate::broadcast;
+use crate::buffered::BufStream;
+use crate::duplex::{self, Duplex};
+use crate::handler::{echo_handled, finish_handled};
+use crate::http;
+use crate::limit::Throttle;
+use crate::modes;
+use crate::proxy;
+use crate::server::{Mode, ServerConfig};
+use crate::span;
+use crate::stats::{Outcome, ServerStats};
+use crate::websocket;
+use log::{debug, error, info, warn};
+use std::collections::hash_map::RandomState;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{self, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MAX_INTERRUPTED_RETRIES: u32 = 16;
+
+// RFC 864 lines are 72 printable characters followed by CRLF.
+const CHARGEN_LINE: u64 = 74;
+
+/// Totals accumulated over the lifetime of a single connection.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionStats {
+    pub bytes_echoed: u64,
+    /// Bytes read but deliberately not echoed because of the drop rate.
+    pub bytes_dropped: u64,
+    pub reads: u64,
+    /// Kind of the error that ended the connection, if it did not end with
+    /// the peer closing it or the byte limit being reached.
+    pub error: Option<ErrorKind>,
+}
+
+impl ConnectionStats {
+    /// How the connection ended, telling clients that went away abruptly
+    /// apart from genuine errors.
+    pub fn outcome(&self) -> Outcome {
+        match self.error {
+            None => Outcome::Closed,
+            Some(kind) if is_disconnect(kind) => Outcome::Disconnected,
+            Some(_) => Outcome::Failed,
+        }
+    }
+
+    /// Bytes read from the connection, echoed or not.
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_echoed + self.bytes_dropped
+    }
+}
+
+/// Applies the TCP-specific socket options to an accepted stream before it
+/// is handed to `handle`.
+pub fn configure_tcp_stream(
+    stream: &TcpStream,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    nodelay: bool,
+) -> io::Result<()> {
+    // Nagle's algorithm holds back small segments until the previous one is
+    // acknowledged, which interacts badly with delayed ACKs on clients that
+    // write a request in several pieces before waiting for the echo.
+    if let Err(e) = stream.set_nodelay(nodelay) {
+        warn!("Could not configure TCP_NODELAY due to: {:?}", e);
+    }
+
+    stream.set_read_timeout(read_timeout)?;
+    stream.set_write_timeout(write_timeout)
+}
+
+/// A stream whose write side can be shut down on its own, so the peer sees
+/// end of stream while the connection stays open for reading.
+pub trait HalfClose {
+    fn shutdown_write(&mut self) -> io::Result<()>;
+}
+
+impl HalfClose for TcpStream {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.shutdown(net::Shutdown::Write)
+    }
+}
+
+#[cfg(unix)]
+impl HalfClose for UnixStream {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.shutdown(net::Shutdown::Write)
+    }
+}
+
+/// Echoes everything read from `stream` back to it, in chunks or lines as
+/// selected by the configured `Mode` and passed through the configured
+/// transform, until the peer closes the connection, a read or write times
+/// out, the connection goes idle, the connection's byte limit is reached, or
+/// an I/O error occurs. Echoed bytes are also added to `server_stats` as
+/// they go, and the connection's `Outcome` once it ends.
+///
+/// `Mode::Discard` and `Mode::Reply` drop what they read and `Mode::Chargen`
+/// only writes; their read or written byte counts stand in for the echoed
+/// ones.
+///
+/// When the connection ends because the peer closed its side or the byte
+/// limit was reached, the write side is shut down once every echo has been
+/// flushed, so a half-closed client reads a clean end of stream. A client
+/// that stops receiving echoes, so writing them fails with a reset, broken
+/// pipe or zero bytes written, is closed as `Outcome::Disconnected` rather
+/// than counted as a failure.
+///
+/// Logs are tagged with the connection id current on this thread, as the
+/// server's accept loops set it, or a newly allocated one otherwise.
+pub fn handle<S: Read + Write + HalfClose>(
+    stream: S,
+    config: &ServerConfig,
+    server_stats: &ServerStats,
+) -> ConnectionStats {
+    let _span = match span::current() {
+        Some(_) => None,
+        None => Some(span::enter(span::next_id())),
+    };
+    let stats = serve(stream, None, config, server_stats);
+    server_stats.connection_ended(stats.outcome());
+    stats
+}
+
+/// Like `handle`, but in raw mode echoes through a writer thread of the
+/// connection's own, see `Duplex`, so a client can keep sending while its
+/// echoes are written. `writer` is a second handle to the socket of
+/// `stream`, and `close` shuts that socket down once a write fails.
+///
+/// This costs a thread per connection on top of the worker serving it.
+pub fn handle_duplex<S, W, F>(
+    stream: S,
+    writer: W,
+    close: F,
+    config: &ServerConfig,
+    server_stats: &ServerStats,
+) -> ConnectionStats
+where
+    S: Read + Write + HalfClose,
+    W: Write + Send + 'static,
+    F: FnOnce() + Send + 'static,
+{
+    let (id, _span) = match span::current() {
+        Some(id) => (id, None),
+        None => {
+            let id = span::next_id();
+            (id, Some(span::enter(id)))
+        }
+    };
+    let peer = peer_name();
+    let stats = match Duplex::spawn(id, writer, close) {
+        Ok(duplex) => serve(stream, Some(duplex), config, server_stats),
+        Err(e) => {
+            warn!(
+                "Closing connection from {}, could not start its writer: {}",
+                peer, e
+            );
+            ConnectionStats {
+                error: Some(e.kind()),
+                ..ConnectionStats::default()
+            }
+        }
+    };
+    server_stats.connection_ended(stats.outcome());
+    stats
+}
+
+fn serve<S: Read + Write + HalfClose>(
+    stream: S,
+    duplex: Option<Duplex>,
+    config: &ServerConfig,
+    server_stats: &ServerStats,
+) -> ConnectionStats {
+    let peer = peer_name();
+    let transform = &*config.transform;
+    let max_bytes = config.max_connection_bytes;
+    let mut throttle = Throttle::new(config.max_bytes_per_second);
+    let mut stats = ConnectionStats::default();
+    let mut buffer = vec![0u8; config.buffer_size];
+    let mut message = Vec::new();
+    let mut stream = BufStream::new(IdleTimeout::new(stream, config.idle_timeout));
+    let deadline = config
+        .max_lifetime
+        .map(|lifetime| Instant::now() + lifetime);
+    let outlived = || deadline.is_some_and(|deadline| Instant::now() >= deadline);
+    // Start and received byte count of the current minimum throughput
+    // window.
+    let mut window = (Instant::now(), 0);
+    let mut finished = false;
+    let mut keep_alive = true;
+    let mut jitter = config.echo_jitter.map(|(min, max)| Jitter::new(min, max));
+    let mut drops = Some(Rng::new()).filter(|_| config.drop_rate > 0.0);
+    // Duplex connections echo through their writer thread instead.
+    let mut handler = match &config.handler {
+        Some(factory) => Some(factory()),
+        None if duplex.is_none() => modes::for_mode(config),
+        None => None,
+    };
+
+    if config.proxy_protocol {
+        match proxy::read_header(&mut stream) {
+            Ok(Some(client)) => info!("Serving connection proxied for {}", client),
+            Ok(None) => info!("Serving connection proxied for an unknown client"),
+            Err(e) => {
+                error!(
+                    "Closing connection from {}, invalid PROXY protocol header: {}",
+                    peer, e
+                );
+                stats.error = Some(e.kind());
+                return stats;
+            }
+        }
+    }
+
+    if let Some(banner) = &config.banner {
+        let greeted = stream
+            .write_all(banner.as_bytes())
+            .and_then(|()| stream.write_all(b"\r\n"))
+            .and_then(|()| stream.flush());
+        if let Err(e) = greeted {
+            warn!(
+                "Closing connection from {}, could not send the banner: {}",
+                peer, e
+            );
+            stats.error = Some(e.kind());
+            return stats;
+        }
+    }
+
+    if config.mode == Mode::WebSocket {
+        match websocket::accept(&mut stream, &mut message, config.http_limits) {
+            Ok(true) => debug!("Completed the WebSocket handshake"),
+            Ok(false) => return stats,
+            Err(e) => {
+                warn!(
+                    "Closing connection from {}, WebSocket handshake failed: {}",
+                    peer, e
+                );
+                stats.error = Some(e.io_error().kind());
+                // Deliver the `400` explaining the rejection.
+                let _ = stream.flush();
+                return stats;
+            }
+        }
+    }
+
+    loop {
+        if !keep_alive {
+            debug!(
+                "Closing connection from {}, the client did not ask to keep it alive",
+                peer
+            );
+            finished = true;
+            break;
+        }
+
+        if outlived() {
+            info!(
+                "Closing connection from {}, it reached the maximum lifetime of {:?}",
+                peer,
+                config.max_lifetime.unwrap_or_default()
+            );
+            finished = true;
+            break;
+        }
+
+        if let Some(min_bytes) = config.min_throughput {
+            let (start, received_before) = window;
+            if start.elapsed() >= config.min_throughput_window {
+                let received = stats.bytes_received() - received_before;
+                if received < min_bytes {
+                    info!(
+                        "Closing connection from {}, it sent {} bytes in {:?}, below the minimum of {}",
+                        peer, received, config.min_throughput_window, min_bytes
+                    );
+                    stats.error = Some(ErrorKind::TimedOut);
+                    break;
+                }
+                window = (Instant::now(), stats.bytes_received());
+            }
+        }
+
+        if let Some(max_bytes) = max_bytes {
+            if stats.bytes_echoed >= max_bytes {
+                info!(
+                    "Closing connection from {}, it reached the limit of {} echoed bytes",
+                    peer, max_bytes
+                );
+                // Nothing more is read, so echo what the handler held back.
+                if let Some(handler) = &mut handler {
+                    if let Err(e) = finish_handled(&mut stream, &mut **handler, &mut message) {
+                        warn!(
+                            "Stopping further processing of stream from {} due to: {}",
+                            peer, e
+                        );
+                        stats.error = Some(e.io_error().kind());
+                        break;
+                    }
+                }
+                finished = true;
+                break;
+            }
+        }
+
+        // Never read past the byte limit, so no more than `max_bytes` are
+        // echoed even when the final read would have filled the buffer.
+        let len = match max_bytes {
+            Some(max_bytes) => buffer
+                .len()
+                .min(usize::try_from(max_bytes - stats.bytes_echoed).unwrap_or(usize::MAX)),
+            None => buffer.len(),
+        };
+
+        let delay = config.echo_delay + jitter.as_mut().map_or(Duration::ZERO, Jitter::next);
+        let dropping = drops
+            .as_mut()
+            .is_some_and(|rng| rng.chance(config.drop_rate));
+        let result = match (&duplex, &mut handler) {
+            _ if dropping => discard(&mut stream, &mut buffer[..len]),
+            (Some(duplex), _) => {
+                duplex::forward(&mut stream, &mut buffer[..len], duplex, transform, delay)
+            }
+            (None, Some(handler)) => echo_handled(
+                &mut stream,
+                &mut buffer[..len],
+                &mut **handler,
+                &mut message,
+                delay,
+            ),
+            (None, None) => match config.mode {
+                Mode::Http => http::echo_request(
+                    &mut stream,
+                    &mut message,
+                    config.http_limits,
+                    config.max_frame_size,
+                    transform,
+                    delay,
+                )
+                .map(|exchange| {
+                    keep_alive = exchange.keep_alive;
+                    exchange.bytes_read
+                }),
+                Mode::WebSocket => websocket::echo_frame(
+                    &mut stream,
+                    &mut message,
+                    config.max_frame_size,
+                    transform,
+                    delay,
+                ),
+                Mode::Chargen => chargen(&mut stream, &mut buffer[..len], stats.bytes_echoed),
+                Mode::Broadcast => match &config.room {
+                    Some(room) => {
+                        broadcast::publish(&mut stream, &mut buffer[..len], room, transform, delay)
+                    }
+                    None => discard(&mut stream, &mut buffer[..len]),
+                },
+                mode => unreachable!("{:?} connections are served by a handler", mode),
+            },
+        };
+
+        if let Err(e) = &result {
+            stats.error = Some(e.io_error().kind());
+        }
+
+        match result {
+            Ok(0) => {
+                debug!("All bytes were read!");
+                finished = true;
+                break;
+            }
+            Err(EchoError::Read(ref e)) if is_timeout(e) && outlived() => {
+                // Reaching the lifetime ends the connection cleanly, as the
+                // check at the top of the loop does for an active one.
+                stats.error = None;
+                continue;
+            }
+            Err(EchoError::Read(ref e)) if is_timeout(e) && stream.get_ref().expired() => {
+                info!(
+                    "Closing connection from {}, no complete read within the idle timeout",
+                    peer
+                );
+                break;
+            }
+            Err(EchoError::Read(ref e)) if is_timeout(e) => {
+                info!(
+                    "Closing connection from {}, no data received within the read timeout",
+                    peer
+                );
+                break;
+            }
+            Err(EchoError::Write(ref e)) if is_timeout(e) => {
+                info!(
+                    "Closing connection from {}, client stopped reading and the write timed out",
+                    peer
+                );
+                break;
+            }
+            // The duplex writer stopped, which finishing it below reports.
+            Err(EchoError::Write(_)) if duplex.is_some() => break,
+            Err(EchoError::Write(ref e)) if is_disconnect(e.kind()) => {
+                info!(
+                    "Closing connection from {}, client stopped receiving echoes",
+                    peer
+                );
+                debug!("The echo could not be written, {}", e);
+                break;
+            }
+            Err(ref e) if is_disconnect(e.io_error().kind()) => {
+                debug!("Client {} disconnected abruptly, {}", peer, e);
+                break;
+            }
+            Err(e) => {
+                warn!(
+                    "Stopping further processing of stream from {} due to: {}",
+                    peer, e
+                );
+                break;
+            }
+            Ok(read_bytes) => {
+                if dropping {
+                    stats.bytes_dropped += read_bytes as u64;
+                } else {
+                    stats.bytes_echoed += read_bytes as u64;
+                    server_stats.add_bytes_echoed(read_bytes as u64);
+                }
+                stats.reads += 1;
+                // Throttle before touching, so time spent asleep here never
+                // counts against the client's idle timeout.
+                throttle.consume(read_bytes as u64);
+                if !handler
+                    .as_ref()
+                    .is_some_and(|handler| handler.holds_partial_message())
+                {
+                    stream.get_mut().touch();
+                }
+            }
+        }
+    }
+
+    // The write side may only be shut down once the writer is done with it.
+    if let Some(duplex) = duplex {
+        if let Err(e) = duplex.finish() {
+            if is_timeout(&e) {
+                info!(
+                    "Closing connection from {}, client stopped reading and the write timed out",
+                    peer
+                );
+            } else if is_disconnect(e.kind()) {
+                info!(
+                    "Closing connection from {}, client stopped receiving echoes",
+                    peer
+                );
+                debug!("The echo could not be written, {}", e);
+            } else {
+                debug!("Client {} disconnected abruptly, {}", peer, e);
+            }
+            stats.error = Some(e.kind());
+            finished = false;
+        }
+    }
+
+    if let Err(e) = stream.flush() {
+        debug!("Could not flush remaining echo due to: {:?}", e);
+    } else if finished {
+        if let Err(e) = stream.get_mut().stream.shutdown_write() {
+            debug!("Could not shut down the write side due to: {:?}", e);
+        }
+    }
+    stats
+}
+
+// Fails reads once `timeout` has passed since the last `touch`, including a
+// read that only returns after that point. Unlike a socket read timeout this
+// spans every read needed to complete one echo, so a client trickling in a
+// line a byte at a time cannot hold on to a worker indefinitely.
+struct IdleTimeout<S> {
+    stream: S,
+    timeout: Option<Duration>,
+    last_activity: Instant,
+}
+
+impl<S> IdleTimeout<S> {
+    fn new(stream: S, timeout: Option<Duration>) -> IdleTimeout<S> {
+        IdleTimeout {
+            stream,
+            timeout,
+            last_activity: Instant::now(),
+        }
+    }
+
+    fn touch(&mut self) {
+        self.last_activity = Instant::now();
+    }
+
+    fn expired(&self) -> bool {
+        self.timeout
+            .is_some_and(|timeout| self.last_activity.elapsed() >= timeout)
+    }
+
+    fn check(&self) -> io::Result<()> {
+        if self.expired() {
+            Err(io::Error::new(ErrorKind::TimedOut, "idle timeout expired"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<S: Read> Read for IdleTimeout<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.check()?;
+        let read_bytes = self.stream.read(buf)?;
+        self.check()?;
+        Ok(read_bytes)
+    }
+}
+
+impl<S: Write> Write for IdleTimeout<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+// A connection's xorshift generator, which is plenty for emulating a
+// jittery or lossy network.
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    fn new() -> Rng {
+        Rng {
+            // The standard hasher is keyed randomly, which gives every
+            // connection a different sequence. Xorshift must not start at 0.
+            state: RandomState::new().build_hasher().finish() | 1,
+        }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    // Whether an event with the given probability happens this time.
+    fn chance(&mut self, probability: f64) -> bool {
+        // The top 53 bits make a uniform float in [0, 1).
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+}
+
+// Draws a connection's random extra echo delays.
+struct Jitter {
+    min: Duration,
+    range_nanos: u64,
+    rng: Rng,
+}
+
+impl Jitter {
+    fn new(min: Duration, max: Duration) -> Jitter {
+        Jitter {
+            min,
+            range_nanos: u64::try_from((max - min).as_nanos()).unwrap_or(u64::MAX),
+            rng: Rng::new(),
+        }
+    }
+
+    fn next(&mut self) -> Duration {
+        let random = self.rng.next();
+        match self.range_nanos {
+            0 => self.min,
+            range => self.min + Duration::from_nanos(random % (range + 1)),
+        }
+    }
+}
+
+// The peer named in a connection's log lines, as its accept loop entered
+// it.
+fn peer_name() -> Arc<str> {
+    span::peer().unwrap_or_else(|| Arc::from("unknown"))
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+// Errors caused by the client going away without a clean close, which is
+// routine churn rather than a problem on the server side. A write accepting
+// no bytes, which `write_all` reports as `WriteZero`, means the client no
+// longer takes any echoes either.
+pub(crate) fn is_disconnect(kind: ErrorKind) -> bool {
+    matches!(
+        kind,
+        ErrorKind::ConnectionReset
+            | ErrorKind::BrokenPipe
+            | ErrorKind::UnexpectedEof
+            | ErrorKind::WriteZero
+    )
+}
+
+/// Reads one chunk from `stream` and drops it, returning the number of bytes
+/// read.
+pub fn discard<S: Read>(stream: &mut S, buffer: &mut [u8]) -> Result<usize, EchoError> {
+    retry_interrupted(|| stream.read(buffer)).map_err(EchoError::Read)
+}
+
+/// Writes the next `buffer.len()` bytes of the RFC 864 character generator
+/// pattern, starting `position` bytes into it, and returns how many were
+/// written.
+///
+/// Line `n` of the pattern holds 72 consecutive printable ASCII characters
+/// starting at the `n`-th one, wrapping around after `~`, and ends in CRLF.
+pub fn chargen<S: Write>(
+    stream: &mut S,
+    buffer: &mut [u8],
+    position: u64,
+) -> Result<usize, EchoError> {
+    for (offset, byte) in (position..).zip(buffer.iter_mut()) {
+        let (line, column) = (offset / CHARGEN_LINE, offset % CHARGEN_LINE);
+        *byte = match column {
+            72 => b'\r',
+            73 => b'\n',
+            _ => b' ' + ((line + column) % 95) as u8,
+        };
+    }
+    stream.write_all(buffer).map_err(EchoError::Write)?;
+    Ok(buffer.len())
+}
+
+pub(crate) fn delay_echo(delay: Duration) {
+    if delay > Duration::ZERO {
+        thread::sleep(delay);
+    }
+}
+
+// Like `read_exact`, but returns how much was read before the end of the
+// stream instead of failing, so callers can tell a clean close from a
+// truncated message.
+pub(crate) fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match retry_interrupted(|| reader.read(&mut buf[filled..]))? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+// A signal arriving during a read makes it fail with `Interrupted` before
+// anything was transferred, so the read is simply repeated, though only a
+// few times in a row so a stream that keeps reporting it cannot spin a
+// worker forever.
+pub(crate) fn retry_interrupted<T>(mut operation: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut retries = 0;
+    loop {
+        match operation() {
+            Err(ref e)
+                if e.kind() == ErrorKind::Interrupted && retries < MAX_INTERRUPTED_RETRIES =>
+            {
+                retries += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+pub(crate) fn truncated_frame(read: usize) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("connection closed after {} bytes of a frame", read),
+    )
+}
+
+/// Failure of one `echo` round, tagged with the direction that failed.
+#[derive(Debug)]
+pub enum EchoError {
+    Read(io::Error),
+    Write(io::Error),
+}
+
+impl EchoError {
+    pub fn io_error(&self) -> &io::Error {
+        match self {
+            EchoError::Read(e) | EchoError::Write(e) => e,
+        }
+    }
+
+    pub fn into_io_error(self) -> io::Error {
+        match self {
+            EchoError::Read(e) | EchoError::Write(e) => e,
+        }
+    }
+}
+
+impl fmt::Display for EchoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EchoError::Read(e) => write!(f, "read failed: {}", e),
+            EchoError::Write(e) => write!(f, "write failed: {}", e),
+        }
+    }
+}
+
+impl Error for EchoError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.io_error())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::ThreadPool;
+    use crate::server::Mode;
+    use crate::testing::MockStream;
+    use crate::transform;
+    use std::borrow::Cow;
+
+    // Serves `chunks` with `config` and returns everything echoed back.
+    fn served(config: ServerConfig, chunks: &[&[u8]]) -> Vec<u8> {
+        let mut stream = MockStream::new(chunks);
+        handle(&mut stream, &config, &ServerStats::default());
+        stream.output
+    }
+
+    #[test]
+    fn reverse_reverses_each_raw_chunk_as_it_is() {
+        let config = ServerConfig::builder()
+            .transform(transform::by_name("reverse").unwrap())
+            .build();
+
+        assert_eq!(served(config, &[b"abc"]), b"cba");
+    }
+
+    #[test]
+    fn registered_transform_uppercases_the_echo() {
+        let config = ServerConfig::builder()
+            .transform(Arc::new(|bytes: &[u8]| {
+                Cow::Owned(bytes.to_ascii_uppercase())
+            }))
+            .build();
+
+        assert_eq!(served(config, &[b"hello, ", b"world"]), b"HELLO, WORLD");
+    }
+
+    #[test]
+    fn transform_output_longer_than_the_buffer_is_written_whole() {
+        let config = ServerConfig::builder()
+            .buffer_size(4)
+            .transform(Arc::new(|bytes: &[u8]| {
+                Cow::Owned(bytes.iter().flat_map(|&b| vec![b; 3]).collect())
+            }))
+            .build();
+
+        assert_eq!(served(config, &[b"abcd"]), b"aaabbbcccddd");
+    }
+
+    #[test]
+    fn reverse_keeps_line_endings_in_line_mode() {
+        let config = ServerConfig::builder()
+            .mode(Mode::Line)
+            .transform(transform::by_name("reverse").unwrap())
+            .build();
+
+        assert_eq!(served(config, &[b"abc\ndef\r\n"]), b"cba\nfed\r\n");
+    }
+
+    #[test]
+    fn reverse_mode_echoes_a_single_read_reversed() {
+        let config = ServerConfig::builder().mode(Mode::Reverse).build();
+
+        assert_eq!(served(config, &[b"abc"]), b"cba");
+    }
+
+    #[test]
+    fn reverse_mode_reverses_what_the_transform_returns() {
+        let config = ServerConfig::builder()
+            .mode(Mode::Reverse)
+            .transform(transform::by_name("uppercase").unwrap())
+            .build();
+
+        assert_eq!(served(config, &[b"abc", b"de\n"]), b"CBA\nED");
+    }
+
+    #[test]
+    fn handle_echoes_over_an_in_memory_stream() {
+        let mut stream = MockStream::new(&[b"no ", b"socket ", b"needed"]);
+
+        handle(
+            &mut stream,
+            &ServerConfig::builder().build(),
+            &ServerStats::default(),
+        );
+
+        assert_eq!(stream.output, b"no socket needed");
+        // The client's close is answered by closing the write side.
+        assert!(stream.write_shut);
+    }
+
+    #[test]
+    fn stats_count_every_byte_and_read_of_a_chunked_payload() {
+        let mut stream = MockStream::new(&[b"hel", b"lo wor", b"ld"]);
+
+        let stats = handle(
+            &mut stream,
+            &ServerConfig::builder().build(),
+            &ServerStats::default(),
+        );
+
+        assert_eq!(stats.bytes_echoed, 11);
+        assert_eq!(stats.reads, 3);
+        assert_eq!(stats.bytes_received(), 11);
+    }
+
+    #[test]
+    fn payload_larger_than_the_buffer_is_echoed_over_several_reads() {
+        let payload: Vec<u8> = (0..=255).cycle().take(1_000).collect();
+        let config = ServerConfig::builder().buffer_size(64).build();
+        let mut stream = MockStream::new(&[&payload]);
+
+        let stats = handle(&mut stream, &config, &ServerStats::default());
+
+        assert_eq!(stream.output, payload);
+        assert_eq!(stats.reads, 16);
+    }
+
+    #[test]
+    fn interrupted_reads_and_writes_are_retried() {
+        let interrupted = || io::Error::from(io::ErrorKind::Interrupted);
+        let mut stream = MockStream::new(&[b"hel"])
+            .then_fail_read(interrupted())
+            .then_send(b"lo")
+            .fail_write(interrupted());
+
+        let stats = handle(
+            &mut stream,
+            &ServerConfig::builder().build(),
+            &ServerStats::default(),
+        );
+
+        assert_eq!(stream.output, b"hello");
+        assert_eq!(stats.outcome(), Outcome::Closed);
+    }
+
+    #[test]
+    fn a_read_that_keeps_being_interrupted_gives_up() {
+        let mut stream = MockStream::new(&[]);
+        for _ in 0..=MAX_INTERRUPTED_RETRIES {
+            stream = stream.then_fail_read(io::Error::from(io::ErrorKind::Interrupted));
+        }
+
+        let stats = handle(
+            &mut stream,
+            &ServerConfig::builder().build(),
+            &ServerStats::default(),
+        );
+
+        assert_eq!(stats.error, Some(io::ErrorKind::Interrupted));
+    }
+
+    #[test]
+    fn write_accepting_no_bytes_is_a_disconnect() {
+        let mut stream =
+            MockStream::new(&[b"hello"]).fail_write(io::Error::from(io::ErrorKind::WriteZero));
+
+        let stats = handle(
+            &mut stream,
+            &ServerConfig::builder().build(),
+            &ServerStats::default(),
+        );
+
+        assert_eq!(stats.outcome(), Outcome::Disconnected);
+        assert!(!stream.write_shut);
+    }
+
+    #[test]
+    fn connection_is_closed_once_it_echoed_its_byte_limit() {
+        let config = ServerConfig::builder().max_connection_bytes(8).build();
+        let mut stream = MockStream::new(&[b"hello", b" world", b" again"]);
+
+        let stats = handle(&mut stream, &config, &ServerStats::default());
+
+        assert_eq!(stream.output, b"hello wo");
+        assert_eq!(stats.bytes_echoed, 8);
+        assert_eq!(stats.outcome(), Outcome::Closed);
+        assert!(stream.write_shut);
+    }
+
+    #[test]
+    fn dropped_chunks_count_as_received_but_not_echoed() {
+        let chunks = vec![&b"0123456789"[..]; 200];
+        let server_stats = ServerStats::default();
+
+        let config = ServerConfig::builder().drop_rate(0.5).build();
+        let mut stream = MockStream::new(&chunks);
+        let stats = handle(&mut stream, &config, &server_stats);
+
+        assert_eq!(stats.bytes_received(), 2000);
+        assert!(
+            stats.bytes_dropped > 0 && stats.bytes_echoed > 0,
+            "{:?}",
+            stats
+        );
+        assert_eq!(stream.output.len() as u64, stats.bytes_echoed);
+        assert_eq!(server_stats.bytes_echoed(), stats.bytes_echoed);
+
+        let config = ServerConfig::builder().drop_rate(1.0).build();
+        let mut stream = MockStream::new(&chunks);
+        let stats = handle(&mut stream, &config, &ServerStats::default());
+
+        assert_eq!(stats.bytes_dropped, 2000);
+        assert!(stream.output.is_empty());
+    }
+
+    // Serves a client that sends `hello` and then fails with `error`, or
+    // closes cleanly if it is `None`, on a pool worker, and returns the
+    // stats it left behind.
+    fn ended(error: Option<io::ErrorKind>) -> Arc<ServerStats> {
+        let stats = Arc::new(ServerStats::default());
+        let pool = ThreadPool::new(1).unwrap();
+        let served = Arc::clone(&stats);
+        pool.execute_with_result(move || {
+            let mut stream = MockStream::new(&[b"hello"]);
+            if let Some(kind) = error {
+                stream = stream.then_fail_read(io::Error::from(kind));
+            }
+            handle(&mut stream, &ServerConfig::builder().build(), &served);
+        })
+        .unwrap()
+        .recv()
+        .unwrap();
+        stats
+    }
+
+    #[test]
+    fn clean_close_is_counted_as_closed() {
+        let stats = ended(None);
+
+        assert_eq!(
+            (stats.closed(), stats.disconnects(), stats.errors()),
+            (1, 0, 0)
+        );
+        assert_eq!(stats.bytes_echoed(), 5);
+    }
+
+    #[test]
+    fn reset_is_counted_as_a_disconnect_and_not_a_clean_close() {
+        let stats = ended(Some(io::ErrorKind::ConnectionReset));
+
+        assert_eq!(
+            (stats.closed(), stats.disconnects(), stats.errors()),
+            (0, 1, 0)
+        );
+    }
+
+    #[test]
+    fn io_error_is_counted_as_an_error_and_not_a_clean_close() {
+        let stats = ended(Some(io::ErrorKind::InvalidData));
+
+        assert_eq!(
+            (stats.closed(), stats.disconnects(), stats.errors()),
+            (0, 0, 1)
+        );
+        let workers = stats.worker_outcomes();
+        assert_eq!(workers.len(), 1);
+        assert_eq!(workers[0].1, stats.outcomes());
+    }
+}
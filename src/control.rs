@@ -0,0 +1,140 @@
+use crate::pool::ThreadPool;
+use crate::shutdown::Shutdown;
+use crate::socket;
+use crate::stats::ServerStats;
+use log::{info, warn};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+/// Serves the control protocol on `listener` until shutdown is requested.
+///
+/// Clients send one command per line and get one line back, starting with
+/// `ok` or `error:`. The commands are `stats`, `resize <workers>` and
+/// `shutdown`. Connections are served one at a time, so `read_timeout`
+/// keeps an idle client from locking everyone else out.
+pub fn serve_control(
+    listener: TcpListener,
+    thread_pool: &ThreadPool,
+    stats: &ServerStats,
+    shutdown: &Shutdown,
+    read_timeout: Option<Duration>,
+) {
+    while let Some(stream) = shutdown.accept(|| socket::accept_blocking(&listener)) {
+        let result = stream
+            .and_then(|stream| {
+                stream.set_read_timeout(read_timeout)?;
+                Ok(stream)
+            })
+            .and_then(|stream| handle_control(stream, thread_pool, stats, shutdown));
+        if let Err(e) = result {
+            warn!("Control connection failed due to: {:?}", e);
+        }
+    }
+}
+
+fn handle_control(
+    stream: TcpStream,
+    thread_pool: &ThreadPool,
+    stats: &ServerStats,
+    shutdown: &Shutdown,
+) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let command = line.trim();
+        if command.is_empty() {
+            continue;
+        }
+
+        info!("Control command: {}", command);
+        let response = execute(command, thread_pool, stats);
+        writeln!(writer, "{}", response)?;
+
+        if command == "shutdown" {
+            shutdown.request();
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn execute(command: &str, thread_pool: &ThreadPool, stats: &ServerStats) -> String {
+    let mut words = command.split_whitespace();
+
+    match (words.next(), words.next(), words.next()) {
+        (Some("stats"), None, None) => format!(
+            "ok accepted={} active={} closed={} disconnected={} failed={} bytes_echoed={} pending={} workers={}",
+            stats.accepted(),
+            stats.active(),
+            stats.closed(),
+            stats.disconnects(),
+            stats.errors(),
+            stats.bytes_echoed(),
+            thread_pool.pending_tasks(),
+            thread_pool.worker_count()
+        ),
+        (Some("resize"), Some(size), None) => match size.parse::<usize>() {
+            Ok(size) if size > 0 => match thread_pool.resize(size) {
+                Ok(()) => format!("ok workers={}", thread_pool.worker_count()),
+                Err(e) => format!("error: could not resize the pool: {}", e),
+            },
+            _ => format!("error: invalid worker count {:?}", size),
+        },
+        (Some("shutdown"), None, None) => String::from("ok"),
+        _ => format!("error: unknown command {:?}", command),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn stats_response_parses_into_the_current_counters() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (thread_pool, shutdown) = (ThreadPool::new(2).unwrap(), Shutdown::default());
+        let stats = Arc::new(ServerStats::default());
+        let _open = stats.connection_opened();
+        stats.add_bytes_echoed(5);
+
+        thread::scope(|scope| {
+            scope.spawn(|| serve_control(listener, &thread_pool, &stats, &shutdown, None));
+            let client = TcpStream::connect(addr).unwrap();
+            client
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let mut responses = BufReader::new(client.try_clone().unwrap()).lines();
+            let mut send = |command: &str| {
+                writeln!(&client, "{}", command).unwrap();
+                responses.next().unwrap().unwrap()
+            };
+
+            let response = send("stats");
+            let counters: HashMap<_, u64> = response
+                .strip_prefix("ok ")
+                .unwrap_or_else(|| panic!("not a success: {}", response))
+                .split(' ')
+                .map(|pair| {
+                    let (key, value) = pair.split_once('=').unwrap();
+                    (key, value.parse().unwrap())
+                })
+                .collect();
+            assert_eq!(counters["accepted"], 1);
+            assert_eq!(counters["active"], 1);
+            assert_eq!(counters["bytes_echoed"], 5);
+            assert_eq!(counters["pending"], 0);
+            assert_eq!(counters["workers"], 2);
+
+            assert!(send("stats please").starts_with("error: unknown command"));
+            assert_eq!(send("shutdown"), "ok");
+        });
+        assert!(shutdown.is_requested());
+    }
+}
@@ -0,0 +1,16 @@
+use crate::transform::Transform;
+use log::warn;
+use std::net::{SocketAddr, UdpSocket};
+
+/// Sends `transform`'s output for `datagram` back to `peer` as a single
+/// datagram, preserving its boundaries.
+pub fn handle_datagram(
+    socket: &UdpSocket,
+    datagram: &[u8],
+    peer: SocketAddr,
+    transform: &Transform,
+) {
+    if let Err(e) = socket.send_to(&transform(datagram), peer) {
+        warn!("Could not echo datagram to {} due to: {:?}", peer, e);
+    }
+}
@@ -0,0 +1,88 @@
+use crate::connection::{delay_echo, retry_interrupted, EchoError};
+use crate::span;
+use crate::transform::Transform;
+use log::debug;
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::mpsc::{self, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Chunks the reader of a duplex connection may get ahead of its writer
+/// before it waits for the writer to catch up.
+pub const DUPLEX_QUEUE_SIZE: usize = 16;
+
+/// The writer thread of a duplex connection, which echoes the chunks its
+/// reader queues with `forward` while the reader goes on reading.
+///
+/// A write that fails stops the writer and calls the `close` it was
+/// spawned with, so a reader blocked on the socket notices too. The reader
+/// ends the connection with `finish`, which waits until everything queued
+/// has been written.
+pub struct Duplex {
+    queue: SyncSender<Vec<u8>>,
+    writer: JoinHandle<io::Result<()>>,
+}
+
+impl Duplex {
+    /// Spawns the writer of connection `id`, writing to `writer`, a second
+    /// handle to its socket.
+    pub fn spawn<W, F>(id: u64, mut writer: W, close: F) -> io::Result<Duplex>
+    where
+        W: Write + Send + 'static,
+        F: FnOnce() + Send + 'static,
+    {
+        let (queue, chunks) = mpsc::sync_channel::<Vec<u8>>(DUPLEX_QUEUE_SIZE);
+        let writer = thread::Builder::new()
+            .name(format!("duplex-{}", id))
+            .spawn(move || {
+                let _span = span::enter(id);
+                for chunk in chunks {
+                    if let Err(e) = writer.write_all(&chunk) {
+                        debug!("Stopped writing echoes due to: {:?}", e);
+                        close();
+                        return Err(e);
+                    }
+                }
+                Ok(())
+            })?;
+        Ok(Duplex { queue, writer })
+    }
+
+    /// Waits for the writer to write what is still queued, returning the
+    /// error that stopped it early, if any.
+    pub fn finish(self) -> io::Result<()> {
+        drop(self.queue);
+        self.writer
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("the duplex writer panicked")))
+    }
+}
+
+/// Reads one chunk from `stream` and queues `transform`'s output for it on
+/// the writer of `duplex` after sleeping for `delay`, returning the number
+/// of bytes read. Waits while the writer is `DUPLEX_QUEUE_SIZE` chunks
+/// behind, and fails once it stopped.
+pub fn forward<S: Read>(
+    stream: &mut S,
+    buffer: &mut [u8],
+    duplex: &Duplex,
+    transform: &Transform,
+    delay: Duration,
+) -> Result<usize, EchoError> {
+    let read_bytes = retry_interrupted(|| stream.read(buffer)).map_err(EchoError::Read)?;
+    if read_bytes == 0 {
+        return Ok(0);
+    }
+
+    delay_echo(delay);
+    duplex
+        .queue
+        .send(transform(&buffer[..read_bytes]).into_owned())
+        .map_err(|_| {
+            EchoError::Write(io::Error::new(
+                ErrorKind::BrokenPipe,
+                "the duplex writer stopped",
+            ))
+        })?;
+    Ok(read_bytes)
+}
@@ -0,0 +1,555 @@
+use crate::connection::{configure_tcp_stream, is_disconnect, ConnectionStats, EchoError};
+use crate::events::Accepted;
+use crate::mirror::Mirror;
+use crate::record::Recording;
+use crate::server::{log_closed, start_mirror, Admission, Mode, Permit, Protocol, ServerConfig};
+use crate::shutdown::{self, Shutdown, Tracked, ACCEPT_POLL_INTERVAL, MAX_ACCEPT_BACKOFF};
+use crate::socket;
+use crate::span;
+use crate::stats::{ActiveConnection, ServerStats};
+use log::{debug, error, info, warn};
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Token, Waker};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{self, SocketAddr};
+use std::os::fd::OwnedFd;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+const EVENTS_CAPACITY: usize = 1024;
+
+// How often open connections are checked against the timeouts and the
+// maximum lifetime, which bounds how late past them one is closed.
+const TIMER_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Fails unless everything `config` asks for can be served by an event loop.
+/// Anything that blocks in the middle of an echo, like a handshake, a delay
+/// or a framing mode, needs the thread pool.
+pub(crate) fn check(config: &ServerConfig) -> io::Result<()> {
+    let unsupported = [
+        (config.unix.is_some(), "Unix sockets"),
+        (config.protocol == Protocol::Udp, "UDP"),
+        (config.tls.is_some(), "TLS"),
+        (config.mode != Mode::Raw, "modes other than raw"),
+        (config.proxy_protocol, "the PROXY protocol"),
+        (!config.echo_delay.is_zero(), "an echo delay"),
+        (config.echo_jitter.is_some(), "an echo jitter"),
+        (config.drop_rate > 0.0, "a drop rate"),
+        (config.max_bytes_per_second > 0, "a bandwidth limit"),
+        (config.min_throughput.is_some(), "a minimum throughput"),
+        (config.handler.is_some(), "a custom echo handler"),
+        (config.duplex, "duplex connections"),
+    ];
+    match unsupported.iter().find(|(unsupported, _)| *unsupported) {
+        Some((_, feature)) => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("the event loop runtime does not support {}", feature),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Serves the connections of every listener on its own event loop thread,
+/// returning once all of them stopped accepting because shutdown was
+/// requested. The connections they still serve are tracked by `shutdown`,
+/// so they are drained or closed like the ones on the thread pool, and each
+/// loop exits after its last connection ended.
+pub(crate) fn serve(
+    listeners: Vec<net::TcpListener>,
+    config: &Arc<ServerConfig>,
+    shutdown: &Arc<Shutdown>,
+    stats: &Arc<ServerStats>,
+    admission: Admission,
+) -> io::Result<()> {
+    let admission = Arc::new(admission);
+    // Every loop holds a sender until it stops accepting.
+    let (accepting, stopped) = mpsc::channel::<()>();
+
+    for (id, listener) in listeners.into_iter().enumerate() {
+        let poll = Poll::new()?;
+        let mut listener = TcpListener::from_std(listener);
+        poll.registry()
+            .register(&mut listener, LISTENER, Interest::READABLE)?;
+        // The loop keeps the waker too, since closing it would take a wake
+        // that was not polled yet along with it.
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let wake = Arc::clone(&waker);
+        shutdown.on_request(move || {
+            if let Err(e) = wake.wake() {
+                error!("Could not wake event loop due to: {:?}", e);
+            }
+        });
+
+        let event_loop = EventLoop {
+            poll,
+            _waker: waker,
+            listener: Some(listener),
+            accepting: Some(accepting.clone()),
+            connections: HashMap::new(),
+            next_token: WAKER.0 + 1,
+            buffer: vec![0u8; config.buffer_size],
+            config: Arc::clone(config),
+            stats: Arc::clone(stats),
+            shutdown: Arc::clone(shutdown),
+            admission: Arc::clone(&admission),
+            retry_accept: None,
+            accept_backoff: ACCEPT_POLL_INTERVAL,
+        };
+        thread::Builder::new()
+            .name(format!("event-loop-{}", id))
+            .spawn(move || event_loop.run())?;
+    }
+
+    drop(accepting);
+    // Fails once the last sender is gone.
+    while stopped.recv().is_ok() {}
+    Ok(())
+}
+
+struct EventLoop {
+    poll: Poll,
+    _waker: Arc<Waker>,
+    listener: Option<TcpListener>,
+    accepting: Option<mpsc::Sender<()>>,
+    connections: HashMap<Token, Connection>,
+    next_token: usize,
+    // Shared by all connections, since each read is echoed before the next.
+    buffer: Vec<u8>,
+    config: Arc<ServerConfig>,
+    stats: Arc<ServerStats>,
+    shutdown: Arc<Shutdown>,
+    admission: Arc<Admission>,
+    // When to accept again after an accept failed for a reason that is not
+    // about the connection at hand, and how long the next failure waits.
+    retry_accept: Option<Instant>,
+    accept_backoff: Duration,
+}
+
+impl EventLoop {
+    fn run(mut self) {
+        let timed = self.config.read_timeout.is_some()
+            || self.config.idle_timeout.is_some()
+            || self.config.write_timeout.is_some()
+            || self.config.max_lifetime.is_some();
+        let timer = if timed { Some(TIMER_INTERVAL) } else { None };
+        let mut events = Events::with_capacity(EVENTS_CAPACITY);
+        let mut last_check = Instant::now();
+
+        while self.listener.is_some() || !self.connections.is_empty() {
+            let timeout = match self.retry_accept {
+                Some(retry) => {
+                    let wait = retry.saturating_duration_since(Instant::now());
+                    Some(timer.map_or(wait, |timer| timer.min(wait)))
+                }
+                None => timer,
+            };
+            if let Err(e) = self.poll.poll(&mut events, timeout) {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                error!("Event loop failed due to: {:?}", e);
+                return;
+            }
+
+            for event in &events {
+                match event.token() {
+                    LISTENER => self.accept(),
+                    WAKER => self.stop_accepting(),
+                    token => self.ready(token),
+                }
+            }
+
+            // The listener is edge-triggered, so the connections left waiting
+            // by a failed accept are not reported again until a new one comes
+            // in, and are accepted once the pause after the failure is over.
+            if self
+                .retry_accept
+                .is_some_and(|retry| retry <= Instant::now())
+            {
+                self.accept();
+            }
+
+            if timed && last_check.elapsed() >= TIMER_INTERVAL {
+                last_check = Instant::now();
+                self.expire(last_check);
+            }
+        }
+    }
+
+    fn stop_accepting(&mut self) {
+        if let Some(mut listener) = self.listener.take() {
+            // The listener may be a clone, whose registration would outlive
+            // it until the others are closed too.
+            let _ = self.poll.registry().deregister(&mut listener);
+        }
+        self.accepting = None;
+        self.retry_accept = None;
+    }
+
+    fn accept(&mut self) {
+        self.retry_accept = None;
+        loop {
+            let accepted = match &self.listener {
+                Some(listener) => listener.accept(),
+                None => return,
+            };
+            let (stream, peer) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if shutdown::is_transient(&e) => {
+                    error!("Could not establish connection due to: {:?}", e);
+                    continue;
+                }
+                // Like running out of file descriptors, which would most
+                // likely fail the next accept straight away too.
+                Err(e) => {
+                    warn!(
+                        "Could not accept connections due to: {:?} ({}), retrying in {:?}",
+                        e.kind(),
+                        e,
+                        self.accept_backoff
+                    );
+                    self.retry_accept = Some(Instant::now() + self.accept_backoff);
+                    self.accept_backoff = (self.accept_backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                    return;
+                }
+            };
+            self.accept_backoff = ACCEPT_POLL_INTERVAL;
+
+            let id = span::next_id();
+            let _span = span::enter_with_peer(id, Some(peer));
+            if !self.config.access.is_allowed(peer.ip()) {
+                debug!("Refusing connection from {}, denied by access list", peer);
+                continue;
+            }
+            if let Some(permit) = self.admission.admit(Some(peer.ip()), &stream) {
+                info!("Accepted connection from {}", peer);
+                if let Err(e) = self.open(stream, id, peer, permit) {
+                    warn!("Could not configure connection due to: {:?}", e);
+                }
+            }
+        }
+    }
+
+    fn open(
+        &mut self,
+        stream: TcpStream,
+        id: u64,
+        peer: SocketAddr,
+        permit: Permit,
+    ) -> io::Result<()> {
+        let config = &*self.config;
+        let accepted = config.events.accepted(peer);
+
+        // Configures the socket like an accept loop does, while it is a
+        // standard stream again.
+        let stream = net::TcpStream::from(OwnedFd::from(stream));
+        configure_tcp_stream(&stream, None, None, config.nodelay)?;
+        socket::set_buffer_sizes(&stream, config.recv_buffer_size, config.send_buffer_size);
+        if let Some(ttl) = config.ttl {
+            socket::set_ttl(&stream, ttl);
+        }
+        if let Some(linger) = config.linger {
+            socket::set_linger(&stream, linger);
+        }
+        if let Some(keepalive) = &config.keepalive {
+            socket::set_keepalive(&stream, keepalive);
+        }
+        let closer = stream.try_clone()?;
+        let tracked = self.shutdown.track(move || {
+            let _ = closer.shutdown(net::Shutdown::Both);
+        });
+
+        let mut stream = TcpStream::from_std(stream);
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        self.poll.registry().register(
+            &mut stream,
+            token,
+            Interest::READABLE | Interest::WRITABLE,
+        )?;
+
+        // The banner goes out with the first write, before any echo.
+        let mut pending = Vec::new();
+        if let Some(banner) = &config.banner {
+            pending.extend_from_slice(banner.as_bytes());
+            pending.extend_from_slice(b"\r\n");
+        }
+        let now = Instant::now();
+        let connection = Connection {
+            stream,
+            id,
+            peer,
+            pending,
+            written: 0,
+            draining: false,
+            writing: false,
+            since: now,
+            deadline: config.max_lifetime.map(|lifetime| now + lifetime),
+            stats: ConnectionStats::default(),
+            recording: config
+                .recorder
+                .as_ref()
+                .map(|recorder| recorder.start(id, peer)),
+            mirror: start_mirror(config, id),
+            _accepted: accepted,
+            _active: self.stats.connection_opened(),
+            _permit: permit,
+            _tracked: tracked,
+        };
+        self.connections.insert(token, connection);
+        Ok(())
+    }
+
+    fn ready(&mut self, token: Token) {
+        let (_span, peer, result) = match self.connections.get_mut(&token) {
+            Some(connection) => (
+                span::enter_with_peer(connection.id, Some(connection.peer)),
+                connection.peer,
+                connection.drive(&mut self.buffer, &self.config, &self.stats),
+            ),
+            // Already closed by an earlier event in the same batch.
+            None => return,
+        };
+        match result {
+            Ok(false) => {}
+            Ok(true) => self.close(token, None),
+            Err(EchoError::Write(ref e)) if is_disconnect(e.kind()) => {
+                info!(
+                    "Closing connection from {}, client stopped receiving echoes",
+                    peer
+                );
+                debug!("The echo could not be written, {}", e);
+                self.close(token, Some(e.kind()));
+            }
+            Err(ref e) if is_disconnect(e.io_error().kind()) => {
+                debug!("Client {} disconnected abruptly, {}", peer, e);
+                self.close(token, Some(e.io_error().kind()));
+            }
+            Err(e) => {
+                warn!(
+                    "Stopping further processing of stream from {} due to: {}",
+                    peer, e
+                );
+                self.close(token, Some(e.io_error().kind()));
+            }
+        }
+    }
+
+    // Closes the connections that ran into a timeout and starts draining the
+    // ones that reached the maximum lifetime.
+    fn expire(&mut self, now: Instant) {
+        let config = Arc::clone(&self.config);
+        let read_timeout = [config.read_timeout, config.idle_timeout]
+            .iter()
+            .flatten()
+            .min()
+            .copied();
+        let expired: Vec<(Token, Expiry)> = self
+            .connections
+            .iter()
+            .filter_map(|(token, connection)| {
+                connection
+                    .expiry(now, read_timeout, config.write_timeout)
+                    .map(|expiry| (*token, expiry))
+            })
+            .collect();
+
+        for (token, expiry) in expired {
+            let (_span, peer) = match self.connections.get(&token) {
+                Some(connection) => (
+                    span::enter_with_peer(connection.id, Some(connection.peer)),
+                    connection.peer,
+                ),
+                None => continue,
+            };
+            match expiry {
+                Expiry::Lifetime => {
+                    info!(
+                        "Closing connection from {}, it reached the maximum lifetime of {:?}",
+                        peer,
+                        config.max_lifetime.unwrap_or_default()
+                    );
+                    if let Some(connection) = self.connections.get_mut(&token) {
+                        connection.draining = true;
+                    }
+                    self.ready(token);
+                }
+                Expiry::Read => {
+                    info!(
+                        "Closing connection from {}, no data received within the read timeout",
+                        peer
+                    );
+                    self.close(token, Some(ErrorKind::TimedOut));
+                }
+                Expiry::Write => {
+                    info!(
+                        "Closing connection from {}, client stopped reading and the write timed out",
+                        peer
+                    );
+                    self.close(token, Some(ErrorKind::TimedOut));
+                }
+            }
+        }
+    }
+
+    fn close(&mut self, token: Token, error: Option<ErrorKind>) {
+        let mut connection = match self.connections.remove(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+        let _span = span::enter_with_peer(connection.id, Some(connection.peer));
+        let _ = self.poll.registry().deregister(&mut connection.stream);
+
+        connection.stats.error = error;
+        self.stats.connection_ended(connection.stats.outcome());
+        log_closed(connection.peer, &connection.stats);
+        if error.is_some() {
+            self.config.events.failed(&connection.peer);
+        }
+    }
+}
+
+enum Expiry {
+    Lifetime,
+    Read,
+    Write,
+}
+
+// One connection's side of the echo, advanced whenever its socket becomes
+// ready. It only reads again once the previous echo was written in full, so
+// a client that stops reading stops being read from, like a blocked worker.
+struct Connection {
+    stream: TcpStream,
+    id: u64,
+    peer: SocketAddr,
+    // Bytes still to be echoed, of which the first `written` already were.
+    pending: Vec<u8>,
+    written: usize,
+    // Set once nothing more is read, because the peer closed its side or a
+    // limit was reached; the connection ends when `pending` is written.
+    draining: bool,
+    // Whether the connection waits to write rather than to read, and since
+    // when it made no progress.
+    writing: bool,
+    since: Instant,
+    deadline: Option<Instant>,
+    stats: ConnectionStats,
+    recording: Option<Recording>,
+    mirror: Option<Mirror>,
+    // Released in this order once the connection is dropped, as an accept
+    // loop's task releases them.
+    _accepted: Accepted,
+    _active: ActiveConnection,
+    _permit: Permit,
+    _tracked: Tracked,
+}
+
+impl Connection {
+    // Writes and reads until the socket would block, returning whether the
+    // connection finished cleanly.
+    fn drive(
+        &mut self,
+        buffer: &mut [u8],
+        config: &ServerConfig,
+        server_stats: &ServerStats,
+    ) -> Result<bool, EchoError> {
+        loop {
+            while self.written < self.pending.len() {
+                match self.stream.write(&self.pending[self.written..]) {
+                    Ok(0) => return Err(EchoError::Write(ErrorKind::WriteZero.into())),
+                    Ok(written) => {
+                        self.written += written;
+                        self.since = Instant::now();
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                        self.writing = true;
+                        return Ok(false);
+                    }
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(e) => return Err(EchoError::Write(e)),
+                }
+            }
+            self.pending.clear();
+            self.written = 0;
+            self.writing = false;
+
+            if self.draining {
+                if let Err(e) = self.stream.shutdown(net::Shutdown::Write) {
+                    debug!("Could not shut down the write side due to: {:?}", e);
+                }
+                return Ok(true);
+            }
+
+            if let Some(max_bytes) = config.max_connection_bytes {
+                if self.stats.bytes_echoed >= max_bytes {
+                    info!(
+                        "Closing connection from {}, it reached the limit of {} echoed bytes",
+                        self.peer, max_bytes
+                    );
+                    self.draining = true;
+                    continue;
+                }
+            }
+
+            // Never read past the byte limit, as a worker does not either.
+            let len = match config.max_connection_bytes {
+                Some(max_bytes) => buffer.len().min(
+                    usize::try_from(max_bytes - self.stats.bytes_echoed).unwrap_or(usize::MAX),
+                ),
+                None => buffer.len(),
+            };
+            match self.stream.read(&mut buffer[..len]) {
+                Ok(0) => {
+                    debug!("All bytes were read!");
+                    self.draining = true;
+                }
+                Ok(read_bytes) => {
+                    let chunk = &buffer[..read_bytes];
+                    if let Some(recording) = &mut self.recording {
+                        recording.record(chunk);
+                    }
+                    if let Some(mirror) = &mut self.mirror {
+                        mirror.copy(chunk);
+                    }
+                    self.pending.extend_from_slice(&(config.transform)(chunk));
+                    self.stats.bytes_echoed += read_bytes as u64;
+                    self.stats.reads += 1;
+                    server_stats.add_bytes_echoed(read_bytes as u64);
+                    self.since = Instant::now();
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(EchoError::Read(e)),
+            }
+        }
+    }
+
+    fn expiry(
+        &self,
+        now: Instant,
+        read_timeout: Option<Duration>,
+        write_timeout: Option<Duration>,
+    ) -> Option<Expiry> {
+        let stalled = |timeout: Option<Duration>| {
+            timeout.is_some_and(|timeout| now.duration_since(self.since) >= timeout)
+        };
+        if self.writing && stalled(write_timeout) {
+            Some(Expiry::Write)
+        } else if self.draining {
+            None
+        } else if self.deadline.is_some_and(|deadline| now >= deadline) {
+            Some(Expiry::Lifetime)
+        } else if !self.writing && stalled(read_timeout) {
+            Some(Expiry::Read)
+        } else {
+            None
+        }
+    }
+}
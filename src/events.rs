@@ -0,0 +1,61 @@
+use crate::pool::panic_message;
+use log::warn;
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+/// Called with the peer address of a TCP connection.
+pub type Callback = dyn Fn(&SocketAddr) + Send + Sync;
+
+/// Hooks an embedder can register to observe TCP connections.
+///
+/// `on_accept` runs on the accept loop, so a slow callback holds up new
+/// connections; `on_close` and `on_error` run on the worker serving the
+/// connection. A panicking callback is logged and otherwise ignored.
+#[derive(Clone, Default)]
+pub struct Events {
+    pub(crate) on_accept: Option<Arc<Callback>>,
+    pub(crate) on_close: Option<Arc<Callback>>,
+    pub(crate) on_error: Option<Arc<Callback>>,
+}
+
+impl Events {
+    // The returned guard runs `on_close` when dropped, so it also fires for
+    // a connection that is turned away by a full queue or whose handler
+    // unwinds.
+    pub(crate) fn accepted(&self, peer: SocketAddr) -> Accepted {
+        notify("on_accept", &self.on_accept, &peer);
+        Accepted {
+            on_close: self.on_close.clone(),
+            peer,
+        }
+    }
+
+    pub(crate) fn failed(&self, peer: &SocketAddr) {
+        notify("on_error", &self.on_error, peer);
+    }
+}
+
+pub(crate) struct Accepted {
+    on_close: Option<Arc<Callback>>,
+    peer: SocketAddr,
+}
+
+impl Drop for Accepted {
+    fn drop(&mut self) {
+        notify("on_close", &self.on_close, &self.peer);
+    }
+}
+
+fn notify(name: &str, callback: &Option<Arc<Callback>>, peer: &SocketAddr) {
+    if let Some(callback) = callback {
+        if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(|| callback(peer))) {
+            warn!(
+                "Ignoring panic in {} callback for {}, reason: {}",
+                name,
+                peer,
+                panic_message(&*panic)
+            );
+        }
+    }
+}
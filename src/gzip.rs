@@ -0,0 +1,115 @@
+use crate::handler::EchoHandler;
+use crate::transform::Transform;
+use flate2::write::{GzEncoder, MultiGzDecoder};
+use flate2::Compression;
+use std::io::{self, ErrorKind, Write};
+use std::mem;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Which side of a `Mode::Gzip` connection carries gzip data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GzipDirection {
+    /// The client sends plain bytes and reads back a single gzip stream.
+    Compress,
+    /// The client sends one or more gzip members and reads back the plain
+    /// bytes they hold.
+    Decompress,
+}
+
+impl FromStr for GzipDirection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<GzipDirection, String> {
+        match s {
+            "compress" => Ok(GzipDirection::Compress),
+            "decompress" => Ok(GzipDirection::Decompress),
+            other => Err(format!(
+                "unsupported gzip direction {:?}, expected compress or decompress",
+                other
+            )),
+        }
+    }
+}
+
+/// The gzip state of one connection, which spans all of its reads, and the
+/// `EchoHandler` of `Mode::Gzip`.
+///
+/// When compressing, the transform's output for every chunk is compressed
+/// and sync-flushed, so everything echoed so far can be decoded before the
+/// stream ends; the gzip trailer follows once the peer closes its side.
+/// When decompressing, the transform runs on whatever plain bytes a chunk
+/// completed, and input that is not valid gzip, or ends in the middle of a
+/// member, fails with `ErrorKind::InvalidData`.
+pub struct Codec {
+    inner: Inner,
+    transform: Arc<Transform>,
+    received: bool,
+}
+
+enum Inner {
+    Compress(GzEncoder<Vec<u8>>),
+    Decompress(MultiGzDecoder<Vec<u8>>),
+}
+
+impl Codec {
+    pub fn new(direction: GzipDirection, transform: Arc<Transform>) -> Codec {
+        let inner = match direction {
+            GzipDirection::Compress => {
+                Inner::Compress(GzEncoder::new(Vec::new(), Compression::default()))
+            }
+            GzipDirection::Decompress => Inner::Decompress(MultiGzDecoder::new(Vec::new())),
+        };
+        Codec {
+            inner,
+            transform,
+            received: false,
+        }
+    }
+}
+
+impl EchoHandler for Codec {
+    fn process(&mut self, chunk: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        self.received = true;
+        match &mut self.inner {
+            Inner::Compress(encoder) => {
+                // Compressing into memory cannot fail.
+                let _ = encoder
+                    .write_all(&(self.transform)(chunk))
+                    .and_then(|()| encoder.flush());
+                out.append(encoder.get_mut());
+            }
+            Inner::Decompress(decoder) => {
+                decoder
+                    .write_all(chunk)
+                    .and_then(|()| decoder.flush())
+                    .map_err(invalid_input)?;
+                out.extend_from_slice(&(self.transform)(&mem::take(decoder.get_mut())));
+            }
+        }
+        Ok(())
+    }
+
+    // Ends the connection's gzip stream. A client that never sent anything
+    // gets nothing back, not an empty gzip stream.
+    fn finish(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        if !self.received {
+            return Ok(());
+        }
+        match &mut self.inner {
+            Inner::Compress(encoder) => {
+                let _ = encoder.try_finish();
+                out.append(encoder.get_mut());
+            }
+            Inner::Decompress(decoder) => {
+                decoder.try_finish().map_err(invalid_input)?;
+                out.extend_from_slice(&(self.transform)(&mem::take(decoder.get_mut())));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn invalid_input(e: io::Error) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("invalid gzip input, {}", e))
+}
@@ -0,0 +1,90 @@
+use crate::connection::{delay_echo, retry_interrupted, EchoError};
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+/// An echo behavior, which decides what to write back for every chunk a
+/// connection sends. The built-in modes that echo chunk by chunk are
+/// handlers too, see `modes`, and a custom one set with
+/// `ServerConfigBuilder::handler` serves a raw mode connection in place of
+/// the built-in echo.
+///
+/// Every connection gets a handler of its own, so it can keep state across
+/// the chunks that connection sends, like a running checksum or a partial
+/// message. Closures taking the same arguments as `process` are handlers
+/// too.
+pub trait EchoHandler: Send {
+    /// Appends to `out` what to echo for `input`, one chunk exactly as it
+    /// was read. `out` is empty on every call; leaving it empty echoes
+    /// nothing for the chunk. An error, such as for input the handler
+    /// cannot make sense of, closes the connection.
+    fn process(&mut self, input: &[u8], out: &mut Vec<u8>) -> io::Result<()>;
+
+    /// Appends to `out` what is left to echo once no more input follows,
+    /// because the peer closed its side or the connection's byte limit was
+    /// reached, or fails if the input ended in the middle of a message.
+    /// Does nothing by default.
+    fn finish(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        let _ = out;
+        Ok(())
+    }
+
+    /// Whether part of a message is held back until the rest of it arrives.
+    /// The idle timeout keeps running while it is, so a client trickling in
+    /// a message cannot hold on to a worker. `false` by default, which makes
+    /// every read count as activity.
+    fn holds_partial_message(&self) -> bool {
+        false
+    }
+}
+
+impl<F> EchoHandler for F
+where
+    F: FnMut(&[u8], &mut Vec<u8>) + Send,
+{
+    fn process(&mut self, input: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        self(input, out);
+        Ok(())
+    }
+}
+
+/// Creates the `EchoHandler` of a newly accepted connection.
+pub type HandlerFactory = dyn Fn() -> Box<dyn EchoHandler> + Send + Sync;
+
+/// Reads one chunk from `stream` and writes back what `handler` made of it
+/// after sleeping for `delay`, returning the number of bytes read. At the
+/// end of the stream it writes what `finish` leaves instead and returns 0.
+/// `out` is reused between calls to save allocations.
+pub fn echo_handled<S: Read + Write>(
+    stream: &mut S,
+    buffer: &mut [u8],
+    handler: &mut dyn EchoHandler,
+    out: &mut Vec<u8>,
+    delay: Duration,
+) -> Result<usize, EchoError> {
+    let read_bytes = retry_interrupted(|| stream.read(buffer)).map_err(EchoError::Read)?;
+    if read_bytes == 0 {
+        finish_handled(stream, handler, out)?;
+        return Ok(0);
+    }
+
+    out.clear();
+    handler
+        .process(&buffer[..read_bytes], out)
+        .map_err(EchoError::Read)?;
+    if !out.is_empty() {
+        delay_echo(delay);
+        stream.write_all(out).map_err(EchoError::Write)?;
+    }
+    Ok(read_bytes)
+}
+
+/// Writes what `handler` has left to echo once no more input follows.
+pub fn finish_handled<S: Write>(
+    stream: &mut S,
+    handler: &mut dyn EchoHandler,
+    out: &mut Vec<u8>,
+) -> Result<(), EchoError> {
+    out.clear();
+    handler.finish(out).map_err(EchoError::Read)?;
+    stream.write_all(out).map_err(EchoError::Write)
+}
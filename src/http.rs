@@ -0,0 +1,442 @@
+use crate::connection::{delay_echo, read_full, EchoError};
+use crate::transform::Transform;
+use std::io::{self, BufRead, ErrorKind, Read, Write};
+use std::str;
+use std::time::Duration;
+
+/// Longest request line plus headers accepted from a client by default.
+pub const MAX_HEAD_LEN: usize = 8 * 1024;
+/// Header fields a request may have by default.
+pub const MAX_HEADERS: usize = 100;
+
+/// How large a request head may grow before it is answered with a `431`.
+/// The length is enforced while the head is read, so an oversized one is
+/// never buffered whole.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeadLimits {
+    /// Bytes of the request line and header fields together.
+    pub max_len: usize,
+    /// Number of header fields.
+    pub max_headers: usize,
+}
+
+impl Default for HeadLimits {
+    fn default() -> HeadLimits {
+        HeadLimits {
+            max_len: MAX_HEAD_LEN,
+            max_headers: MAX_HEADERS,
+        }
+    }
+}
+
+/// What one request/response exchange consumed, and whether the client asked
+/// to keep the connection open for another request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exchange {
+    pub bytes_read: usize,
+    pub keep_alive: bool,
+}
+
+/// Reads one HTTP/1.x request from `stream` and answers it with a `200 OK`
+/// whose `message/http` body is `transform`'s output for the request exactly
+/// as it was received, written after sleeping for `delay`. Returns
+/// `bytes_read` of 0 if the peer closed the connection between requests.
+///
+/// The body is read according to `Content-Length`, up to `max_body_size`
+/// bytes. A malformed request is answered with a `400`, one whose body is
+/// too large with a `413`, one whose head exceeds `limits` with a `431`,
+/// and chunked bodies with a `501`, after which the request fails with
+/// `ErrorKind::InvalidData`.
+pub fn echo_request<S: BufRead + Write>(
+    stream: &mut S,
+    request: &mut Vec<u8>,
+    limits: HeadLimits,
+    max_body_size: usize,
+    transform: &Transform,
+    delay: Duration,
+) -> Result<Exchange, EchoError> {
+    request.clear();
+    let head = match read_head(stream, request, limits.max_len).map_err(EchoError::Read)? {
+        Some(head) => head,
+        None => {
+            return Ok(Exchange {
+                bytes_read: 0,
+                keep_alive: false,
+            })
+        }
+    };
+
+    let head = match head.and_then(|head_len| parse_head(&request[..head_len], limits.max_headers))
+    {
+        Ok(head) => head,
+        Err(rejection) => return Err(reject(stream, rejection)),
+    };
+    if head.content_length > max_body_size {
+        return Err(reject(
+            stream,
+            Rejection::new(
+                Status::PayloadTooLarge,
+                format!(
+                    "body of {} bytes exceeds the limit of {} bytes",
+                    head.content_length, max_body_size
+                ),
+            ),
+        ));
+    }
+
+    let head_len = request.len();
+    request.resize(head_len + head.content_length, 0);
+    let read = read_full(stream, &mut request[head_len..]).map_err(EchoError::Read)?;
+    if read < head.content_length {
+        return Err(EchoError::Read(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "connection closed after {} of {} body bytes",
+                read, head.content_length
+            ),
+        )));
+    }
+
+    delay_echo(delay);
+    let body = transform(request);
+    let response_head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: message/http\r\nContent-Length: {}\r\nConnection: {}\r\n\r\n",
+        body.len(),
+        if head.keep_alive { "keep-alive" } else { "close" }
+    );
+    stream
+        .write_all(response_head.as_bytes())
+        .and_then(|()| stream.write_all(&body))
+        .map_err(EchoError::Write)?;
+
+    Ok(Exchange {
+        bytes_read: request.len(),
+        keep_alive: head.keep_alive,
+    })
+}
+
+struct Head {
+    content_length: usize,
+    keep_alive: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Status {
+    BadRequest,
+    PayloadTooLarge,
+    HeaderFieldsTooLarge,
+    NotImplemented,
+}
+
+impl Status {
+    fn line(self) -> &'static str {
+        match self {
+            Status::BadRequest => "400 Bad Request",
+            Status::PayloadTooLarge => "413 Payload Too Large",
+            Status::HeaderFieldsTooLarge => "431 Request Header Fields Too Large",
+            Status::NotImplemented => "501 Not Implemented",
+        }
+    }
+}
+
+pub(crate) struct Rejection {
+    status: Status,
+    reason: String,
+}
+
+impl Rejection {
+    pub(crate) fn new(status: Status, reason: String) -> Rejection {
+        Rejection { status, reason }
+    }
+
+    pub(crate) fn bad_request(reason: &str) -> Rejection {
+        Rejection::new(Status::BadRequest, String::from(reason))
+    }
+}
+
+// Reads lines up to and including the empty line that ends the head, but no
+// more than `max_len` bytes, or returns `None` if the peer closed the
+// connection before sending anything. The inner result is the head's
+// length, or why it was rejected.
+pub(crate) fn read_head<R: BufRead>(
+    reader: &mut R,
+    request: &mut Vec<u8>,
+    max_len: usize,
+) -> io::Result<Option<Result<usize, Rejection>>> {
+    loop {
+        let limit = max_len - request.len();
+        let read = Read::take(&mut *reader, limit as u64).read_until(b'\n', request)?;
+
+        if read == 0 && request.is_empty() {
+            return Ok(None);
+        }
+        if request.ends_with(b"\r\n\r\n") || request.ends_with(b"\n\n") {
+            return Ok(Some(Ok(request.len())));
+        }
+        // A head that filled the limit without ending is too large even if
+        // the limit fell on a line boundary, where nothing more may be read.
+        if request.len() >= max_len {
+            return Ok(Some(Err(Rejection::new(
+                Status::HeaderFieldsTooLarge,
+                format!("request head exceeds {} bytes", max_len),
+            ))));
+        }
+        if read == 0 || !request.ends_with(b"\n") {
+            return Ok(Some(Err(Rejection::bad_request(
+                "connection closed in the middle of the request head",
+            ))));
+        }
+    }
+}
+
+/// The request line and header fields of a request head.
+pub(crate) struct Request<'a> {
+    pub(crate) method: &'a str,
+    pub(crate) target: &'a str,
+    pub(crate) version: &'a str,
+    pub(crate) headers: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> Request<'a> {
+    // Whether the comma-separated header `name` lists `token`, ignoring
+    // case, as in `Connection: keep-alive, Upgrade`.
+    pub(crate) fn has_token(&self, name: &str, token: &str) -> bool {
+        self.headers
+            .iter()
+            .filter(|(header, _)| header.eq_ignore_ascii_case(name))
+            .flat_map(|(_, value)| value.split(','))
+            .any(|option| option.trim().eq_ignore_ascii_case(token))
+    }
+
+    pub(crate) fn header(&self, name: &str) -> Option<&'a str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| *value)
+    }
+}
+
+// Parses a head `read_head` accepted, rejecting one with more than
+// `max_headers` header fields.
+pub(crate) fn parse_request(head: &[u8], max_headers: usize) -> Result<Request<'_>, Rejection> {
+    let head =
+        str::from_utf8(head).map_err(|_| Rejection::bad_request("request head is not UTF-8"))?;
+    let mut lines = head.lines();
+
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None)
+            if !method.is_empty() && !target.is_empty() =>
+        {
+            (method, target, version)
+        }
+        _ => return Err(Rejection::bad_request("malformed request line")),
+    };
+    if version != "HTTP/1.1" && version != "HTTP/1.0" {
+        return Err(Rejection::bad_request("unsupported HTTP version"));
+    }
+
+    let headers: Vec<_> = lines
+        .take_while(|line| !line.is_empty())
+        .map(|line| {
+            line.split_once(':')
+                .filter(|(name, _)| !name.is_empty() && !name.contains(char::is_whitespace))
+                .map(|(name, value)| (name, value.trim()))
+                .ok_or_else(|| Rejection::bad_request("malformed header line"))
+        })
+        .collect::<Result<_, _>>()?;
+    if headers.len() > max_headers {
+        return Err(Rejection::new(
+            Status::HeaderFieldsTooLarge,
+            format!(
+                "request has {} header fields, more than the limit of {}",
+                headers.len(),
+                max_headers
+            ),
+        ));
+    }
+
+    Ok(Request {
+        method,
+        target,
+        version,
+        headers,
+    })
+}
+
+fn parse_head(head: &[u8], max_headers: usize) -> Result<Head, Rejection> {
+    let request = parse_request(head, max_headers)?;
+
+    // HTTP/1.1 keeps connections open unless told otherwise, HTTP/1.0 only
+    // when asked to.
+    let mut keep_alive = request.version == "HTTP/1.1";
+    let mut content_length = None;
+
+    for &(name, value) in &request.headers {
+        if name.eq_ignore_ascii_case("content-length") {
+            let length = value
+                .parse::<usize>()
+                .map_err(|_| Rejection::bad_request("invalid Content-Length"))?;
+            if content_length.is_some_and(|previous| previous != length) {
+                return Err(Rejection::bad_request("conflicting Content-Length headers"));
+            }
+            content_length = Some(length);
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            return Err(Rejection::new(
+                Status::NotImplemented,
+                String::from("Transfer-Encoding is not supported"),
+            ));
+        } else if name.eq_ignore_ascii_case("connection") {
+            for option in value.split(',').map(str::trim) {
+                if option.eq_ignore_ascii_case("close") {
+                    keep_alive = false;
+                } else if option.eq_ignore_ascii_case("keep-alive") {
+                    keep_alive = true;
+                }
+            }
+        }
+    }
+
+    Ok(Head {
+        content_length: content_length.unwrap_or(0),
+        keep_alive,
+    })
+}
+
+// Answers a request that cannot be echoed, then fails it so the connection
+// is closed; whatever the client sent after it cannot be trusted to start a
+// new request.
+pub(crate) fn reject<W: Write>(stream: &mut W, rejection: Rejection) -> EchoError {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        rejection.status.line(),
+        rejection.reason.len(),
+        rejection.reason
+    );
+    if let Err(e) = stream.write_all(response.as_bytes()) {
+        return EchoError::Write(e);
+    }
+
+    EchoError::Read(io::Error::new(
+        ErrorKind::InvalidData,
+        format!("rejected HTTP request, {}", rejection.reason),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockStream;
+    use std::borrow::Cow;
+
+    fn exchange(request: &[u8], limits: HeadLimits, max_body_size: usize) -> (String, bool) {
+        let mut stream = MockStream::new(&[request]);
+        let result = echo_request(
+            &mut stream,
+            &mut Vec::new(),
+            limits,
+            max_body_size,
+            &|bytes: &[u8]| Cow::Borrowed(bytes),
+            Duration::ZERO,
+        );
+        (String::from_utf8(stream.output).unwrap(), result.is_ok())
+    }
+
+    #[test]
+    fn echoes_the_request_as_the_body() {
+        let request = b"GET /hello HTTP/1.1\r\nHost: example\r\nX-Test: yes\r\n\r\n";
+        let (response, ok) = exchange(request, HeadLimits::default(), 1024);
+
+        assert!(ok);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Connection: keep-alive\r\n"));
+        let body = response.split_once("\r\n\r\n").unwrap().1;
+        assert_eq!(body.as_bytes(), &request[..]);
+        assert!(body.contains("X-Test: yes"));
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        let (response, ok) = exchange(b"GET\r\n\r\n", HeadLimits::default(), 1024);
+
+        assert!(!ok);
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[test]
+    fn oversized_header_block_gets_a_431() {
+        let limits = HeadLimits {
+            max_len: 64,
+            ..HeadLimits::default()
+        };
+        let request = format!("GET / HTTP/1.1\r\nX-Padding: {}\r\n\r\n", "a".repeat(100));
+        let (response, ok) = exchange(request.as_bytes(), limits, 1024);
+
+        assert!(!ok);
+        assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+    }
+
+    #[test]
+    fn too_many_header_fields_get_a_431() {
+        let limits = HeadLimits {
+            max_headers: 2,
+            ..HeadLimits::default()
+        };
+        let request = b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n";
+        let (response, ok) = exchange(request, limits, 1024);
+
+        assert!(!ok);
+        assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+    }
+
+    #[test]
+    fn oversized_content_length_gets_a_413_before_the_body_is_read() {
+        let request = b"POST / HTTP/1.1\r\nContent-Length: 1025\r\n\r\n";
+        let (response, ok) = exchange(request, HeadLimits::default(), 1024);
+
+        assert!(!ok);
+        assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+    }
+
+    #[test]
+    fn head_filling_the_limit_on_a_line_boundary_is_rejected() {
+        let head = b"GET / HTTP/1.1\r\nX-A: bbbbbbbbb\r\n";
+        assert_eq!(head.len(), 32);
+        // More of the head follows, but may not be read past the limit.
+        let mut stream = MockStream::new(&[head, b"X-B: c\r\n\r\n"]);
+
+        let result = read_head(&mut stream, &mut Vec::new(), head.len()).unwrap();
+
+        match result {
+            Some(Err(rejection)) => {
+                assert!(matches!(rejection.status, Status::HeaderFieldsTooLarge))
+            }
+            _ => panic!("expected the head to be rejected"),
+        }
+    }
+
+    #[test]
+    fn head_ending_exactly_at_the_limit_is_accepted() {
+        let head = b"GET / HTTP/1.1\r\nX-A: bbbbbbb\r\n\r\n";
+
+        let result = read_head(&mut MockStream::new(&[head]), &mut Vec::new(), head.len());
+
+        assert!(matches!(result, Ok(Some(Ok(len))) if len == head.len()));
+    }
+
+    #[test]
+    fn head_cut_short_by_the_peer_is_a_bad_request() {
+        let mut stream = MockStream::new(&[b"GET / HTTP/1.1\r\nHost: x\r\n"]);
+
+        let result = read_head(&mut stream, &mut Vec::new(), MAX_HEAD_LEN).unwrap();
+
+        assert!(matches!(
+            result,
+            Some(Err(Rejection {
+                status: Status::BadRequest,
+                ..
+            }))
+        ));
+    }
+}
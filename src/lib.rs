@@ -0,0 +1,65 @@
+//! A small multi-threaded TCP echo server.
+//!
+//! [`server::run`] serves connections according to a [`ServerConfig`], and
+//! [`Server`] splits that into binding and serving for embedders that need to
+//! know the bound address first. [`pool::ThreadPool`] runs connection handlers
+//! on a fixed set of worker threads, while [`connection::handle`] echoes
+//! everything read from a stream back to it until the peer closes the
+//! connection; with [`Runtime::EventLoop`] raw echoes are instead multiplexed
+//! on a few non-blocking event loops, so open connections no longer need a
+//! thread each. UDP peers are served by [`datagram::handle_datagram`], one
+//! datagram at a time, and [`tls`] loads the configuration used to serve TCP
+//! connections over TLS. Log lines about a connection can be told apart by the
+//! id [`span::current`] returns while they are written. Echoed bytes can be
+//! rewritten on the way back through a [`transform::Transform`].
+
+pub mod access;
+pub mod bench;
+pub mod broadcast;
+pub mod buffered;
+pub mod connection;
+pub mod control;
+pub mod datagram;
+pub mod duplex;
+#[cfg(unix)]
+mod event_loop;
+pub mod events;
+pub mod gzip;
+pub mod handler;
+pub mod http;
+pub mod limit;
+pub mod metrics;
+pub mod mirror;
+pub mod modes;
+pub mod pool;
+pub mod proxy;
+pub mod record;
+pub mod server;
+pub mod shutdown;
+pub mod socket;
+pub mod span;
+pub mod stats;
+#[cfg(test)]
+mod testing;
+pub mod tls;
+pub mod transform;
+pub mod websocket;
+
+pub use access::AccessList;
+pub use bench::{BenchConfig, BenchReport};
+pub use connection::{
+    chargen, configure_tcp_stream, discard, handle, handle_duplex, ConnectionStats, EchoError,
+    HalfClose,
+};
+pub use datagram::handle_datagram;
+pub use events::{Callback, Events};
+pub use gzip::GzipDirection;
+pub use handler::{EchoHandler, HandlerFactory};
+pub use limit::{ConnectionLimit, ConnectionPermit, IpPermit, PerIpLimit, RateLimit};
+pub use mirror::{Mirror, Mirrored};
+pub use pool::{ExecuteError, OverflowPolicy, Priority, ThreadPool};
+pub use record::{Recorded, Recorder, Recording};
+pub use server::{run, Mode, Protocol, Runtime, Server, ServerConfig, ServerConfigBuilder};
+pub use shutdown::{Shutdown, ShutdownMode};
+pub use stats::{Outcome, Outcomes, ServerStats};
+pub use transform::Transform;
@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Caps how many connections may be handled at the same time.
+#[derive(Clone)]
+pub struct ConnectionLimit {
+    active: Arc<AtomicUsize>,
+    max: usize,
+}
+
+impl ConnectionLimit {
+    pub fn new(max: usize) -> ConnectionLimit {
+        ConnectionLimit {
+            active: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    /// Reserves a slot for a new connection, or returns `None` when `max`
+    /// connections are already active.
+    pub fn try_acquire(&self) -> Option<ConnectionPermit> {
+        self.active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |active| {
+                if active < self.max {
+                    Some(active + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()
+            .map(|_| ConnectionPermit {
+                active: Arc::clone(&self.active),
+            })
+    }
+
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+}
+
+/// Holds a slot of a `ConnectionLimit` and frees it when dropped, including
+/// when the connection handler unwinds.
+pub struct ConnectionPermit {
+    active: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Caps how many connections a single source address may have open at the
+/// same time. Only addresses with open connections have an entry.
+#[derive(Clone)]
+pub struct PerIpLimit {
+    active: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    max: usize,
+}
+
+impl PerIpLimit {
+    pub fn new(max: usize) -> PerIpLimit {
+        PerIpLimit {
+            active: Arc::new(Mutex::new(HashMap::new())),
+            max,
+        }
+    }
+
+    /// Reserves a slot for a new connection from `ip`, or returns `None` when
+    /// `max` connections from it are already open.
+    pub fn try_acquire(&self, ip: IpAddr) -> Option<IpPermit> {
+        let mut active = lock(&self.active);
+        let count = active.get(&ip).copied().unwrap_or(0);
+        if count >= self.max {
+            return None;
+        }
+        active.insert(ip, count + 1);
+        Some(IpPermit {
+            active: Arc::clone(&self.active),
+            ip,
+        })
+    }
+
+    pub fn active(&self, ip: IpAddr) -> usize {
+        lock(&self.active).get(&ip).copied().unwrap_or(0)
+    }
+}
+
+/// Holds a slot of a `PerIpLimit` and frees it when dropped, including when
+/// the connection handler unwinds.
+pub struct IpPermit {
+    active: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    ip: IpAddr,
+}
+
+impl Drop for IpPermit {
+    fn drop(&mut self) {
+        let mut active = lock(&self.active);
+        if let Some(count) = active.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.ip);
+            }
+        }
+    }
+}
+
+// The counts stay consistent even if a thread panicked while holding the
+// lock, since every update is a single step.
+fn lock(active: &Mutex<HashMap<IpAddr, usize>>) -> MutexGuard<'_, HashMap<IpAddr, usize>> {
+    active
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Caps how many connections may be accepted per second.
+///
+/// This is a token bucket holding up to one second worth of connections,
+/// tracked as the instant at which the bucket will be full again, so a burst
+/// of up to `per_second` connections is admitted at once and further ones
+/// at the steady rate. The whole state is a single atomic. A rate of zero
+/// admits every connection.
+pub struct RateLimit {
+    start: Instant,
+    interval: u64,
+    burst: u64,
+    full_at: AtomicU64,
+}
+
+impl RateLimit {
+    pub fn new(per_second: u32) -> RateLimit {
+        let second = Duration::from_secs(1).as_nanos() as u64;
+        let interval = second.checked_div(u64::from(per_second)).unwrap_or(0);
+        RateLimit {
+            start: Instant::now(),
+            interval,
+            burst: second - interval,
+            full_at: AtomicU64::new(0),
+        }
+    }
+
+    /// Takes a token for a new connection, or returns `false` when the rate
+    /// has been exceeded.
+    pub fn try_acquire(&self) -> bool {
+        let now = self.start.elapsed().as_nanos() as u64;
+        self.full_at
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |full_at| {
+                let full_at = full_at.max(now);
+                if full_at - now <= self.burst {
+                    Some(full_at + self.interval)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+}
+
+/// Caps the byte rate of a single connection.
+///
+/// This is a token bucket holding up to one second worth of bytes, tracked
+/// as the instant at which the bucket will be full again. It starts out
+/// empty, so a busy connection is held to `bytes_per_second` from its first
+/// echo and only a connection that has gone quiet can burst. A rate of zero
+/// never throttles.
+pub struct Throttle {
+    bytes_per_second: u64,
+    full_at: Instant,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_second: u64) -> Throttle {
+        Throttle {
+            bytes_per_second,
+            full_at: Instant::now() + Duration::from_secs(1),
+        }
+    }
+
+    /// Takes `bytes` tokens out of the bucket, sleeping for as long as it
+    /// takes the bucket to pay back any deficit.
+    pub fn consume(&mut self, bytes: u64) {
+        if self.bytes_per_second == 0 {
+            return;
+        }
+
+        let burst = Duration::from_secs(1);
+        let cost = u128::from(bytes) * burst.as_nanos() / u128::from(self.bytes_per_second);
+        let now = Instant::now();
+        self.full_at =
+            self.full_at.max(now) + Duration::from_nanos(u64::try_from(cost).unwrap_or(u64::MAX));
+
+        if let Some(deficit) = (self.full_at - now).checked_sub(burst) {
+            thread::sleep(deficit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn per_ip_slots_are_capped_and_their_entry_goes_with_the_last_one() {
+        let limit = PerIpLimit::new(2);
+        let (ip, other) = (
+            Ipv4Addr::LOCALHOST.into(),
+            Ipv4Addr::new(10, 0, 0, 1).into(),
+        );
+
+        let first = limit.try_acquire(ip).unwrap();
+        let second = limit.try_acquire(ip).unwrap();
+        assert!(limit.try_acquire(ip).is_none());
+        // Every address has a cap of its own.
+        let elsewhere = limit.try_acquire(other).unwrap();
+
+        drop(first);
+        assert_eq!(limit.active(ip), 1);
+        drop((second, elsewhere));
+        assert!(lock(&limit.active).is_empty());
+    }
+}
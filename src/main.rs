@@ -1,140 +1,981 @@
-use std::io::{Read, Result as IoResult, Write};
-use std::net::{TcpListener, TcpStream};
-use std::sync::{mpsc, Arc, LockResult, Mutex, MutexGuard};
+use echo_server_rs::bench::{self, BenchConfig};
+use echo_server_rs::pool;
+use echo_server_rs::server::{self, Mode, Protocol, Server, ServerConfig, ServerConfigBuilder};
+use echo_server_rs::socket::{Keepalive, ListenerOptions};
+use echo_server_rs::span;
+use echo_server_rs::transform;
+use env_logger::fmt::Formatter;
+use env_logger::Env;
+use ipnet::IpNet;
+use log::{info, Record};
+use serde::Deserialize;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fmt::Display;
+use std::fs;
+use std::io::{self, Write};
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::thread;
+use std::time::Duration;
 
-fn main() {
-    println!("Started: Echo Server!");
+const DEFAULT_HOST: &str = "127.0.0.1";
 
-    let listener = TcpListener::bind("127.0.0.1:8080").unwrap();
-    let thread_pool = ThreadPool::new(8);
+// The default format, with the id of the connection a line is about added
+// after the target, so the output of concurrent connections can be told
+// apart.
+fn format_record(buf: &mut Formatter, record: &Record) -> io::Result<()> {
+    let level = buf.default_level_style(record.level());
+    write!(
+        buf,
+        "[{} {}{:<5}{:#} {}",
+        buf.timestamp(),
+        level,
+        record.level(),
+        level,
+        record.target()
+    )?;
+    if let Some(id) = span::current() {
+        write!(buf, " conn={}", id)?;
+    }
+    writeln!(buf, "] {}", record.args())
+}
 
-    for tcp in listener.incoming() {
-        match tcp {
-            Ok(stream) => {
-                thread_pool.execute(|| {
-                    handle(stream);
-                });
-            }
-            Err(e) => {
-                println!("Could not establish connection due to: {:?}", e);
-            }
+/// Why the server did not start or stopped early. Returned from `main`, it
+/// is printed to stderr as `Error: <message>` and the process exits with a
+/// non-zero status.
+enum Failure {
+    Args(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Args(e) => write!(f, "Invalid arguments: {}", e),
+            Failure::Io(e) => write!(f, "{}", e),
         }
     }
 }
 
-fn handle(mut stream: TcpStream) {
-    let mut buffer = [0u8; 1024];
+// The standard library prints the error `main` returns with `Debug`, which
+// should read as a message rather than a struct dump.
+impl fmt::Debug for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
 
-    loop {
-        match echo(&mut stream, &mut buffer) {
-            Ok(read_bytes) if read_bytes == 0 => {
-                println!("All bytes were read!");
-                break;
-            }
-            Err(e) => {
-                println!("Stopping further processing of stream due to: {:?}", e);
-                break;
+impl Error for Failure {}
+
+fn main() -> Result<(), Failure> {
+    env_logger::Builder::from_env(Env::default().default_filter_or("info"))
+        .format(format_record)
+        .init();
+    info!("Started: Echo Server!");
+
+    let result = parse_args(env::args().skip(1))
+        .map_err(Failure::Args)
+        .and_then(|(config, selftest)| {
+            match selftest {
+                Some(bench) => run_selftest(config, &bench),
+                None => server::run(config),
             }
-            _ => {}
-        }
+            .map_err(Failure::Io)
+        });
+    if result.is_ok() {
+        info!("Stopped: Echo Server!");
     }
+    result
 }
 
-fn echo(stream: &mut TcpStream, buffer: &mut [u8]) -> IoResult<usize> {
-    let read_bytes = stream.read(buffer)?;
-    stream.write(&buffer[0..read_bytes])?;
-    Ok(read_bytes)
+// Serves `config` on a loopback port for as long as `bench` keeps clients
+// echoing over it, then prints what they measured and shuts down.
+fn run_selftest(config: ServerConfig, bench: &BenchConfig) -> io::Result<()> {
+    let server = Server::bind(config)?;
+    let addr = server.local_addr()?;
+    let shutdown = server.shutdown();
+    info!(
+        "Running self-test with {} connections sending {} bytes at a time for {:?}",
+        bench.connections, bench.payload_size, bench.duration
+    );
+    let serving = thread::spawn(move || server.run());
+
+    let report = bench::run(addr, bench);
+    shutdown.request();
+    let served = serving
+        .join()
+        .unwrap_or_else(|_| Err(io::Error::other("the server panicked")));
+
+    let report =
+        report.map_err(|e| io::Error::new(e.kind(), format!("Self-test failed due to: {}", e)))?;
+    println!("{}", report);
+    served
 }
 
-enum Operation {
-    Execute(Task),
-    Terminate,
+// Returns the benchmark to run instead of serving for `--selftest`.
+fn parse_args<I>(args: I) -> Result<(ServerConfig, Option<BenchConfig>), String>
+where
+    I: Iterator<Item = String>,
+{
+    let args: Vec<String> = args.collect();
+    let mut options = Options::new();
+
+    if let Some(index) = args.iter().position(|arg| arg == "--config") {
+        let path = args
+            .get(index + 1)
+            .ok_or_else(|| String::from("missing value for --config"))?;
+        load_file(&mut options, Path::new(path), &args)?;
+    }
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            next_value(&mut args, &arg)?;
+            continue;
+        }
+        options.apply(&arg, &mut args)?;
+    }
+    options.build()
 }
 
-type Task = Box<dyn FnOnce() + Send + 'static>;
+// Applies a TOML config file. Its keys are the command line flags without
+// their leading dashes and with underscores for the inner ones, such as
+// `buffer_size = 4096` for `--buffer-size 4096`, typed like the values of
+// `FileConfig`, and `FileConfig::flags` lists which flag each one sets.
+// Keys whose flag is also given in `args` are skipped, so the command line
+// overrides the file, repeated flags included.
+fn load_file(options: &mut Options, path: &Path, args: &[String]) -> Result<(), String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("could not read config file {}: {}", path.display(), e))?;
+    let file: FileConfig = toml::from_str(&contents).map_err(|e| {
+        match e.span().and_then(|span| key_at(&contents, span.start)) {
+            Some(key) => format!("{}, in key {:?} of {}", e.message(), key, path.display()),
+            None => format!("invalid config file {}: {}", path.display(), e),
+        }
+    })?;
 
-pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: mpsc::Sender<Operation>,
+    for (flag, value) in file.flags() {
+        if args.iter().any(|arg| arg == flag) {
+            continue;
+        }
+        let key = flag.trim_start_matches('-').replace('-', "_");
+        options
+            .apply(flag, &mut value.into_iter())
+            .map_err(|e| format!("{}, in key {:?} of {}", e, key, path.display()))?;
+    }
+    Ok(())
 }
 
-impl ThreadPool {
-    pub fn new(size: usize) -> ThreadPool {
-        assert!(size > 0);
+// The key of the `key = value` line of `contents` that `offset` falls on.
+fn key_at(contents: &str, offset: usize) -> Option<&str> {
+    let start = contents[..offset]
+        .rfind('\n')
+        .map_or(0, |newline| newline + 1);
+    let (key, _) = contents[start..].split_once('=')?;
+    Some(key.trim()).filter(|key| !key.is_empty() && !key.contains('\n'))
+}
 
-        let (sender, receiver) = mpsc::channel();
-        let receiver: SharedReceiver = Arc::new(Mutex::new(receiver));
+// A config file, with every key optional. Values are checked against the
+// type of their key while the file is parsed, and against the same rules as
+// their flag once applied; the modes, paths and addresses are strings that
+// are parsed like on the command line.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    verbose: Option<bool>,
+    host: Option<String>,
+    port: Option<Repeated<u16>>,
+    buffer_size: Option<usize>,
+    read_timeout: Option<u64>,
+    write_timeout: Option<u64>,
+    idle_timeout: Option<u64>,
+    max_lifetime_secs: Option<u64>,
+    min_throughput: Option<u64>,
+    min_throughput_window: Option<u64>,
+    nodelay: Option<bool>,
+    shutdown_timeout: Option<u64>,
+    pool_size: Option<usize>,
+    min_workers: Option<usize>,
+    worker_stack_size: Option<usize>,
+    worker_keep_alive: Option<u64>,
+    accept_threads: Option<usize>,
+    runtime: Option<String>,
+    shutdown_mode: Option<String>,
+    queue_capacity: Option<usize>,
+    queue_overflow: Option<String>,
+    queue_timeout: Option<u64>,
+    protocol: Option<String>,
+    mode: Option<String>,
+    allow: Option<Repeated<String>>,
+    deny: Option<Repeated<String>>,
+    rcvbuf: Option<usize>,
+    sndbuf: Option<usize>,
+    ttl: Option<u8>,
+    linger_secs: Option<u64>,
+    keepalive_secs: Option<u64>,
+    keepalive_interval_secs: Option<u64>,
+    keepalive_retries: Option<u32>,
+    max_connections: Option<usize>,
+    busy_message: Option<String>,
+    mirror: Option<String>,
+    max_per_ip: Option<usize>,
+    max_connection_rate: Option<u32>,
+    max_connection_bytes: Option<u64>,
+    max_bps: Option<u64>,
+    delimiter: Option<Byte>,
+    reply: Option<String>,
+    reply_file: Option<String>,
+    reply_once: Option<bool>,
+    gzip_direction: Option<String>,
+    max_frame_size: Option<usize>,
+    max_header_size: Option<usize>,
+    max_headers: Option<usize>,
+    proxy_protocol: Option<bool>,
+    banner: Option<String>,
+    tls: Option<bool>,
+    cert: Option<String>,
+    key: Option<String>,
+    client_ca: Option<String>,
+    unix: Option<String>,
+    backlog: Option<u32>,
+    dual_stack: Option<bool>,
+    reuse_port: Option<bool>,
+    pin_workers: Option<bool>,
+    selftest: Option<bool>,
+    duplex: Option<bool>,
+    selftest_connections: Option<usize>,
+    selftest_payload: Option<usize>,
+    selftest_duration: Option<u64>,
+    worker_core: Option<Repeated<usize>>,
+    transform: Option<String>,
+    record: Option<String>,
+    control: Option<String>,
+    metrics_addr: Option<String>,
+    health_max_pending: Option<usize>,
+    echo_delay_ms: Option<u64>,
+    drop_rate: Option<f64>,
+    echo_jitter_ms: Option<String>,
+    stats_interval: Option<u64>,
+}
 
-        let workers: Vec<Worker> = (0..size)
-            .map(|id| Worker::new(id, Arc::clone(&receiver)))
-            .collect();
-        ThreadPool { workers, sender }
-    }
+// A key standing for a repeatable flag, given once like `port = 7` or as an
+// array like `port = [7, 8]`.
+#[derive(Deserialize)]
+#[serde(untagged, expecting = "a value or an array of values")]
+enum Repeated<T> {
+    One(T),
+    Many(Vec<T>),
+}
 
-    pub fn execute<F>(&self, f: F)
-    where
-        F: FnOnce() + Send + 'static,
-    {
-        let task = Box::new(f);
+// A byte given as a number like `delimiter = 10` or spelled out like on the
+// command line, `delimiter = "0x0a"`.
+#[derive(Deserialize)]
+#[serde(untagged, expecting = "a byte value or a string spelling one")]
+enum Byte {
+    Number(u8),
+    Spelled(String),
+}
 
-        if let Err(e) = self.sender.send(Operation::Execute(task)) {
-            println!(
-                "Request rejected, could not enqueue new task, reason: {:?}",
-                e
-            );
+impl fmt::Display for Byte {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Byte::Number(byte) => write!(f, "{}", byte),
+            Byte::Spelled(byte) => f.write_str(byte),
         }
     }
 }
 
-impl Drop for ThreadPool {
-    fn drop(&mut self) {
-        println!("Terminating thread pool responsible for request processing");
+impl FileConfig {
+    // The flags the file sets, each with its value unless it is a switch,
+    // once for every value of a repeated one. Switches set to `false` are
+    // left out.
+    fn flags(self) -> Vec<(&'static str, Option<String>)> {
+        let mut flags = Flags(Vec::new());
+        flags.switch("--verbose", self.verbose);
+        flags.value("--host", self.host);
+        flags.repeated("--port", self.port);
+        flags.value("--buffer-size", self.buffer_size);
+        flags.value("--read-timeout", self.read_timeout);
+        flags.value("--write-timeout", self.write_timeout);
+        flags.value("--idle-timeout", self.idle_timeout);
+        flags.value("--max-lifetime-secs", self.max_lifetime_secs);
+        flags.value("--min-throughput", self.min_throughput);
+        flags.value("--min-throughput-window", self.min_throughput_window);
+        flags.value("--nodelay", self.nodelay);
+        flags.value("--shutdown-timeout", self.shutdown_timeout);
+        flags.value("--pool-size", self.pool_size);
+        flags.value("--min-workers", self.min_workers);
+        flags.value("--worker-stack-size", self.worker_stack_size);
+        flags.value("--worker-keep-alive", self.worker_keep_alive);
+        flags.value("--accept-threads", self.accept_threads);
+        flags.value("--runtime", self.runtime);
+        flags.value("--shutdown-mode", self.shutdown_mode);
+        flags.value("--queue-capacity", self.queue_capacity);
+        flags.value("--queue-overflow", self.queue_overflow);
+        flags.value("--queue-timeout", self.queue_timeout);
+        flags.value("--protocol", self.protocol);
+        flags.value("--mode", self.mode);
+        flags.repeated("--allow", self.allow);
+        flags.repeated("--deny", self.deny);
+        flags.value("--rcvbuf", self.rcvbuf);
+        flags.value("--sndbuf", self.sndbuf);
+        flags.value("--ttl", self.ttl);
+        flags.value("--linger-secs", self.linger_secs);
+        flags.value("--keepalive-secs", self.keepalive_secs);
+        flags.value("--keepalive-interval-secs", self.keepalive_interval_secs);
+        flags.value("--keepalive-retries", self.keepalive_retries);
+        flags.value("--max-connections", self.max_connections);
+        flags.value("--busy-message", self.busy_message);
+        flags.value("--mirror", self.mirror);
+        flags.value("--max-per-ip", self.max_per_ip);
+        flags.value("--max-connection-rate", self.max_connection_rate);
+        flags.value("--max-connection-bytes", self.max_connection_bytes);
+        flags.value("--max-bps", self.max_bps);
+        flags.value("--delimiter", self.delimiter);
+        flags.value("--reply", self.reply);
+        flags.value("--reply-file", self.reply_file);
+        flags.switch("--reply-once", self.reply_once);
+        flags.value("--gzip-direction", self.gzip_direction);
+        flags.value("--max-frame-size", self.max_frame_size);
+        flags.value("--max-header-size", self.max_header_size);
+        flags.value("--max-headers", self.max_headers);
+        flags.switch("--proxy-protocol", self.proxy_protocol);
+        flags.value("--banner", self.banner);
+        flags.switch("--tls", self.tls);
+        flags.value("--cert", self.cert);
+        flags.value("--key", self.key);
+        flags.value("--client-ca", self.client_ca);
+        flags.value("--unix", self.unix);
+        flags.value("--backlog", self.backlog);
+        flags.switch("--dual-stack", self.dual_stack);
+        flags.switch("--reuse-port", self.reuse_port);
+        flags.switch("--pin-workers", self.pin_workers);
+        flags.switch("--selftest", self.selftest);
+        flags.switch("--duplex", self.duplex);
+        flags.value("--selftest-connections", self.selftest_connections);
+        flags.value("--selftest-payload", self.selftest_payload);
+        flags.value("--selftest-duration", self.selftest_duration);
+        flags.repeated("--worker-core", self.worker_core);
+        flags.value("--transform", self.transform);
+        flags.value("--record", self.record);
+        flags.value("--control", self.control);
+        flags.value("--metrics-addr", self.metrics_addr);
+        flags.value("--health-max-pending", self.health_max_pending);
+        flags.value("--echo-delay-ms", self.echo_delay_ms);
+        flags.value("--drop-rate", self.drop_rate);
+        flags.value("--echo-jitter-ms", self.echo_jitter_ms);
+        flags.value("--stats-interval", self.stats_interval);
+        flags.0
+    }
+}
+
+struct Flags(Vec<(&'static str, Option<String>)>);
 
-        for _ in 0..self.workers.len() {
-            self.sender.send(Operation::Terminate).unwrap();
+impl Flags {
+    fn value<T: Display>(&mut self, flag: &'static str, value: Option<T>) {
+        if let Some(value) = value {
+            self.0.push((flag, Some(value.to_string())));
         }
+    }
 
-        for worker in &mut self.workers {
-            if let Some(worker) = worker.thread.take() {
-                worker.join().unwrap();
-            }
+    fn switch(&mut self, flag: &'static str, on: Option<bool>) {
+        if on == Some(true) {
+            self.0.push((flag, None));
+        }
+    }
+
+    fn repeated<T: Display>(&mut self, flag: &'static str, values: Option<Repeated<T>>) {
+        let values = match values {
+            Some(Repeated::One(value)) => vec![value],
+            Some(Repeated::Many(values)) => values,
+            None => Vec::new(),
+        };
+        for value in values {
+            self.value(flag, Some(value));
         }
     }
 }
 
-struct Worker {
-    id: usize,
-    thread: Option<thread::JoinHandle<()>>,
+// Settings collected from the command line and the config file, validated
+// together once every flag has been seen.
+struct Options {
+    config: ServerConfigBuilder,
+    host: String,
+    ports: Vec<u16>,
+    protocol: Protocol,
+    tls: bool,
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    client_ca: Option<PathBuf>,
+    unix: Option<PathBuf>,
+    listener: ListenerOptions,
+    transform_name: String,
+    pin_workers: bool,
+    worker_cores: Vec<usize>,
+    mirror: bool,
+    keepalive: Option<Duration>,
+    keepalive_interval: Option<Duration>,
+    keepalive_retries: Option<u32>,
+    selftest: bool,
+    bench: BenchConfig,
 }
 
-type SharedReceiver = Arc<Mutex<mpsc::Receiver<Operation>>>;
+impl Options {
+    fn new() -> Options {
+        Options {
+            config: ServerConfig::builder(),
+            host: String::from(DEFAULT_HOST),
+            ports: Vec::new(),
+            protocol: Protocol::Tcp,
+            tls: false,
+            cert: None,
+            key: None,
+            client_ca: None,
+            unix: None,
+            listener: ListenerOptions::default(),
+            transform_name: String::from("identity"),
+            pin_workers: false,
+            worker_cores: Vec::new(),
+            mirror: false,
+            keepalive: None,
+            keepalive_interval: None,
+            keepalive_retries: None,
+            selftest: false,
+            bench: BenchConfig::default(),
+        }
+    }
 
-impl Worker {
-    pub fn new(id: usize, receiver: SharedReceiver) -> Worker {
-        let thread = thread::spawn(move || loop {
-            if let Some(op_res) = receiver.lock().ok().map(|r| r.recv()) {
-                match op_res {
-                    Ok(operation) => match operation {
-                        Operation::Execute(task) => {
-                            println!("Worker {} starts processing new request", id);
-                            task();
-                        }
-                        Operation::Terminate => {
-                            println!("Worker {} received terminate signal", id);
-                            break;
-                        }
-                    },
-                    Err(e) => {
-                        println!("Could not establish connection due to: {:?}", e);
-                    }
+    // Applies `arg`, pulling its value from `args` if it takes one.
+    fn apply<I>(&mut self, arg: &str, args: &mut I) -> Result<(), String>
+    where
+        I: Iterator<Item = String>,
+    {
+        let mut config = mem::replace(&mut self.config, ServerConfig::builder());
+        match arg {
+            "-v" | "--verbose" => config = config.log_tasks(true),
+            "--host" => self.host = next_value(args, arg)?,
+            "--port" => self.ports.push(parse_value(args, arg)?),
+            "--buffer-size" => {
+                let buffer_size = parse_value(args, arg)?;
+                if buffer_size < 1 {
+                    return Err(String::from("buffer size must be at least 1 byte"));
                 }
+                config = config.buffer_size(buffer_size);
             }
-        });
+            "--read-timeout" => {
+                config = config.read_timeout(duration_from_secs(parse_value(args, arg)?))
+            }
+            "--write-timeout" => {
+                config = config.write_timeout(duration_from_secs(parse_value(args, arg)?))
+            }
+            "--idle-timeout" => {
+                config = config.idle_timeout(duration_from_secs(parse_value(args, arg)?))
+            }
+            "--max-lifetime-secs" => {
+                config = config.max_lifetime(duration_from_secs(parse_value(args, arg)?))
+            }
+            "--min-throughput" => config = config.min_throughput(parse_value(args, arg)?),
+            "--min-throughput-window" => {
+                let secs = parse_value(args, arg)?;
+                if secs < 1 {
+                    return Err(String::from(
+                        "minimum throughput window must be at least 1 second",
+                    ));
+                }
+                config = config.min_throughput_window(Duration::from_secs(secs));
+            }
+            "--nodelay" => config = config.nodelay(parse_value(args, arg)?),
+            "--shutdown-timeout" => {
+                config = config.shutdown_timeout(duration_from_secs(parse_value(args, arg)?))
+            }
+            "--pool-size" => {
+                let pool_size = parse_value(args, arg)?;
+                if pool_size < 1 {
+                    return Err(String::from("pool size must be at least 1 worker"));
+                }
+                config = config.pool_size(pool_size);
+            }
+            "--min-workers" => {
+                let min_workers = parse_value(args, arg)?;
+                if min_workers < 1 {
+                    return Err(String::from("minimum workers must be at least 1"));
+                }
+                config = config.min_workers(min_workers);
+            }
+            "--worker-stack-size" => {
+                let bytes = parse_value(args, arg)?;
+                if bytes < pool::MIN_STACK_SIZE {
+                    return Err(format!(
+                        "worker stack size must be at least {} bytes",
+                        pool::MIN_STACK_SIZE
+                    ));
+                }
+                config = config.worker_stack_size(bytes);
+            }
+            "--worker-keep-alive" => {
+                config = config.worker_keep_alive(duration_from_secs(parse_value(args, arg)?))
+            }
+            "--accept-threads" => {
+                let threads = parse_value(args, arg)?;
+                if threads < 1 {
+                    return Err(String::from("accept threads must be at least 1"));
+                }
+                config = config.accept_threads(threads);
+            }
+            "--runtime" => config = config.runtime(parse_value(args, arg)?),
+            "--shutdown-mode" => config = config.shutdown_mode(parse_value(args, arg)?),
+            "--queue-capacity" => config = config.queue_capacity(parse_value(args, arg)?),
+            "--queue-overflow" => config = config.queue_overflow(parse_value(args, arg)?),
+            "--queue-timeout" => {
+                config = config.queue_timeout(duration_from_secs(parse_value(args, arg)?))
+            }
+            "--protocol" => self.protocol = parse_value(args, arg)?,
+            "--mode" => config = config.mode(parse_value(args, arg)?),
+            "--allow" => config = config.allow(parse_net(&next_value(args, arg)?)?),
+            "--deny" => config = config.deny(parse_net(&next_value(args, arg)?)?),
+            "--rcvbuf" => config = config.recv_buffer_size(parse_value(args, arg)?),
+            "--sndbuf" => config = config.send_buffer_size(parse_value(args, arg)?),
+            "--ttl" => config = config.ttl(parse_value(args, arg)?),
+            "--linger-secs" => config = config.linger(Duration::from_secs(parse_value(args, arg)?)),
+            "--keepalive-secs" => {
+                self.keepalive = duration_from_secs(parse_value(args, arg)?);
+            }
+            "--keepalive-interval-secs" => {
+                let secs = parse_value(args, arg)?;
+                if secs < 1 {
+                    return Err(String::from("keepalive interval must be at least 1 second"));
+                }
+                self.keepalive_interval = Some(Duration::from_secs(secs));
+            }
+            "--keepalive-retries" => {
+                let retries = parse_value(args, arg)?;
+                if retries < 1 {
+                    return Err(String::from("keepalive retries must be at least 1"));
+                }
+                self.keepalive_retries = Some(retries);
+            }
+            "--max-connections" => config = config.max_connections(parse_value(args, arg)?),
+            "--busy-message" => config = config.busy_message(next_value(args, arg)?),
+            "--mirror" => {
+                self.mirror = true;
+                config = config.mirror(parse_value(args, arg)?);
+            }
+            "--max-per-ip" => config = config.max_connections_per_ip(parse_value(args, arg)?),
+            "--max-connection-rate" => config = config.max_connection_rate(parse_value(args, arg)?),
+            "--max-connection-bytes" => {
+                config = config.max_connection_bytes(parse_value(args, arg)?)
+            }
+            "--max-bps" => config = config.max_bytes_per_second(parse_value(args, arg)?),
+            "--delimiter" => {
+                let delimiter = parse_byte(&next_value(args, arg)?)?;
+                config = config.mode(Mode::Delimited).delimiter(delimiter);
+            }
+            "--reply" => {
+                config = config
+                    .mode(Mode::Reply)
+                    .reply(next_value(args, arg)?.into_bytes())
+            }
+            "--reply-file" => {
+                let path: PathBuf = parse_value(args, arg)?;
+                let reply = fs::read(&path)
+                    .map_err(|e| format!("could not read reply file {}: {}", path.display(), e))?;
+                config = config.mode(Mode::Reply).reply(reply);
+            }
+            "--reply-once" => config = config.reply_once(true),
+            "--gzip-direction" => {
+                config = config
+                    .mode(Mode::Gzip)
+                    .gzip_direction(parse_value(args, arg)?)
+            }
+            "--max-frame-size" => config = config.max_frame_size(parse_value(args, arg)?),
+            "--max-header-size" => {
+                let size = parse_value(args, arg)?;
+                if size < 1 {
+                    return Err(String::from("max header size must be at least 1 byte"));
+                }
+                config = config.max_header_size(size);
+            }
+            "--max-headers" => {
+                let count = parse_value(args, arg)?;
+                if count < 1 {
+                    return Err(String::from("max headers must be at least 1"));
+                }
+                config = config.max_headers(count);
+            }
+            "--proxy-protocol" => config = config.proxy_protocol(true),
+            "--banner" => config = config.banner(next_value(args, arg)?),
+            "--tls" => self.tls = true,
+            "--cert" => self.cert = Some(parse_value(args, arg)?),
+            "--key" => self.key = Some(parse_value(args, arg)?),
+            "--client-ca" => self.client_ca = Some(parse_value(args, arg)?),
+            "--unix" => self.unix = Some(parse_value(args, arg)?),
+            "--backlog" => self.listener.backlog = parse_value(args, arg)?,
+            "--dual-stack" => self.listener.dual_stack = true,
+            "--reuse-port" => self.listener.reuse_port = true,
+            "--pin-workers" => self.pin_workers = true,
+            "--selftest" => self.selftest = true,
+            "--duplex" => config = config.duplex(true),
+            "--selftest-connections" => {
+                self.bench.connections = parse_value(args, arg)?;
+                if self.bench.connections < 1 {
+                    return Err(String::from("self-test needs at least 1 connection"));
+                }
+            }
+            "--selftest-payload" => {
+                self.bench.payload_size = parse_value(args, arg)?;
+                if self.bench.payload_size < 1 {
+                    return Err(String::from("self-test payload must be at least 1 byte"));
+                }
+            }
+            "--selftest-duration" => {
+                self.bench.duration = Duration::from_secs(parse_value(args, arg)?)
+            }
+            "--worker-core" => self.worker_cores.push(parse_value(args, arg)?),
+            "--transform" => self.transform_name = next_value(args, arg)?,
+            "--record" => config = config.record(parse_value(args, arg)?),
+            "--control" => config = config.control(parse_value(args, arg)?),
+            "--metrics-addr" => config = config.metrics(parse_value(args, arg)?),
+            "--health-max-pending" => {
+                config = config.health_max_pending_per_worker(parse_value(args, arg)?)
+            }
+            "--echo-delay-ms" => {
+                config = config.echo_delay(Duration::from_millis(parse_value(args, arg)?))
+            }
+            "--drop-rate" => {
+                let rate = parse_value(args, arg)?;
+                if !(0.0..=1.0).contains(&rate) {
+                    return Err(String::from("drop rate must be between 0 and 1"));
+                }
+                config = config.drop_rate(rate);
+            }
+            "--echo-jitter-ms" => {
+                let (min, max) = parse_jitter(&next_value(args, arg)?)?;
+                config = config.echo_jitter(min, max)
+            }
+            "--stats-interval" => {
+                config = config.stats_interval(duration_from_secs(parse_value(args, arg)?))
+            }
+            other => return Err(format!("unknown argument {:?}", other)),
+        }
+        self.config = config;
+        Ok(())
+    }
 
-        Worker {
-            id,
-            thread: Some(thread),
+    fn build(self) -> Result<(ServerConfig, Option<BenchConfig>), String> {
+        let Options {
+            mut config,
+            host,
+            ports,
+            protocol,
+            tls,
+            cert,
+            key,
+            client_ca,
+            unix,
+            listener,
+            transform_name,
+            pin_workers,
+            worker_cores,
+            mirror,
+            keepalive,
+            keepalive_interval,
+            keepalive_retries,
+            selftest,
+            bench,
+        } = self;
+
+        if listener.backlog < 1 {
+            return Err(String::from("backlog must be at least 1"));
+        }
+
+        match (tls, cert, key) {
+            (true, Some(cert), Some(key)) => config = config.tls(cert, key),
+            (true, _, _) => return Err(String::from("--tls requires both --cert and --key")),
+            (false, None, None) => {}
+            (false, _, _) => return Err(String::from("--cert and --key require --tls")),
+        }
+
+        match (tls, client_ca) {
+            (true, Some(client_ca)) => config = config.client_ca(client_ca),
+            (false, Some(_)) => return Err(String::from("--client-ca requires --tls")),
+            (_, None) => {}
         }
+
+        if tls && protocol == Protocol::Udp {
+            return Err(String::from(
+                "--tls is only supported with the tcp protocol",
+            ));
+        }
+
+        if mirror && protocol == Protocol::Udp {
+            return Err(String::from(
+                "--mirror is only supported with the tcp protocol",
+            ));
+        }
+
+        if ports.len() > 1 && protocol == Protocol::Udp {
+            return Err(String::from(
+                "--port can only be repeated with the tcp protocol",
+            ));
+        }
+
+        // The self-test checks that what it sends comes back unchanged, over
+        // a TCP port of its own on --host.
+        if selftest {
+            if tls || unix.is_some() || protocol == Protocol::Udp {
+                return Err(String::from(
+                    "--selftest cannot be combined with --tls, --unix or --protocol udp",
+                ));
+            }
+            if !ports.is_empty() {
+                return Err(String::from(
+                    "--selftest picks its own port, it cannot be combined with --port",
+                ));
+            }
+            if transform_name != "identity" {
+                return Err(String::from("--selftest requires the identity transform"));
+            }
+            config = config.mode(Mode::Raw);
+        }
+
+        if let Some(path) = unix {
+            if !ports.is_empty() {
+                return Err(String::from("--unix cannot be combined with --port"));
+            }
+            if tls || protocol == Protocol::Udp {
+                return Err(String::from(
+                    "--unix cannot be combined with --tls or --protocol udp",
+                ));
+            }
+            config = config.unix(path);
+        }
+
+        match keepalive {
+            Some(idle) => {
+                config = config.keepalive(Keepalive {
+                    idle,
+                    interval: keepalive_interval,
+                    retries: keepalive_retries,
+                })
+            }
+            None if keepalive_interval.is_some() || keepalive_retries.is_some() => {
+                return Err(String::from(
+                    "--keepalive-interval-secs and --keepalive-retries require --keepalive-secs",
+                ))
+            }
+            None => {}
+        }
+
+        match (pin_workers, worker_cores.is_empty()) {
+            (true, _) => config = config.pin_workers(worker_cores),
+            (false, false) => return Err(String::from("--worker-core requires --pin-workers")),
+            (false, true) => {}
+        }
+
+        let transform = transform::by_name(&transform_name).ok_or_else(|| {
+            format!(
+                "unknown transform {:?}, expected one of: {}",
+                transform_name,
+                transform::NAMES.join(", ")
+            )
+        })?;
+
+        // Every --port gets its own listener on the same host.
+        let addrs = if selftest {
+            vec![parse_host(&host, Some(0))?]
+        } else if ports.is_empty() {
+            vec![parse_host(&host, None)?]
+        } else {
+            ports
+                .into_iter()
+                .map(|port| parse_host(&host, Some(port)))
+                .collect::<Result<_, _>>()?
+        };
+
+        let config = config
+            .addrs(addrs)
+            .protocol(protocol)
+            .listener(listener)
+            .transform(transform)
+            .build();
+        Ok((config, if selftest { Some(bench) } else { None }))
+    }
+}
+
+// Accepts a bare address (`::1`, `127.0.0.1`), a bracketed IPv6 address
+// (`[::1]`), or either form with a port (`[::1]:8080`), in which case the
+// port must not also be given through --port.
+fn parse_host(host: &str, port: Option<u16>) -> Result<SocketAddr, String> {
+    if let Ok(addr) = host.parse::<SocketAddr>() {
+        return match port {
+            Some(_) => Err(format!("host {:?} already includes a port", host)),
+            None => Ok(addr),
+        };
+    }
+
+    let bare = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    let ip: IpAddr = bare
+        .parse()
+        .map_err(|e| format!("invalid host {:?}: {}", host, e))?;
+    Ok(SocketAddr::new(ip, port.unwrap_or(server::DEFAULT_PORT)))
+}
+
+// Accepts a decimal (`0`, `10`) or hexadecimal (`0x1e`) byte value.
+fn parse_byte(value: &str) -> Result<u8, String> {
+    let parsed = match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.map_err(|_| {
+        format!(
+            "invalid delimiter {:?}, expected a byte like 0 or 0x1e",
+            value
+        )
+    })
+}
+
+// Accepts CIDR notation (`10.0.0.0/8`) or a single address, which stands
+// for a range containing only that address.
+fn parse_net(net: &str) -> Result<IpNet, String> {
+    net.parse::<IpNet>()
+        .or_else(|_| net.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("invalid address range {:?}", net))
+}
+
+// Accepts a range of milliseconds like `10:50`, whose start must not lie
+// past its end.
+fn parse_jitter(value: &str) -> Result<(Duration, Duration), String> {
+    let invalid = || {
+        format!(
+            "invalid echo jitter {:?}, expected min:max like 10:50",
+            value
+        )
+    };
+    let (min, max) = value.split_once(':').ok_or_else(invalid)?;
+    let min: u64 = min.parse().map_err(|_| invalid())?;
+    let max: u64 = max.parse().map_err(|_| invalid())?;
+    if min > max {
+        return Err(format!(
+            "invalid echo jitter {:?}, the minimum exceeds the maximum",
+            value
+        ));
+    }
+    Ok((Duration::from_millis(min), Duration::from_millis(max)))
+}
+
+fn next_value<I>(args: &mut I, flag: &str) -> Result<String, String>
+where
+    I: Iterator<Item = String>,
+{
+    args.next()
+        .ok_or_else(|| format!("missing value for {}", flag))
+}
+
+// Zero seconds disables the timeout or interval altogether.
+fn duration_from_secs(secs: u64) -> Option<Duration> {
+    if secs == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(secs))
+    }
+}
+
+fn parse_value<I, T>(args: &mut I, flag: &str) -> Result<T, String>
+where
+    I: Iterator<Item = String>,
+    T: FromStr,
+    T::Err: Display,
+{
+    let value = next_value(args, flag)?;
+    value
+        .parse()
+        .map_err(|e| format!("invalid value {:?} for {}: {}", value, flag, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes `contents` to a config file named after `name` and parses the
+    // command line `args` with `--config` pointing at it.
+    fn parse_with_file(name: &str, contents: &str, args: &[&str]) -> Result<ServerConfig, String> {
+        let path = env::temp_dir().join(format!(
+            "echo-server-rs-{}-{}.toml",
+            name,
+            std::process::id()
+        ));
+        fs::write(&path, contents).unwrap();
+        let mut all = vec![String::from("--config"), path.display().to_string()];
+        all.extend(args.iter().map(|arg| arg.to_string()));
+        let parsed = parse_args(all.into_iter());
+        fs::remove_file(&path).unwrap();
+        parsed.map(|(config, _)| config)
+    }
+
+    const FILE: &str = "host = \"127.0.0.2\"\n\
+                        port = [9001, 9002]\n\
+                        pool_size = 3\n\
+                        buffer_size = 4096\n\
+                        read_timeout = 7\n\
+                        write_timeout = 0\n";
+
+    #[test]
+    fn config_file_sets_the_fields_it_names() {
+        let config = parse_with_file("fields", FILE, &[]).unwrap();
+
+        let addrs: Vec<SocketAddr> = vec![
+            "127.0.0.2:9001".parse().unwrap(),
+            "127.0.0.2:9002".parse().unwrap(),
+        ];
+        assert_eq!(config.addrs(), &addrs[..]);
+        assert_eq!(config.pool_size(), 3);
+        assert_eq!(config.buffer_size(), 4096);
+        assert_eq!(config.read_timeout(), Some(Duration::from_secs(7)));
+        assert_eq!(config.write_timeout(), None);
+    }
+
+    #[test]
+    fn command_line_flag_overrides_the_file() {
+        let config = parse_with_file("override", FILE, &["--buffer-size", "64"]).unwrap();
+
+        assert_eq!(config.buffer_size(), 64);
+        assert_eq!(config.pool_size(), 3);
+    }
+
+    #[test]
+    fn invalid_value_names_its_key() {
+        let e = match parse_with_file("invalid", "pool_size = \"many\"\n", &[]) {
+            Ok(_) => panic!("a pool size of \"many\" was accepted"),
+            Err(e) => e,
+        };
+
+        assert!(e.contains("\"pool_size\""), "{}", e);
+    }
+
+    #[test]
+    fn unknown_key_is_rejected_by_name() {
+        let e = match parse_with_file("unknown", "pool_szie = 3\n", &[]) {
+            Ok(_) => panic!("the misspelt key was accepted"),
+            Err(e) => e,
+        };
+
+        assert!(e.contains("unknown field `pool_szie`"), "{}", e);
+    }
+
+    #[test]
+    fn repeated_flag_can_be_given_a_single_value() {
+        let config = parse_with_file("single", "port = 9003\n", &[]).unwrap();
+
+        assert_eq!(config.addrs(), &["127.0.0.1:9003".parse().unwrap()][..]);
     }
 }
@@ -0,0 +1,323 @@
+use crate::http;
+use crate::pool::ThreadPool;
+use crate::shutdown::Shutdown;
+use crate::socket;
+use crate::stats::ServerStats;
+use log::warn;
+use std::fmt::Write as _;
+use std::io::{self, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+/// Serves `GET /metrics` on `listener` until shutdown is requested, exposing
+/// `stats` in the Prometheus text exposition format, along with a `/healthz`
+/// check. Any other path gets a `404`.
+///
+/// `/healthz` answers `503 Service Unavailable` once more than
+/// `max_pending_per_worker` tasks per worker are waiting in the pool's
+/// queue, so a load balancer can stop routing to a saturated server, and
+/// `200 OK` otherwise.
+///
+/// Each connection carries a single request and is served on this thread,
+/// so `read_timeout` keeps a stalled scraper from blocking the next one.
+pub fn serve_metrics(
+    listener: TcpListener,
+    thread_pool: &ThreadPool,
+    stats: &ServerStats,
+    shutdown: &Shutdown,
+    read_timeout: Option<Duration>,
+    max_pending_per_worker: usize,
+) {
+    while let Some(stream) = shutdown.accept(|| socket::accept_blocking(&listener)) {
+        let result = stream
+            .and_then(|stream| {
+                stream.set_read_timeout(read_timeout)?;
+                Ok(stream)
+            })
+            .and_then(|stream| handle_scrape(stream, thread_pool, stats, max_pending_per_worker));
+        if let Err(e) = result {
+            warn!("Metrics connection failed due to: {:?}", e);
+        }
+    }
+}
+
+fn handle_scrape(
+    stream: TcpStream,
+    thread_pool: &ThreadPool,
+    stats: &ServerStats,
+    max_pending_per_worker: usize,
+) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut request = Vec::new();
+
+    let head_len = match http::read_head(&mut reader, &mut request, http::MAX_HEAD_LEN)? {
+        None => return Ok(()),
+        Some(Ok(head_len)) => head_len,
+        Some(Err(rejection)) => return Err(http::reject(&mut writer, rejection).into_io_error()),
+    };
+    let request = match http::parse_request(&request[..head_len], http::MAX_HEADERS) {
+        Ok(request) => request,
+        Err(rejection) => return Err(http::reject(&mut writer, rejection).into_io_error()),
+    };
+
+    let (status, body) = match (request.method, request.target) {
+        ("GET", "/metrics") => ("200 OK", render(stats)),
+        ("GET", "/healthz") => health(thread_pool, max_pending_per_worker),
+        (_, "/metrics") | (_, "/healthz") => (
+            "405 Method Not Allowed",
+            String::from("only GET is supported\n"),
+        ),
+        _ => ("404 Not Found", String::from("not found\n")),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    writer.write_all(response.as_bytes())
+}
+
+fn health(thread_pool: &ThreadPool, max_pending_per_worker: usize) -> (&'static str, String) {
+    let pending = thread_pool.pending_tasks();
+    let workers = thread_pool.worker_count();
+    let status = if pending > workers.saturating_mul(max_pending_per_worker) {
+        "503 Service Unavailable"
+    } else {
+        "200 OK"
+    };
+    (status, format!("pending={} workers={}\n", pending, workers))
+}
+
+fn render(stats: &ServerStats) -> String {
+    let metrics = [
+        (
+            "connections_total",
+            "counter",
+            "Connections accepted for serving.",
+            stats.accepted(),
+        ),
+        (
+            "active_connections",
+            "gauge",
+            "Connections currently being served or waiting for a worker.",
+            stats.active(),
+        ),
+        (
+            "bytes_echoed_total",
+            "counter",
+            "Bytes echoed back to clients.",
+            stats.bytes_echoed(),
+        ),
+        (
+            "connections_closed_total",
+            "counter",
+            "Connections that ended with a clean close.",
+            stats.closed(),
+        ),
+        (
+            "connection_disconnects_total",
+            "counter",
+            "Connections that ended with the client going away abruptly.",
+            stats.disconnects(),
+        ),
+        (
+            "connection_errors_total",
+            "counter",
+            "Connections that ended because of an error or a timeout.",
+            stats.errors(),
+        ),
+    ];
+
+    let mut body = String::new();
+    for (name, kind, help, value) in metrics {
+        // Writing to a `String` cannot fail.
+        let _ = write!(
+            body,
+            "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n",
+            name = name,
+            help = help,
+            kind = kind,
+            value = value
+        );
+    }
+
+    let workers = stats.worker_outcomes();
+    if !workers.is_empty() {
+        body.push_str(
+            "# HELP worker_connections_total Connections served by each pool worker, by how they ended.\n# TYPE worker_connections_total counter\n",
+        );
+    }
+    for (id, outcomes) in workers {
+        for (outcome, value) in [
+            ("closed", outcomes.closed),
+            ("disconnected", outcomes.disconnected),
+            ("failed", outcomes.failed),
+        ] {
+            let _ = writeln!(
+                body,
+                "worker_connections_total{{worker=\"{}\",outcome=\"{}\"}} {}",
+                id, outcome, value
+            );
+        }
+    }
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::Outcome;
+    use std::io::Read;
+    use std::net::SocketAddr;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::Instant;
+
+    fn get(addr: SocketAddr, request: &[u8]) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream.write_all(request).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    // Serves metrics for `thread_pool` and `stats` while `scrape` runs,
+    // passing it the address to scrape.
+    fn serving(
+        thread_pool: &ThreadPool,
+        stats: &ServerStats,
+        max_pending_per_worker: usize,
+        scrape: impl FnOnce(SocketAddr),
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = Shutdown::default();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                serve_metrics(
+                    listener,
+                    thread_pool,
+                    stats,
+                    &shutdown,
+                    None,
+                    max_pending_per_worker,
+                )
+            });
+            scrape(addr);
+            shutdown.request();
+        });
+    }
+
+    #[test]
+    fn scrape_reports_the_traffic_counted_so_far() {
+        let thread_pool = ThreadPool::new(1).unwrap();
+        let stats = Arc::new(ServerStats::default());
+        for outcome in &[Outcome::Closed, Outcome::Failed] {
+            let _connection = stats.connection_opened();
+            stats.add_bytes_echoed(5);
+            stats.connection_ended(*outcome);
+        }
+        let _open = stats.connection_opened();
+
+        serving(&thread_pool, &stats, 1, |addr| {
+            let response = get(addr, b"GET /metrics HTTP/1.1\r\n\r\n");
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+            for sample in &[
+                "connections_total 3",
+                "active_connections 1",
+                "bytes_echoed_total 10",
+                "connections_closed_total 1",
+                "connection_errors_total 1",
+            ] {
+                assert!(
+                    response.lines().any(|line| line == *sample),
+                    "{} missing from {}",
+                    sample,
+                    response
+                );
+            }
+            assert!(response.contains("# TYPE active_connections gauge\n"));
+
+            let response = get(addr, b"GET /other HTTP/1.1\r\n\r\n");
+            assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        });
+    }
+
+    #[test]
+    fn healthz_turns_unavailable_while_the_pool_is_saturated() {
+        let thread_pool = ThreadPool::new(1).unwrap();
+        let stats = ServerStats::default();
+        let healthz = |addr| get(addr, b"GET /healthz HTTP/1.1\r\n\r\n");
+
+        serving(&thread_pool, &stats, 1, |addr| {
+            assert!(healthz(addr).starts_with("HTTP/1.1 200 OK\r\n"));
+
+            // One task holds the only worker and two more wait behind it,
+            // more than the one per worker allowed.
+            let (started, starts) = mpsc::channel();
+            let (release, released) = mpsc::channel::<()>();
+            thread_pool
+                .execute(move || {
+                    started.send(()).unwrap();
+                    let _ = released.recv();
+                })
+                .unwrap();
+            starts.recv_timeout(Duration::from_secs(5)).unwrap();
+            for _ in 0..2 {
+                thread_pool.execute(|| {}).unwrap();
+            }
+            let response = healthz(addr);
+            assert!(
+                response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+                "{}",
+                response
+            );
+            assert!(response.ends_with("pending=2 workers=1\n"));
+
+            drop(release);
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while thread_pool.pending_tasks() > 0 {
+                assert!(Instant::now() < deadline, "the queue never drained");
+                thread::sleep(Duration::from_millis(5));
+            }
+            assert!(healthz(addr).starts_with("HTTP/1.1 200 OK\r\n"));
+        });
+    }
+
+    #[test]
+    fn oversized_head_is_rejected_without_stalling_the_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = Arc::new(Shutdown::default());
+        let server = {
+            let shutdown = Arc::clone(&shutdown);
+            thread::spawn(move || {
+                let thread_pool = ThreadPool::new(1).unwrap();
+                let stats = ServerStats::default();
+                serve_metrics(listener, &thread_pool, &stats, &shutdown, None, 1);
+            })
+        };
+
+        // Exactly as long as the limit, and ending on a line boundary.
+        let mut head = b"GET /healthz HTTP/1.1\r\n".to_vec();
+        let padding = http::MAX_HEAD_LEN - head.len() - "X-Pad: \r\n".len();
+        head.extend_from_slice(format!("X-Pad: {}\r\n", "a".repeat(padding)).as_bytes());
+        assert_eq!(head.len(), http::MAX_HEAD_LEN);
+        let response = get(addr, &head);
+        assert!(response.starts_with("HTTP/1.1 431 "));
+
+        let response = get(addr, b"GET /healthz HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        shutdown.request();
+        server.join().unwrap();
+    }
+}
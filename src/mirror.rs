@@ -0,0 +1,120 @@
+use crate::connection::HalfClose;
+use crate::span;
+use log::{info, warn};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+/// Received chunks a mirror connection may fall behind by before further
+/// ones are dropped.
+pub const MIRROR_QUEUE_SIZE: usize = 64;
+
+// How long a mirror connection may take to be established.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Copies everything one connection receives to a mirror upstream, over a
+/// connection of its own.
+///
+/// The upstream is connected to and written to by a thread of its own, fed
+/// through a bounded queue, so a slow or unreachable mirror never holds up
+/// the echo. Chunks that do not fit in the queue are dropped, and a mirror
+/// that cannot be reached or stops accepting writes is given up on; both
+/// are logged, but neither affects the connection being mirrored.
+pub struct Mirror {
+    queue: SyncSender<Vec<u8>>,
+    dropped: u64,
+}
+
+impl Mirror {
+    /// Starts mirroring connection `id` to `upstream`.
+    pub fn start(id: u64, upstream: SocketAddr) -> io::Result<Mirror> {
+        let (queue, chunks) = mpsc::sync_channel::<Vec<u8>>(MIRROR_QUEUE_SIZE);
+        thread::Builder::new()
+            .name(format!("mirror-{}", id))
+            .spawn(move || {
+                let _span = span::enter(id);
+                let mut stream = match TcpStream::connect_timeout(&upstream, CONNECT_TIMEOUT) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("Could not connect to mirror {} due to: {:?}", upstream, e);
+                        return;
+                    }
+                };
+                for chunk in chunks {
+                    if let Err(e) = stream.write_all(&chunk) {
+                        warn!("Stopped mirroring to {} due to: {:?}", upstream, e);
+                        return;
+                    }
+                }
+            })?;
+        Ok(Mirror { queue, dropped: 0 })
+    }
+
+    pub(crate) fn copy(&mut self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        match self.queue.try_send(bytes.to_vec()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                if self.dropped == 0 {
+                    warn!("Mirror fell behind, dropping received bytes until it catches up");
+                }
+                self.dropped += bytes.len() as u64;
+            }
+            // The mirror was given up on, which was logged already.
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+impl Drop for Mirror {
+    fn drop(&mut self) {
+        if self.dropped > 0 {
+            info!(
+                "Dropped {} received bytes the mirror could not keep up with",
+                self.dropped
+            );
+        }
+    }
+}
+
+/// A stream whose received bytes are copied to a `Mirror`, if it has one.
+pub struct Mirrored<S> {
+    stream: S,
+    mirror: Option<Mirror>,
+}
+
+impl<S> Mirrored<S> {
+    pub fn new(stream: S, mirror: Option<Mirror>) -> Mirrored<S> {
+        Mirrored { stream, mirror }
+    }
+}
+
+impl<S: Read> Read for Mirrored<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read_bytes = self.stream.read(buf)?;
+        if let Some(mirror) = &mut self.mirror {
+            mirror.copy(&buf[..read_bytes]);
+        }
+        Ok(read_bytes)
+    }
+}
+
+impl<S: Write> Write for Mirrored<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<S: HalfClose> HalfClose for Mirrored<S> {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.stream.shutdown_write()
+    }
+}
@@ -0,0 +1,576 @@
+//! The built-in `EchoHandler`s behind the modes that echo what they read a
+//! chunk at a time. `Mode::Http`, `Mode::WebSocket`, `Mode::Chargen` and
+//! `Mode::Broadcast` are protocols that answer on the stream themselves, so
+//! they are served without one.
+
+use crate::connection::truncated_frame;
+use crate::gzip::Codec;
+use crate::handler::EchoHandler;
+use crate::server::{Mode, ServerConfig};
+use crate::transform::{self, Transform};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::convert::TryFrom;
+use std::io::{self, ErrorKind};
+use std::sync::Arc;
+
+/// Creates the handler serving a connection in `config`'s mode, or `None`
+/// for the modes that are not served through one.
+pub fn for_mode(config: &ServerConfig) -> Option<Box<dyn EchoHandler>> {
+    let transform = Arc::clone(&config.transform);
+    let handler: Box<dyn EchoHandler> = match config.mode {
+        Mode::Raw => Box::new(Echo::new(transform)),
+        Mode::Reverse => Box::new(Echo::new(transform::reversed(transform))),
+        Mode::Checksum => Box::new(Checksum::new(transform)),
+        Mode::Base64Encode => Box::new(Base64Encoder::new(transform)),
+        Mode::Base64Decode => Box::new(Base64Decoder::new(transform)),
+        Mode::Line => Box::new(Lines::new(config.buffer_size, transform)),
+        Mode::Delimited => Box::new(Delimited::new(
+            config.delimiter,
+            config.max_frame_size,
+            transform,
+        )),
+        Mode::Framed => Box::new(Frames::new(config.max_frame_size, transform)),
+        Mode::Gzip => Box::new(Codec::new(config.gzip_direction, transform)),
+        Mode::Discard => Box::new(Discard),
+        Mode::Reply => Box::new(Reply::new(config.reply.clone(), config.reply_once)),
+        Mode::Http | Mode::WebSocket | Mode::Chargen | Mode::Broadcast => return None,
+    };
+    Some(handler)
+}
+
+/// Echoes `transform`'s output for every chunk, as `Mode::Raw` does.
+pub struct Echo {
+    transform: Arc<Transform>,
+}
+
+impl Echo {
+    pub fn new(transform: Arc<Transform>) -> Echo {
+        Echo { transform }
+    }
+}
+
+impl EchoHandler for Echo {
+    fn process(&mut self, input: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        out.extend_from_slice(&(self.transform)(input));
+        Ok(())
+    }
+}
+
+/// Like `Echo`, but follows every echoed chunk with a 4-byte big-endian
+/// CRC32 computed over exactly those bytes, after the transform has run.
+pub struct Checksum {
+    transform: Arc<Transform>,
+}
+
+impl Checksum {
+    pub fn new(transform: Arc<Transform>) -> Checksum {
+        Checksum { transform }
+    }
+}
+
+impl EchoHandler for Checksum {
+    fn process(&mut self, input: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        out.extend_from_slice(&(self.transform)(input));
+        let checksum = crc32fast::hash(out);
+        out.extend_from_slice(&checksum.to_be_bytes());
+        Ok(())
+    }
+}
+
+/// Echoes the base64 encoding of the transform's output.
+///
+/// A connection is encoded as one continuous stream: up to two bytes that do
+/// not fill a 3-byte group are held back until more arrive, and are encoded
+/// with padding once the peer closes its side.
+pub struct Base64Encoder {
+    transform: Arc<Transform>,
+    pending: Vec<u8>,
+}
+
+impl Base64Encoder {
+    pub fn new(transform: Arc<Transform>) -> Base64Encoder {
+        Base64Encoder {
+            transform,
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl EchoHandler for Base64Encoder {
+    fn process(&mut self, input: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        self.pending.extend_from_slice(&(self.transform)(input));
+        let complete = self.pending.len() - self.pending.len() % 3;
+        out.extend_from_slice(STANDARD.encode(&self.pending[..complete]).as_bytes());
+        self.pending.drain(..complete);
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            out.extend_from_slice(STANDARD.encode(&self.pending).as_bytes());
+            self.pending.clear();
+        }
+        Ok(())
+    }
+}
+
+/// Reads base64 and echoes the transform's output for the bytes it decodes
+/// to.
+///
+/// Whitespace such as line breaks is skipped, and up to three characters
+/// that do not complete a 4-character group are held back until more
+/// arrive. A padded group ends the encoding it belongs to, so separately
+/// encoded messages may follow each other. Input that is not base64, or
+/// that ends in the middle of a group, fails with `ErrorKind::InvalidData`.
+pub struct Base64Decoder {
+    transform: Arc<Transform>,
+    pending: Vec<u8>,
+}
+
+impl Base64Decoder {
+    pub fn new(transform: Arc<Transform>) -> Base64Decoder {
+        Base64Decoder {
+            transform,
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl EchoHandler for Base64Decoder {
+    fn process(&mut self, input: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        let pending = &mut self.pending;
+        pending.extend(input.iter().filter(|byte| !byte.is_ascii_whitespace()));
+        let complete = pending.len() - pending.len() % 4;
+        let mut decoded = Vec::new();
+        let mut start = 0;
+        for end in (4..=complete).step_by(4) {
+            if pending[end - 1] == b'=' || end == complete {
+                STANDARD
+                    .decode_vec(&pending[start..end], &mut decoded)
+                    .map_err(|e| {
+                        io::Error::new(
+                            ErrorKind::InvalidData,
+                            format!("invalid base64 input, {}", e),
+                        )
+                    })?;
+                start = end;
+            }
+        }
+        pending.drain(..complete);
+
+        out.extend_from_slice(&(self.transform)(&decoded));
+        Ok(())
+    }
+
+    fn finish(&mut self, _out: &mut Vec<u8>) -> io::Result<()> {
+        if self.pending.is_empty() {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "connection closed in the middle of a base64 group",
+            ))
+        }
+    }
+}
+
+/// Echoes complete lines, ending in `\n`. The transform sees each line
+/// without its ending, `\n` or `\r\n`, which is echoed unchanged after the
+/// output.
+///
+/// A line longer than `limit` bytes is echoed in `limit`-sized pieces, and
+/// trailing data without a newline is echoed once the peer closes its side.
+pub struct Lines {
+    limit: usize,
+    transform: Arc<Transform>,
+    pending: Vec<u8>,
+}
+
+impl Lines {
+    pub fn new(limit: usize, transform: Arc<Transform>) -> Lines {
+        assert!(limit > 0);
+        Lines {
+            limit,
+            transform,
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl EchoHandler for Lines {
+    fn process(&mut self, input: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        self.pending.extend_from_slice(input);
+        let mut start = 0;
+        loop {
+            let rest = &self.pending[start..];
+            let window = &rest[..rest.len().min(self.limit)];
+            let len = match window.iter().position(|&byte| byte == b'\n') {
+                Some(newline) => newline + 1,
+                None if window.len() == self.limit => self.limit,
+                None => break,
+            };
+            echo_message(&*self.transform, &rest[..len], b'\n', out);
+            start += len;
+        }
+        self.pending.drain(..start);
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            echo_message(&*self.transform, &self.pending, b'\n', out);
+            self.pending.clear();
+        }
+        Ok(())
+    }
+
+    fn holds_partial_message(&self) -> bool {
+        !self.pending.is_empty()
+    }
+}
+
+/// Echoes messages ending in `delimiter`. The transform sees each message
+/// without its delimiter, which is echoed unchanged after the output.
+/// Trailing data without a delimiter is echoed once the peer closes its
+/// side.
+///
+/// A message with more than `max_message_size` bytes before its delimiter
+/// fails with `ErrorKind::InvalidData`, since what follows cannot be split
+/// into messages anymore.
+pub struct Delimited {
+    delimiter: u8,
+    max_message_size: usize,
+    transform: Arc<Transform>,
+    pending: Vec<u8>,
+}
+
+impl Delimited {
+    pub fn new(delimiter: u8, max_message_size: usize, transform: Arc<Transform>) -> Delimited {
+        Delimited {
+            delimiter,
+            max_message_size,
+            transform,
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl EchoHandler for Delimited {
+    fn process(&mut self, input: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        self.pending.extend_from_slice(input);
+        let mut start = 0;
+        loop {
+            let rest = &self.pending[start..];
+            let len = match rest.iter().position(|&byte| byte == self.delimiter) {
+                Some(end) if end <= self.max_message_size => end + 1,
+                None if rest.len() <= self.max_message_size => break,
+                _ => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "message exceeds the limit of {} bytes without a delimiter",
+                            self.max_message_size
+                        ),
+                    ))
+                }
+            };
+            echo_message(&*self.transform, &rest[..len], self.delimiter, out);
+            start += len;
+        }
+        self.pending.drain(..start);
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            echo_message(&*self.transform, &self.pending, self.delimiter, out);
+            self.pending.clear();
+        }
+        Ok(())
+    }
+
+    fn holds_partial_message(&self) -> bool {
+        !self.pending.is_empty()
+    }
+}
+
+/// Echoes frames made of a 4-byte big-endian length followed by that many
+/// payload bytes, each as a frame of its own holding the transform's output
+/// for the payload.
+///
+/// A frame announcing more than `max_frame_size` payload bytes, or a peer
+/// closing the connection in the middle of a frame, fails with
+/// `ErrorKind::InvalidData`.
+pub struct Frames {
+    max_frame_size: usize,
+    transform: Arc<Transform>,
+    pending: Vec<u8>,
+}
+
+impl Frames {
+    pub fn new(max_frame_size: usize, transform: Arc<Transform>) -> Frames {
+        Frames {
+            max_frame_size,
+            transform,
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl EchoHandler for Frames {
+    fn process(&mut self, input: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        self.pending.extend_from_slice(input);
+        let mut start = 0;
+        while let Some(prefix) = self.pending.get(start..start + 4) {
+            let len = u32::from_be_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as usize;
+            if len > self.max_frame_size {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "frame of {} bytes exceeds the limit of {} bytes",
+                        len, self.max_frame_size
+                    ),
+                ));
+            }
+            let payload = match self.pending.get(start + 4..start + 4 + len) {
+                Some(payload) => (self.transform)(payload),
+                None => break,
+            };
+            let payload_len = u32::try_from(payload.len()).map_err(|_| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    "transformed frame does not fit a 4-byte length",
+                )
+            })?;
+            out.extend_from_slice(&payload_len.to_be_bytes());
+            out.extend_from_slice(&payload);
+            start += 4 + len;
+        }
+        self.pending.drain(..start);
+        Ok(())
+    }
+
+    fn finish(&mut self, _out: &mut Vec<u8>) -> io::Result<()> {
+        match self.pending.len() {
+            0 => Ok(()),
+            read => Err(truncated_frame(read)),
+        }
+    }
+
+    fn holds_partial_message(&self) -> bool {
+        !self.pending.is_empty()
+    }
+}
+
+/// Drops everything, echoing nothing, like the discard protocol of RFC 863.
+pub struct Discard;
+
+impl EchoHandler for Discard {
+    fn process(&mut self, _input: &[u8], _out: &mut Vec<u8>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Answers every chunk, or only the first one if `once` is set, with
+/// `reply`, dropping what was read.
+pub struct Reply {
+    reply: Vec<u8>,
+    once: bool,
+    replied: bool,
+}
+
+impl Reply {
+    pub fn new(reply: Vec<u8>, once: bool) -> Reply {
+        Reply {
+            reply,
+            once,
+            replied: false,
+        }
+    }
+}
+
+impl EchoHandler for Reply {
+    fn process(&mut self, _input: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        if !(self.once && self.replied) {
+            out.extend_from_slice(&self.reply);
+            self.replied = true;
+        }
+        Ok(())
+    }
+}
+
+// Appends `transform`'s output for `message` without its trailing
+// `delimiter`, along with a `\r` before a `\n`, and then that ending, so
+// `abc\r\n` reverses to `cba\r\n` rather than `\n\rcba`.
+fn echo_message(transform: &Transform, message: &[u8], delimiter: u8, out: &mut Vec<u8>) {
+    let ending = if delimiter == b'\n' && message.ends_with(b"\r\n") {
+        2
+    } else if message.last() == Some(&delimiter) {
+        1
+    } else {
+        0
+    };
+    let (content, ending) = message.split_at(message.len() - ending);
+    out.extend_from_slice(&transform(content));
+    out.extend_from_slice(ending);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gzip::GzipDirection;
+    use crate::transform;
+
+    // Feeds `chunks` to `handler` one at a time, then finishes it, and
+    // returns everything it echoed.
+    fn run(mut handler: impl EchoHandler, chunks: &[&[u8]]) -> io::Result<Vec<u8>> {
+        let mut echoed = Vec::new();
+        let mut out = Vec::new();
+        for chunk in chunks {
+            out.clear();
+            handler.process(chunk, &mut out)?;
+            echoed.extend_from_slice(&out);
+        }
+        out.clear();
+        handler.finish(&mut out)?;
+        echoed.extend_from_slice(&out);
+        Ok(echoed)
+    }
+
+    fn identity() -> Arc<Transform> {
+        Arc::new(transform::identity)
+    }
+
+    fn uppercase() -> Arc<Transform> {
+        transform::by_name("uppercase").unwrap()
+    }
+
+    #[test]
+    fn checksum_follows_each_chunk_with_its_crc32() {
+        let echoed = run(Checksum::new(uppercase()), &[b"abc"]).unwrap();
+
+        assert_eq!(&echoed[..3], b"ABC");
+        assert_eq!(echoed[3..], crc32fast::hash(b"ABC").to_be_bytes());
+    }
+
+    #[test]
+    fn base64_encoding_spans_chunks_and_pads_at_the_end() {
+        let echoed = run(Base64Encoder::new(identity()), &[b"he", b"llo"]).unwrap();
+
+        assert_eq!(echoed, b"aGVsbG8=");
+    }
+
+    #[test]
+    fn base64_encoding_of_uneven_chunks_decodes_to_the_input() {
+        let input: Vec<u8> = (0..=255).cycle().take(1_000).collect();
+        let mut chunks = Vec::new();
+        let mut rest = &input[..];
+        for size in (1..=7).cycle() {
+            if rest.is_empty() {
+                break;
+            }
+            let (chunk, tail) = rest.split_at(size.min(rest.len()));
+            chunks.push(chunk);
+            rest = tail;
+        }
+
+        let echoed = run(Base64Encoder::new(identity()), &chunks).unwrap();
+
+        assert_eq!(STANDARD.decode(&echoed).unwrap(), input);
+    }
+
+    #[test]
+    fn base64_decoding_joins_groups_split_across_chunks() {
+        let echoed = run(Base64Decoder::new(identity()), &[b"aGVs", b"b", b"G8=\n"]).unwrap();
+
+        assert_eq!(echoed, b"hello");
+    }
+
+    #[test]
+    fn base64_decoding_rejects_a_dangling_group() {
+        let e = run(Base64Decoder::new(identity()), &[b"aGV"]).unwrap_err();
+
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn lines_are_echoed_once_complete_and_long_ones_in_pieces() {
+        let echoed = run(Lines::new(4, uppercase()), &[b"ab", b"c\nlonger\r\nend"]).unwrap();
+
+        assert_eq!(echoed, b"ABC\nLONGER\r\nEND");
+    }
+
+    #[test]
+    fn a_line_held_back_counts_as_a_partial_message() {
+        let mut lines = Lines::new(16, identity());
+        let mut out = Vec::new();
+
+        lines.process(b"abc", &mut out).unwrap();
+        assert!(lines.holds_partial_message());
+        lines.process(b"\n", &mut out).unwrap();
+        assert!(!lines.holds_partial_message());
+    }
+
+    #[test]
+    fn delimited_messages_keep_their_delimiter() {
+        let echoed = run(Delimited::new(b';', 3, uppercase()), &[b"ab;c", b"d;e"]).unwrap();
+
+        assert_eq!(echoed, b"AB;CD;E");
+    }
+
+    #[test]
+    fn delimited_message_over_the_limit_is_rejected() {
+        let e = run(Delimited::new(b';', 3, identity()), &[b"abcd"]).unwrap_err();
+
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn frames_are_echoed_once_complete() {
+        let echoed = run(
+            Frames::new(16, uppercase()),
+            &[b"\0\0\0\x03a", b"bc\0\0", b"\0\x01d"],
+        )
+        .unwrap();
+
+        assert_eq!(echoed, b"\0\0\0\x03ABC\0\0\0\x01D");
+    }
+
+    #[test]
+    fn oversized_and_truncated_frames_are_rejected() {
+        let oversized = run(Frames::new(2, identity()), &[b"\0\0\0\x03"]).unwrap_err();
+        let truncated = run(Frames::new(16, identity()), &[b"\0\0\0\x03ab"]).unwrap_err();
+
+        assert_eq!(oversized.kind(), ErrorKind::InvalidData);
+        assert_eq!(truncated.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn gzip_round_trips_through_both_directions() {
+        let compressed = run(
+            Codec::new(GzipDirection::Compress, uppercase()),
+            &[b"hello ", b"world"],
+        )
+        .unwrap();
+        let plain = run(
+            Codec::new(GzipDirection::Decompress, identity()),
+            &[&compressed],
+        )
+        .unwrap();
+
+        assert_eq!(plain, b"HELLO WORLD");
+    }
+
+    #[test]
+    fn reply_answers_every_chunk_or_only_the_first() {
+        assert_eq!(
+            run(Reply::new(b"ok".to_vec(), false), &[b"a", b"b"]).unwrap(),
+            b"okok"
+        );
+        assert_eq!(
+            run(Reply::new(b"ok".to_vec(), true), &[b"a", b"b"]).unwrap(),
+            b"ok"
+        );
+    }
+}
@@ -0,0 +1,1770 @@
+use core_affinity::CoreId;
+use crossbeam_deque::{Injector, Steal};
+use log::{debug, error, info, warn};
+use std::any::Any;
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::iter;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::str::FromStr;
+use std::sync::atomic::{self, AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+enum Operation {
+    Execute(Task),
+    // A task queued under `OverflowPolicy::DropOldest`, which a later
+    // `execute` may still withdraw; whoever takes it out first owns it.
+    Withdrawable(Arc<Slot>),
+    Terminate,
+}
+
+type Task = Box<dyn FnOnce() + Send + 'static>;
+
+type Slot = Mutex<Option<Task>>;
+
+/// Smallest stack `ThreadPool::with_stack_size` gives a worker; less does
+/// not even leave room for logging a line.
+pub const MIN_STACK_SIZE: usize = 64 * 1024;
+
+/// Target of the line a worker logs for every task it starts, once turned
+/// on with `ThreadPool::set_task_logging`.
+pub const TASK_LOG_TARGET: &str = "echo_server_rs::pool::tasks";
+
+thread_local! {
+    static CURRENT_WORKER: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Where `ThreadPool::execute_with_priority` places a task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Queued behind the tasks submitted before it.
+    Normal,
+    /// Started by the next worker to become free, ahead of every normal
+    /// task, and never rejected by a bounded queue.
+    High,
+}
+
+/// What `ThreadPool::execute` does with a normal-priority task when the
+/// bounded queue has no free slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Hands the new task back in `ExecuteError::Full`.
+    Reject,
+    /// Drops the task that has been queued the longest without starting,
+    /// and queues the new one in its place. Fails with `ExecuteError::Full`
+    /// only if every queued task is a high-priority one.
+    DropOldest,
+    /// Blocks the caller until a worker starts a task and frees a slot.
+    Block,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OverflowPolicy, String> {
+        match s {
+            "reject" => Ok(OverflowPolicy::Reject),
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "block" => Ok(OverflowPolicy::Block),
+            other => Err(format!(
+                "unsupported overflow policy {:?}, expected reject, drop-oldest or block",
+                other
+            )),
+        }
+    }
+}
+
+pub struct ThreadPool {
+    next_id: AtomicUsize,
+    shared: Arc<Shared>,
+    exited: mpsc::Sender<usize>,
+    exits: Mutex<mpsc::Receiver<usize>>,
+    shutdown_timeout: Option<Duration>,
+    overflow: OverflowPolicy,
+    queue_timeout: Option<Duration>,
+}
+
+// State shared between the pool and its workers. Every worker owns a queue
+// that `execute` fills round-robin, and steals from the others once its own
+// runs dry, so submitting a task never contends on a single lock.
+struct Shared {
+    workers: Mutex<Vec<Worker>>,
+    // Workers that have not decided to exit yet.
+    live: AtomicUsize,
+    // Size the pool was last resized to; workers beyond it exit once they
+    // finish their current task.
+    target: AtomicUsize,
+    min_workers: AtomicUsize,
+    // Nanoseconds a worker beyond `min_workers` may go without a task,
+    // `u64::MAX` for as long as it takes.
+    keep_alive: AtomicU64,
+    // Cores the workers are pinned to, round-robin by id, and how often
+    // they were changed, so workers notice and pin themselves again.
+    cores: Mutex<Vec<usize>>,
+    core_changes: AtomicUsize,
+    // Stack size of worker threads, the platform default when unset.
+    stack_size: Option<usize>,
+    // Whether workers log every task they start.
+    log_tasks: AtomicBool,
+    // Set once the pool is dropped, so workers exit after the last task even
+    // if no terminate operation reaches them.
+    closed: AtomicBool,
+    queues: RwLock<Vec<(usize, Arc<Injector<Operation>>)>>,
+    next_queue: AtomicUsize,
+    urgent: Injector<Task>,
+    pending: AtomicUsize,
+    capacity: Option<usize>,
+    // Withdrawable tasks, oldest first. Tasks a worker already took are
+    // only pruned once they reach the front.
+    withdrawable: Mutex<VecDeque<Arc<Slot>>>,
+    // Callers of `execute` waiting for a slot under `OverflowPolicy::Block`,
+    // so workers only take the lock when there is someone to notify.
+    blocked: AtomicUsize,
+    space: Mutex<()>,
+    freed: Condvar,
+    // Set while a worker is parked and no wakeup is on its way, so `execute`
+    // only takes the lock when there is someone to wake.
+    wakeable: AtomicBool,
+    // Bumped by every `wake_all`, so a worker that looked at the pool's
+    // settings before the bump does not park through the wakeup meant to
+    // make it look again.
+    wake_epoch: AtomicUsize,
+    idle: Mutex<Idle>,
+    wake: Condvar,
+}
+
+#[derive(Default)]
+struct Idle {
+    // Parked workers that nobody has woken yet.
+    sleepers: usize,
+    // Wakeups handed out but not yet claimed by a parked worker. Only one is
+    // handed out at a time, however many tasks are pushed before it runs; a
+    // woken worker that finds work passes it on, so the pool ramps up
+    // without every push waking a thread.
+    wakeups: usize,
+}
+
+impl Idle {
+    fn wakeable(&self) -> bool {
+        self.sleepers > 0 && self.wakeups == 0
+    }
+}
+
+impl ThreadPool {
+    /// Creates a pool of `size` workers fed by an unbounded task queue.
+    ///
+    /// Fails if a worker thread cannot be spawned, after stopping the
+    /// workers that were already started.
+    pub fn new(size: usize) -> io::Result<ThreadPool> {
+        ThreadPool::with_queue(size, None, None)
+    }
+
+    /// Creates a pool of `size` workers whose queue holds at most `capacity`
+    /// pending tasks; `execute` fails with `ExecuteError::Full` beyond that.
+    pub fn with_capacity(size: usize, capacity: usize) -> io::Result<ThreadPool> {
+        ThreadPool::with_queue(size, Some(capacity), None)
+    }
+
+    /// Creates a pool like `new`, or `with_capacity` if `capacity` is set,
+    /// whose workers run on stacks of `stack_size` bytes instead of the
+    /// platform default, including the ones spawned when it grows.
+    ///
+    /// The pool reserves `stack_size` times its size of address space, but
+    /// only the pages a task actually touches take up memory, so a large
+    /// stack mostly costs address space and a small one mostly risks an
+    /// overflow. Panics if `stack_size` is below `MIN_STACK_SIZE`.
+    pub fn with_stack_size(
+        size: usize,
+        capacity: Option<usize>,
+        stack_size: usize,
+    ) -> io::Result<ThreadPool> {
+        assert!(stack_size >= MIN_STACK_SIZE);
+        ThreadPool::with_queue(size, capacity, Some(stack_size))
+    }
+
+    fn with_queue(
+        size: usize,
+        capacity: Option<usize>,
+        stack_size: Option<usize>,
+    ) -> io::Result<ThreadPool> {
+        assert!(size > 0);
+
+        let (exited, exits) = mpsc::channel();
+
+        let pool = ThreadPool {
+            next_id: AtomicUsize::new(0),
+            shared: Arc::new(Shared {
+                workers: Mutex::new(Vec::new()),
+                live: AtomicUsize::new(0),
+                target: AtomicUsize::new(size),
+                min_workers: AtomicUsize::new(1),
+                keep_alive: AtomicU64::new(u64::MAX),
+                cores: Mutex::new(Vec::new()),
+                core_changes: AtomicUsize::new(0),
+                stack_size,
+                log_tasks: AtomicBool::new(false),
+                closed: AtomicBool::new(false),
+                queues: RwLock::new(Vec::new()),
+                next_queue: AtomicUsize::new(0),
+                urgent: Injector::new(),
+                pending: AtomicUsize::new(0),
+                capacity,
+                withdrawable: Mutex::new(VecDeque::new()),
+                blocked: AtomicUsize::new(0),
+                space: Mutex::new(()),
+                freed: Condvar::new(),
+                wakeable: AtomicBool::new(false),
+                wake_epoch: AtomicUsize::new(0),
+                idle: Mutex::new(Idle::default()),
+                wake: Condvar::new(),
+            }),
+            exited,
+            exits: Mutex::new(exits),
+            shutdown_timeout: None,
+            overflow: OverflowPolicy::Reject,
+            queue_timeout: None,
+        };
+        pool.spawn_workers(size)?;
+        Ok(pool)
+    }
+
+    pub fn worker_count(&self) -> usize {
+        self.shared.live.load(Ordering::SeqCst)
+    }
+
+    /// Id of the worker running the calling thread, or `None` when called
+    /// from outside any pool. Ids match the ones in the log lines.
+    pub fn current_worker_id() -> Option<usize> {
+        CURRENT_WORKER.with(Cell::get)
+    }
+
+    /// Number of tasks each current worker has started, ordered by worker id,
+    /// to check that the load is spread evenly. Workers that exited take
+    /// their counts with them.
+    pub fn tasks_per_worker(&self) -> Vec<u64> {
+        let mut workers: Vec<_> = self
+            .shared
+            .lock_workers()
+            .iter()
+            .map(|worker| (worker.id, worker.tasks.load(Ordering::Relaxed)))
+            .collect();
+        workers.sort_unstable();
+        workers.into_iter().map(|(_, tasks)| tasks).collect()
+    }
+
+    /// Number of tasks accepted by `execute` that no worker has started yet.
+    pub fn pending_tasks(&self) -> usize {
+        self.shared.pending.load(Ordering::SeqCst)
+    }
+
+    pub fn execute<F>(&self, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.execute_with_priority(Priority::Normal, f)
+    }
+
+    /// Like `execute`, but lets operational work such as a control command
+    /// jump ahead of the tasks already queued. Tasks of the same priority
+    /// start roughly in the order they were submitted; a worker that runs
+    /// out of tasks may steal ones queued for another worker.
+    ///
+    /// A high-priority task does not interrupt running tasks, so it waits
+    /// until a worker finishes its current one when every worker is busy.
+    pub fn execute_with_priority<F>(&self, priority: Priority, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let task: Task = match (priority, self.queue_timeout) {
+            (Priority::Normal, Some(timeout)) => {
+                let queued_at = Instant::now();
+                Box::new(move || {
+                    let waited = queued_at.elapsed();
+                    if waited > timeout {
+                        warn!(
+                            "Dropping a task that waited {:?} in the queue, longer than the queue timeout of {:?}",
+                            waited, timeout
+                        );
+                        return;
+                    }
+                    f()
+                })
+            }
+            _ => Box::new(f),
+        };
+        let shared = &*self.shared;
+
+        // Count the task before it becomes visible to workers, so a worker
+        // picking it up straight away never decrements below zero.
+        let mut withdrawn = None;
+        let reserved = match (priority, shared.capacity) {
+            (Priority::Normal, Some(capacity)) => match self.overflow {
+                OverflowPolicy::Reject => shared.reserve(capacity),
+                OverflowPolicy::DropOldest => loop {
+                    if shared.reserve(capacity) {
+                        break true;
+                    }
+                    // The withdrawn task's slot is handed over to the new one.
+                    withdrawn = shared.withdraw_oldest();
+                    if withdrawn.is_some() {
+                        break true;
+                    }
+                    // Unless every slot holds a high-priority task, one holds
+                    // a normal task on its way into a queue or out to a
+                    // worker, which either becomes withdrawable or frees its
+                    // slot in a moment.
+                    let pending = shared.pending.load(Ordering::SeqCst);
+                    if pending >= capacity && pending <= shared.urgent.len() {
+                        break false;
+                    }
+                    thread::yield_now();
+                },
+                OverflowPolicy::Block => {
+                    shared.reserve_blocking(capacity);
+                    true
+                }
+            },
+            _ => {
+                shared.pending.fetch_add(1, Ordering::SeqCst);
+                true
+            }
+        };
+        if !reserved {
+            return Err(ExecuteError::Full(task));
+        }
+        if let Some(withdrawn) = withdrawn {
+            warn!("Task queue is full, dropping the oldest queued task");
+            drop(withdrawn);
+        }
+
+        let result = match (priority, self.overflow) {
+            (Priority::Normal, OverflowPolicy::DropOldest) => {
+                shared.push_withdrawable(task).map_err(Operation::Execute)
+            }
+            (Priority::Normal, _) => shared.push(Operation::Execute(task)),
+            (Priority::High, _) => shared.push_urgent(task).map_err(Operation::Execute),
+        };
+        match result {
+            Ok(()) => Ok(()),
+            Err(Operation::Execute(task)) => {
+                shared.release();
+                Err(ExecuteError::Disconnected(task))
+            }
+            Err(_) => unreachable!(),
+        }
+    }
+
+    /// Runs `f` on the pool and returns a receiver that yields its result
+    /// once the task has finished. The receiver reports a disconnect instead
+    /// if the task panics.
+    pub fn execute_with_result<F, T>(&self, f: F) -> Result<mpsc::Receiver<T>, ExecuteError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel(1);
+
+        self.execute(move || {
+            let _ = sender.send(f());
+        })?;
+        Ok(receiver)
+    }
+
+    /// Grows or shrinks the pool to `new_size` workers, but never below the
+    /// minimum set with `set_keep_alive`.
+    ///
+    /// Shrinking lets the surplus workers exit once they have finished their
+    /// current task, so in-flight tasks are never cut short, and moves the
+    /// tasks queued for them to the remaining workers. It blocks until the
+    /// pool is down to `new_size` workers.
+    ///
+    /// Growing fails if a worker thread cannot be spawned; the workers that
+    /// were started before the failure stay in the pool.
+    pub fn resize(&self, new_size: usize) -> io::Result<()> {
+        assert!(new_size > 0);
+
+        let min_workers = self.shared.min_workers.load(Ordering::SeqCst);
+        let new_size = if new_size < min_workers {
+            warn!(
+                "Not shrinking thread pool below its minimum of {} workers",
+                min_workers
+            );
+            min_workers
+        } else {
+            new_size
+        };
+
+        // Holding the exit receiver for the whole call serializes resizes,
+        // so concurrent shrinks cannot reap each other's workers.
+        let exits = self.exits.lock().unwrap_or_else(recover_poisoned);
+        // Notices left behind by workers that exited on their own.
+        while exits.try_recv().is_ok() {}
+        let current = self.worker_count();
+        self.shared.target.store(new_size, Ordering::SeqCst);
+
+        if new_size > current {
+            info!(
+                "Growing thread pool from {} to {} workers",
+                current, new_size
+            );
+            self.spawn_workers(new_size - current)?;
+        } else if new_size < current {
+            info!(
+                "Shrinking thread pool from {} to {} workers",
+                current, new_size
+            );
+
+            // Parked workers have to wake up to notice they are surplus.
+            self.shared.wake_all();
+            while self.shared.lock_workers().len() > new_size {
+                if exits.recv().is_err() {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Lets workers beyond the first `min_workers` exit on their own once
+    /// they have gone `keep_alive` without a task, so a pool grown with
+    /// `resize` for a burst shrinks back afterwards, while load that comes
+    /// and goes within `keep_alive` keeps reusing the same threads. `None`,
+    /// the default, keeps idle workers around for good.
+    ///
+    /// The pool never shrinks below `min_workers`, neither through idle
+    /// workers exiting nor through `resize`. It does not grow to reach it
+    /// either.
+    pub fn set_keep_alive(&self, min_workers: usize, keep_alive: Option<Duration>) {
+        assert!(min_workers > 0);
+
+        let nanos = keep_alive.map_or(u64::MAX, |keep_alive| {
+            u64::try_from(keep_alive.as_nanos()).unwrap_or(u64::MAX)
+        });
+        self.shared.min_workers.store(min_workers, Ordering::SeqCst);
+        self.shared.keep_alive.store(nanos, Ordering::SeqCst);
+        // Parked workers have to wake up to start counting.
+        self.shared.wake_all();
+    }
+
+    /// Pins every worker, current and future, to one of `cores`, by worker
+    /// id round-robin, or to one of all the cores available to the process
+    /// if `cores` is empty. It keeps a worker on the same core instead of
+    /// having the scheduler move it around, which makes latencies steadier
+    /// when benchmarking. Busy workers pin themselves once they finish
+    /// their current task.
+    ///
+    /// Where thread affinity is not supported this only logs a warning.
+    pub fn pin_workers(&self, cores: &[usize]) {
+        let available = match core_affinity::get_core_ids() {
+            Some(available) => available,
+            None => {
+                warn!("Not pinning workers, CPU affinity is not supported on this platform");
+                return;
+            }
+        };
+        let cores = if cores.is_empty() {
+            available.iter().map(|core| core.id).collect()
+        } else {
+            cores.to_vec()
+        };
+
+        *self.shared.cores.lock().unwrap_or_else(recover_poisoned) = cores;
+        self.shared.core_changes.fetch_add(1, Ordering::SeqCst);
+        // Parked workers have to wake up to pin themselves.
+        self.shared.wake_all();
+    }
+
+    /// Has workers, current and future, log a line under `TASK_LOG_TARGET`
+    /// for every task they start. Off by default, since under load it
+    /// floods the log with a line per connection.
+    pub fn set_task_logging(&self, enabled: bool) {
+        self.shared.log_tasks.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Bounds how long `shutdown` and `Drop` wait for workers to finish
+    /// their tasks; `None`, the default, waits as long as it takes.
+    ///
+    /// Workers still busy when the timeout runs out are logged and detached,
+    /// not killed: they keep running their task until it returns or the
+    /// process exits.
+    pub fn set_shutdown_timeout(&mut self, timeout: Option<Duration>) {
+        self.shutdown_timeout = timeout;
+    }
+
+    /// Chooses what `execute` does once a pool made with `with_capacity`
+    /// has no free slot; `OverflowPolicy::Reject` by default. It has no
+    /// effect on an unbounded pool or on high-priority tasks.
+    ///
+    /// A task dropped under `OverflowPolicy::DropOldest` is dropped on the
+    /// calling thread without running, which for a connection handler
+    /// closes its connection.
+    pub fn set_overflow_policy(&mut self, policy: OverflowPolicy) {
+        self.overflow = policy;
+    }
+
+    /// Drops normal-priority tasks that waited longer than `timeout` for a
+    /// worker instead of running them, once a worker gets to them; `None`,
+    /// the default, runs every task however long it waited. Like a task
+    /// dropped for overflowing the queue, a connection handler dropped this
+    /// way closes its connection without serving it.
+    ///
+    /// Only tasks submitted after the call are affected.
+    pub fn set_queue_timeout(&mut self, timeout: Option<Duration>) {
+        self.queue_timeout = timeout;
+    }
+
+    pub fn shutdown(mut self) -> Result<(), Vec<Box<dyn Any + Send>>> {
+        let panics = self.terminate_workers();
+
+        if panics.is_empty() {
+            Ok(())
+        } else {
+            Err(panics)
+        }
+    }
+
+    fn terminate_workers(&mut self) -> Vec<Box<dyn Any + Send>> {
+        // Taking the workers over keeps them from detaching themselves, so
+        // every one of them is joined or reported below.
+        let mut workers = mem::take(&mut *self.shared.lock_workers());
+        if workers.is_empty() {
+            return Vec::new();
+        }
+
+        info!("Terminating thread pool responsible for request processing");
+
+        for _ in 0..workers.len() {
+            let _ = self.shared.push(Operation::Terminate);
+        }
+        self.shared.wake_all();
+
+        let mut panics = Vec::new();
+        let timeout = match self.shutdown_timeout {
+            Some(timeout) => timeout,
+            None => {
+                for worker in workers.drain(..) {
+                    worker.join(&mut panics);
+                }
+                return panics;
+            }
+        };
+
+        // `JoinHandle` has no timed join, so wait for the exit notices the
+        // workers send once they stop and only join those.
+        let exits = self.exits.get_mut().unwrap_or_else(recover_poisoned);
+        let deadline = Instant::now() + timeout;
+        while !workers.is_empty() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let id = match exits.recv_timeout(remaining) {
+                Ok(id) => id,
+                Err(_) => break,
+            };
+
+            if let Some(index) = workers.iter().position(|worker| worker.id == id) {
+                workers.swap_remove(index).join(&mut panics);
+            }
+        }
+
+        for worker in workers.drain(..) {
+            warn!(
+                "Worker {} did not finish within the shutdown timeout of {:?}, detaching it",
+                worker.id, timeout
+            );
+        }
+        panics
+    }
+
+    fn spawn_workers(&self, count: usize) -> io::Result<()> {
+        // Holding the lock until a worker is listed keeps it from exiting
+        // before it can find itself in the list.
+        let mut workers = self.shared.lock_workers();
+        for _ in 0..count {
+            let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+            workers.push(Worker::new(
+                id,
+                Arc::clone(&self.shared),
+                self.exited.clone(),
+            )?);
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown"
+    }
+}
+
+impl Shared {
+    // Queues `operation` for the next worker in turn, or hands it back once
+    // every worker has exited.
+    fn push(&self, operation: Operation) -> Result<(), Operation> {
+        {
+            let queues = self.read_queues();
+            if queues.is_empty() {
+                return Err(operation);
+            }
+            let index = self.next_queue.fetch_add(1, Ordering::Relaxed) % queues.len();
+            queues[index].1.push(operation);
+        }
+        self.wake_one();
+        Ok(())
+    }
+
+    // Queues `task` so a later `execute` can still withdraw it, or hands it
+    // back once every worker has exited.
+    fn push_withdrawable(&self, task: Task) -> Result<(), Task> {
+        let slot = Arc::new(Mutex::new(Some(task)));
+        if self
+            .push(Operation::Withdrawable(Arc::clone(&slot)))
+            .is_err()
+        {
+            // Nobody else ever saw the slot.
+            return Err(take(&slot).expect("unqueued task was taken"));
+        }
+
+        let mut withdrawable = self.lock_withdrawable();
+        while withdrawable.front().is_some_and(|oldest| is_taken(oldest)) {
+            withdrawable.pop_front();
+        }
+        withdrawable.push_back(slot);
+        Ok(())
+    }
+
+    // Takes the oldest task no worker has started yet out of the queue. Its
+    // slot stays reserved in `pending`.
+    fn withdraw_oldest(&self) -> Option<Task> {
+        let mut withdrawable = self.lock_withdrawable();
+        iter::from_fn(|| withdrawable.pop_front()).find_map(|slot| take(&slot))
+    }
+
+    // Reserves a slot in a queue that holds at most `capacity` tasks.
+    fn reserve(&self, capacity: usize) -> bool {
+        self.pending
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |pending| {
+                (pending < capacity).then_some(pending + 1)
+            })
+            .is_ok()
+    }
+
+    fn reserve_blocking(&self, capacity: usize) {
+        if self.reserve(capacity) {
+            return;
+        }
+
+        // Announce the wait before looking again: a worker freeing a slot
+        // either sees the waiter and notifies it, or its release is visible
+        // here.
+        self.blocked.fetch_add(1, Ordering::SeqCst);
+        let mut space = self.lock_space();
+        while !self.reserve(capacity) {
+            space = self.freed.wait(space).unwrap_or_else(recover_poisoned);
+        }
+        drop(space);
+        self.blocked.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    // Frees the slot of a task that started or never made it into a queue.
+    fn release(&self) {
+        self.pending.fetch_sub(1, Ordering::SeqCst);
+        if self.blocked.load(Ordering::SeqCst) > 0 {
+            let _space = self.lock_space();
+            self.freed.notify_one();
+        }
+    }
+
+    fn push_urgent(&self, task: Task) -> Result<(), Task> {
+        {
+            // Holding the lock keeps the last worker from exiting between
+            // the check and the push, leaving the task behind.
+            let queues = self.read_queues();
+            if queues.is_empty() {
+                return Err(task);
+            }
+            self.urgent.push(task);
+        }
+        self.wake_one();
+        Ok(())
+    }
+
+    // Urgent tasks come first, then the worker's own queue, then whatever
+    // the other workers have not started yet.
+    fn find(&self, id: usize, own: &Injector<Operation>) -> Option<Operation> {
+        if let Some(task) = steal(&self.urgent) {
+            return Some(Operation::Execute(task));
+        }
+        if let Some(operation) = claim(own) {
+            return Some(operation);
+        }
+        self.read_queues()
+            .iter()
+            .filter(|(queue_id, _)| *queue_id != id)
+            .find_map(|(_, queue)| claim(queue))
+    }
+
+    // Blocks until an operation is available to `id`, or returns `None` once
+    // `timeout` has passed or the worker was woken up without finding one,
+    // so it can check whether it should exit. `epoch` is the `wake_epoch`
+    // read before the worker last checked the pool's settings.
+    fn next(
+        &self,
+        id: usize,
+        own: &Injector<Operation>,
+        timeout: Option<Duration>,
+        epoch: usize,
+    ) -> Option<Operation> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut woken = false;
+        loop {
+            if let Some(operation) = self.find(id, own) {
+                if woken {
+                    self.wake_one();
+                }
+                return Some(operation);
+            }
+            if woken {
+                return None;
+            }
+
+            let mut idle = self.lock_idle();
+            // Announce the sleep before looking again: a producer either sees
+            // the sleeper and wakes it, or its push is visible here.
+            idle.sleepers += 1;
+            self.wakeable.store(idle.wakeable(), Ordering::SeqCst);
+            if let Some(operation) = self.find(id, own) {
+                idle.sleepers -= 1;
+                self.wakeable.store(idle.wakeable(), Ordering::SeqCst);
+                return Some(operation);
+            }
+            // Checked under the lock `wake_all` takes, so a worker never
+            // parks for good after missing the wakeup of a closed pool or of
+            // a change to its settings.
+            if self.closed.load(Ordering::SeqCst) || self.wake_epoch.load(Ordering::SeqCst) != epoch
+            {
+                idle.sleepers -= 1;
+                self.wakeable.store(idle.wakeable(), Ordering::SeqCst);
+                return None;
+            }
+
+            while idle.wakeups == 0 {
+                idle = match deadline {
+                    None => self.wake.wait(idle).unwrap_or_else(recover_poisoned),
+                    Some(deadline) => {
+                        let remaining = deadline.saturating_duration_since(Instant::now());
+                        if remaining.is_zero() {
+                            // Nobody handed this sleeper a wakeup, or it would
+                            // be counted in `wakeups` instead.
+                            idle.sleepers -= 1;
+                            self.wakeable.store(idle.wakeable(), Ordering::SeqCst);
+                            return None;
+                        }
+                        self.wake
+                            .wait_timeout(idle, remaining)
+                            .unwrap_or_else(recover_poisoned)
+                            .0
+                    }
+                };
+            }
+            idle.wakeups -= 1;
+            self.wakeable.store(idle.wakeable(), Ordering::SeqCst);
+            woken = true;
+        }
+    }
+
+    // Lets the calling worker exit if that leaves more than `floor` workers.
+    fn leave(&self, floor: usize) -> bool {
+        self.live
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| {
+                (live > floor).then(|| live - 1)
+            })
+            .is_ok()
+    }
+
+    // Pins the calling worker to its share of `cores`.
+    fn pin(&self, id: usize) {
+        let core = {
+            let cores = self.cores.lock().unwrap_or_else(recover_poisoned);
+            match cores.len() {
+                0 => return,
+                len => cores[id % len],
+            }
+        };
+        if core_affinity::set_for_current(CoreId { id: core }) {
+            debug!("Worker {} pinned to core {}", id, core);
+        } else {
+            warn!("Could not pin worker {} to core {}", id, core);
+        }
+    }
+
+    fn keep_alive(&self) -> Option<Duration> {
+        match self.keep_alive.load(Ordering::SeqCst) {
+            u64::MAX => None,
+            nanos => Some(Duration::from_nanos(nanos)),
+        }
+    }
+
+    fn wake_one(&self) {
+        atomic::fence(Ordering::SeqCst);
+        if !self.wakeable.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let mut idle = self.lock_idle();
+        if idle.wakeable() {
+            idle.sleepers -= 1;
+            idle.wakeups += 1;
+            self.wakeable.store(false, Ordering::SeqCst);
+            self.wake.notify_one();
+        }
+    }
+
+    fn wake_all(&self) {
+        let mut idle = self.lock_idle();
+        self.wake_epoch.fetch_add(1, Ordering::SeqCst);
+        idle.wakeups += idle.sleepers;
+        idle.sleepers = 0;
+        self.wakeable.store(false, Ordering::SeqCst);
+        self.wake.notify_all();
+    }
+
+    // Removes the queue of an exiting worker and moves what is left in it to
+    // the remaining workers. The last worker to exit gets the leftover tasks
+    // back to run them itself, so no accepted task is dropped unrun.
+    fn retire(&self, id: usize, own: &Injector<Operation>) -> Vec<Task> {
+        let mut leftovers = Vec::new();
+        let mut moved = false;
+        {
+            let mut queues = self.queues.write().unwrap_or_else(recover_poisoned);
+            queues.retain(|(queue_id, _)| *queue_id != id);
+
+            while let Some(operation) = steal(own) {
+                if queues.is_empty() {
+                    match operation {
+                        Operation::Execute(task) => leftovers.push(task),
+                        Operation::Withdrawable(slot) => leftovers.extend(take(&slot)),
+                        Operation::Terminate => {}
+                    }
+                } else {
+                    let index = self.next_queue.fetch_add(1, Ordering::Relaxed) % queues.len();
+                    queues[index].1.push(operation);
+                    moved = true;
+                }
+            }
+            if queues.is_empty() {
+                leftovers.extend(iter::from_fn(|| steal(&self.urgent)));
+            }
+        }
+
+        if moved {
+            self.wake_all();
+        }
+        leftovers
+    }
+
+    fn lock_workers(&self) -> MutexGuard<'_, Vec<Worker>> {
+        self.workers.lock().unwrap_or_else(recover_poisoned)
+    }
+
+    fn lock_idle(&self) -> MutexGuard<'_, Idle> {
+        self.idle.lock().unwrap_or_else(recover_poisoned)
+    }
+
+    fn lock_withdrawable(&self) -> MutexGuard<'_, VecDeque<Arc<Slot>>> {
+        self.withdrawable.lock().unwrap_or_else(recover_poisoned)
+    }
+
+    fn lock_space(&self) -> MutexGuard<'_, ()> {
+        self.space.lock().unwrap_or_else(recover_poisoned)
+    }
+
+    fn read_queues(&self) -> RwLockReadGuard<'_, Vec<(usize, Arc<Injector<Operation>>)>> {
+        self.queues.read().unwrap_or_else(recover_poisoned)
+    }
+}
+
+fn steal<T>(queue: &Injector<T>) -> Option<T> {
+    iter::repeat_with(|| queue.steal())
+        .find(|steal| !steal.is_retry())
+        .and_then(Steal::success)
+}
+
+// Takes the next operation off `queue`, skipping the tasks that were
+// withdrawn while they waited in it.
+fn claim(queue: &Injector<Operation>) -> Option<Operation> {
+    loop {
+        match steal(queue)? {
+            Operation::Withdrawable(slot) => {
+                if let Some(task) = take(&slot) {
+                    return Some(Operation::Execute(task));
+                }
+            }
+            operation => return Some(operation),
+        }
+    }
+}
+
+// Recovers the guard of a lock that a panicking thread poisoned, so the
+// pool keeps working, and warns the first time that happens.
+fn recover_poisoned<T>(poisoned: PoisonError<T>) -> T {
+    static WARNED: AtomicBool = AtomicBool::new(false);
+    if !WARNED.swap(true, Ordering::Relaxed) {
+        warn!("Recovered a thread pool lock poisoned by a panicking thread");
+    }
+    poisoned.into_inner()
+}
+
+fn take(slot: &Slot) -> Option<Task> {
+    slot.lock().unwrap_or_else(recover_poisoned).take()
+}
+
+fn is_taken(slot: &Slot) -> bool {
+    slot.lock().unwrap_or_else(recover_poisoned).is_none()
+}
+
+/// Returned by `ThreadPool::execute` when the task could not be enqueued,
+/// handing the rejected task back to the caller.
+pub enum ExecuteError {
+    /// The bounded task queue has no free slot.
+    Full(Task),
+    /// The workers are gone and no task will ever be picked up again.
+    Disconnected(Task),
+}
+
+impl ExecuteError {
+    pub fn is_full(&self) -> bool {
+        matches!(self, ExecuteError::Full(_))
+    }
+
+    pub fn into_task(self) -> Task {
+        match self {
+            ExecuteError::Full(task) | ExecuteError::Disconnected(task) => task,
+        }
+    }
+}
+
+impl fmt::Debug for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecuteError::Full(_) => f.write_str("Full(..)"),
+            ExecuteError::Disconnected(_) => f.write_str("Disconnected(..)"),
+        }
+    }
+}
+
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecuteError::Full(_) => f.write_str("task queue is full"),
+            ExecuteError::Disconnected(_) => {
+                f.write_str("thread pool is no longer accepting tasks")
+            }
+        }
+    }
+}
+
+impl Error for ExecuteError {}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::SeqCst);
+        self.shared.wake_all();
+        self.terminate_workers();
+    }
+}
+
+struct Worker {
+    id: usize,
+    // Tasks started so far, not counting terminate operations.
+    tasks: Arc<AtomicU64>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Worker {
+    pub fn new(id: usize, shared: Arc<Shared>, exited: mpsc::Sender<usize>) -> io::Result<Worker> {
+        let queue = Arc::new(Injector::new());
+        let tasks = Arc::new(AtomicU64::new(0));
+        shared.live.fetch_add(1, Ordering::SeqCst);
+        shared
+            .queues
+            .write()
+            .unwrap_or_else(recover_poisoned)
+            .push((id, Arc::clone(&queue)));
+
+        // Named after the id used in the log lines, so the thread shows up
+        // under the same name in debuggers and `top -H`.
+        let mut builder = thread::Builder::new().name(format!("echo-worker-{}", id));
+        if let Some(stack_size) = shared.stack_size {
+            builder = builder.stack_size(stack_size);
+        }
+        let spawned = builder.spawn({
+            let shared = Arc::clone(&shared);
+            let queue = Arc::clone(&queue);
+            let tasks = Arc::clone(&tasks);
+            move || {
+                CURRENT_WORKER.with(|current| current.set(Some(id)));
+                if Worker::run(id, &queue, &shared, &tasks) {
+                    Worker::detach(id, &shared);
+                }
+                let _ = exited.send(id);
+            }
+        });
+
+        let thread = match spawned {
+            Ok(thread) => thread,
+            Err(e) => {
+                // Nothing was queued for `id` yet that another worker cannot
+                // take over.
+                shared.live.fetch_sub(1, Ordering::SeqCst);
+                shared.retire(id, &queue);
+                return Err(e);
+            }
+        };
+
+        Ok(Worker {
+            id,
+            tasks,
+            thread: Some(thread),
+        })
+    }
+
+    fn join(self, panics: &mut Vec<Box<dyn Any + Send>>) {
+        if let Some(thread) = self.thread {
+            if let Err(panic) = thread.join() {
+                error!(
+                    "Worker {} panicked, reason: {}",
+                    self.id,
+                    panic_message(&*panic)
+                );
+                panics.push(panic);
+            }
+        }
+    }
+
+    // Runs tasks until the worker is terminated, returning `false`, or
+    // leaves a pool that has more workers than it needs, returning `true`.
+    fn run(id: usize, queue: &Injector<Operation>, shared: &Shared, tasks: &AtomicU64) -> bool {
+        let mut keep_alive = shared.keep_alive();
+        let mut idle_since = Instant::now();
+        let mut core_changes = 0;
+        let leaving = loop {
+            let epoch = shared.wake_epoch.load(Ordering::SeqCst);
+            if shared.leave(shared.target.load(Ordering::SeqCst)) {
+                debug!("Worker {} exits to shrink the pool", id);
+                break true;
+            }
+
+            let changes = shared.core_changes.load(Ordering::SeqCst);
+            if changes != core_changes {
+                core_changes = changes;
+                shared.pin(id);
+            }
+
+            // A changed keep-alive counts from when the worker noticed it.
+            let current = shared.keep_alive();
+            if current != keep_alive {
+                keep_alive = current;
+                idle_since = Instant::now();
+            }
+            let timeout =
+                keep_alive.map(|keep_alive| keep_alive.saturating_sub(idle_since.elapsed()));
+            match shared.next(id, queue, timeout, epoch) {
+                Some(Operation::Execute(task)) => {
+                    Worker::execute(id, task, shared, tasks);
+                    // Reading the clock after every task is only worth it
+                    // when idle workers may exit.
+                    if keep_alive.is_some() {
+                        idle_since = Instant::now();
+                    }
+                }
+                // `find` hands these out as the task they hold.
+                Some(Operation::Withdrawable(_)) => unreachable!(),
+                Some(Operation::Terminate) => {
+                    debug!("Worker {} received terminate signal", id);
+                    shared.live.fetch_sub(1, Ordering::SeqCst);
+                    break false;
+                }
+                None if shared.closed.load(Ordering::SeqCst) => {
+                    debug!("Worker {} exits, the pool was dropped", id);
+                    shared.live.fetch_sub(1, Ordering::SeqCst);
+                    break false;
+                }
+                None => match keep_alive {
+                    Some(keep_alive) if idle_since.elapsed() >= keep_alive => {
+                        if shared.leave(shared.min_workers.load(Ordering::SeqCst)) {
+                            debug!("Worker {} exits after {:?} without a task", id, keep_alive);
+                            break true;
+                        }
+                        // The pool is at its minimum, so keep waiting.
+                        idle_since = Instant::now();
+                    }
+                    _ => {}
+                },
+            }
+        };
+
+        for task in shared.retire(id, queue) {
+            Worker::execute(id, task, shared, tasks);
+        }
+        leaving
+    }
+
+    // Drops the handle of a worker that leaves on its own, which detaches its
+    // thread, unless the pool is shutting down and already took it over.
+    fn detach(id: usize, shared: &Shared) {
+        let mut workers = shared.lock_workers();
+        if let Some(index) = workers.iter().position(|worker| worker.id == id) {
+            workers.swap_remove(index);
+        }
+    }
+
+    fn execute(id: usize, task: Task, shared: &Shared, tasks: &AtomicU64) {
+        shared.release();
+        if shared.log_tasks.load(Ordering::Relaxed) {
+            info!(target: TASK_LOG_TARGET, "Worker {} starts processing new request", id);
+        }
+        tasks.fetch_add(1, Ordering::Relaxed);
+        if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(task)) {
+            error!(
+                "Worker {} recovered from panicking task, reason: {}",
+                id,
+                panic_message(&*panic)
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    // Holds back the tasks waiting on it until it is opened.
+    #[derive(Default)]
+    struct Gate {
+        open: Mutex<bool>,
+        opened: Condvar,
+    }
+
+    impl Gate {
+        fn wait(&self) {
+            let mut open = self.open.lock().unwrap();
+            while !*open {
+                open = self.opened.wait(open).unwrap();
+            }
+        }
+
+        fn open(&self) {
+            *self.open.lock().unwrap() = true;
+            self.opened.notify_all();
+        }
+    }
+
+    // Keeps `count` workers busy until `gate` opens, returning once all of
+    // them started.
+    fn occupy(pool: &ThreadPool, count: usize, gate: &Arc<Gate>) {
+        let (started, starts) = mpsc::channel();
+        for _ in 0..count {
+            let (started, gate) = (started.clone(), Arc::clone(gate));
+            pool.execute(move || {
+                started.send(()).unwrap();
+                gate.wait();
+            })
+            .unwrap();
+        }
+        for _ in 0..count {
+            starts
+                .recv_timeout(TIMEOUT)
+                .expect("a worker never started");
+        }
+    }
+
+    fn eventually(what: &str, mut condition: impl FnMut() -> bool) {
+        let deadline = Instant::now() + TIMEOUT;
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn execute_hands_the_task_back_once_every_worker_is_gone() {
+        let mut pool = ThreadPool::new(2).unwrap();
+        assert!(pool.terminate_workers().is_empty());
+
+        let (ran, runs) = mpsc::channel();
+        let e = pool.execute(move || ran.send(()).unwrap()).unwrap_err();
+
+        assert!(!e.is_full());
+        assert_eq!(e.to_string(), "thread pool is no longer accepting tasks");
+        assert_eq!(pool.pending_tasks(), 0);
+        // The task comes back intact and still runs when called.
+        e.into_task()();
+        assert!(runs.try_recv().is_ok());
+    }
+
+    #[test]
+    fn shutdown_returns_ok_once_every_worker_stopped() {
+        let pool = ThreadPool::new(3).unwrap();
+        let result = pool.execute_with_result(|| 42).unwrap();
+
+        assert_eq!(result.recv().unwrap(), 42);
+        assert!(pool.shutdown().is_ok());
+    }
+
+    // A panic payload whose drop panics again, outside the `catch_unwind`
+    // guarding the task, which is the one way left to take a worker down.
+    struct PanicOnDrop;
+
+    impl Drop for PanicOnDrop {
+        fn drop(&mut self) {
+            panic!("worker taken down");
+        }
+    }
+
+    #[test]
+    fn shutdown_returns_the_panics_of_workers_that_died() {
+        let pool = ThreadPool::new(1).unwrap();
+        pool.execute(|| panic::panic_any(PanicOnDrop)).unwrap();
+
+        let panics = pool.shutdown().unwrap_err();
+
+        assert_eq!(panics.len(), 1);
+        assert_eq!(panic_message(&*panics[0]), "worker taken down");
+    }
+
+    #[test]
+    fn worker_survives_a_panicking_task() {
+        let pool = ThreadPool::new(1).unwrap();
+        pool.execute(|| panic!("task failed")).unwrap();
+        let result = pool
+            .execute_with_result(ThreadPool::current_worker_id)
+            .unwrap();
+
+        assert_eq!(result.recv_timeout(TIMEOUT).unwrap(), Some(0));
+        assert_eq!(pool.worker_count(), 1);
+        assert_eq!(pool.tasks_per_worker(), vec![2]);
+    }
+
+    #[test]
+    fn execute_reports_a_full_queue() {
+        let pool = ThreadPool::with_capacity(1, 2).unwrap();
+        let gate = Arc::new(Gate::default());
+        occupy(&pool, 1, &gate);
+        for _ in 0..2 {
+            let gate = Arc::clone(&gate);
+            pool.execute(move || gate.wait()).unwrap();
+        }
+
+        let e = pool.execute(|| {}).unwrap_err();
+
+        assert!(e.is_full());
+        assert_eq!(e.to_string(), "task queue is full");
+        assert_eq!(pool.pending_tasks(), 2);
+        gate.open();
+        // Slots free up again as the worker gets to the queued tasks.
+        eventually("the queue to drain", || pool.pending_tasks() == 0);
+        assert!(pool.execute(|| {}).is_ok());
+    }
+
+    #[test]
+    fn pending_tasks_rise_and_fall_with_the_queue() {
+        let mut pool = ThreadPool::new(2).unwrap();
+        let gate = Arc::new(Gate::default());
+        occupy(&pool, 2, &gate);
+        assert_eq!(pool.worker_count(), 2);
+        assert_eq!(pool.pending_tasks(), 0);
+
+        for queued in 1..=4 {
+            let gate = Arc::clone(&gate);
+            pool.execute(move || gate.wait()).unwrap();
+            assert_eq!(pool.pending_tasks(), queued);
+        }
+        gate.open();
+
+        eventually("the queue to drain", || pool.pending_tasks() == 0);
+        // Terminate operations are never counted as pending.
+        assert!(pool.terminate_workers().is_empty());
+        assert_eq!(pool.pending_tasks(), 0);
+        assert_eq!(pool.worker_count(), 0);
+    }
+
+    #[test]
+    fn resize_grows_to_run_more_tasks_at_once_and_shrinks_back() {
+        let pool = ThreadPool::new(2).unwrap();
+
+        pool.resize(4).unwrap();
+        assert_eq!(pool.worker_count(), 4);
+        // Only returns once four tasks are blocked at the same time.
+        let gate = Arc::new(Gate::default());
+        occupy(&pool, 4, &gate);
+        gate.open();
+
+        pool.resize(2).unwrap();
+        assert_eq!(pool.worker_count(), 2);
+        assert_eq!(pool.tasks_per_worker().len(), 2);
+        let result = pool.execute_with_result(|| 42).unwrap();
+        assert_eq!(result.recv_timeout(TIMEOUT).unwrap(), 42);
+    }
+
+    #[test]
+    fn shrinking_never_cuts_a_running_task_short() {
+        let pool = ThreadPool::new(3).unwrap();
+        let gate = Arc::new(Gate::default());
+        let (finished, finishes) = mpsc::channel();
+        let (started, starts) = mpsc::channel();
+        for _ in 0..3 {
+            let (gate, started, finished) = (Arc::clone(&gate), started.clone(), finished.clone());
+            pool.execute(move || {
+                started.send(()).unwrap();
+                gate.wait();
+                finished.send(()).unwrap();
+            })
+            .unwrap();
+        }
+        for _ in 0..3 {
+            starts.recv_timeout(TIMEOUT).unwrap();
+        }
+
+        thread::scope(|scope| {
+            let shrinking = scope.spawn(|| pool.resize(1));
+            // The surplus workers only leave once their task is done.
+            thread::sleep(Duration::from_millis(50));
+            assert!(!shrinking.is_finished());
+            gate.open();
+            shrinking.join().unwrap().unwrap();
+        });
+
+        assert_eq!(finishes.try_iter().count(), 3);
+        assert_eq!(pool.worker_count(), 1);
+    }
+
+    #[test]
+    fn high_priority_task_runs_ahead_of_queued_normal_ones() {
+        let pool = ThreadPool::new(1).unwrap();
+        let gate = Arc::new(Gate::default());
+        occupy(&pool, 1, &gate);
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let record = |name: &'static str| {
+            let order = Arc::clone(&order);
+            move || order.lock().unwrap().push(name)
+        };
+        for name in &["first", "second", "third"] {
+            pool.execute(record(name)).unwrap();
+        }
+        pool.execute_with_priority(Priority::High, record("urgent"))
+            .unwrap();
+
+        gate.open();
+        eventually("the queue to drain", || order.lock().unwrap().len() == 4);
+
+        assert_eq!(
+            *order.lock().unwrap(),
+            ["urgent", "first", "second", "third"]
+        );
+    }
+
+    #[test]
+    fn shutdown_timeout_detaches_a_stuck_worker() {
+        let mut pool = ThreadPool::new(2).unwrap();
+        pool.set_shutdown_timeout(Some(Duration::from_millis(50)));
+        let (started, starts) = mpsc::channel();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            thread::sleep(Duration::from_secs(2));
+        })
+        .unwrap();
+        starts.recv_timeout(TIMEOUT).unwrap();
+
+        let shutting_down = Instant::now();
+        // The idle worker is joined, the stuck one left running.
+        assert!(pool.shutdown().is_ok());
+        assert!(shutting_down.elapsed() < Duration::from_secs(1));
+    }
+
+    // Workers park without a timeout, so a wakeup lost between a push and a
+    // worker going to sleep would leave the task waiting forever.
+    #[test]
+    fn tasks_submitted_to_idle_workers_are_never_left_waiting() {
+        let pool = ThreadPool::new(4).unwrap();
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for i in 0..2_000 {
+                        let result = pool.execute_with_result(move || i).unwrap();
+                        assert_eq!(
+                            result.recv_timeout(TIMEOUT),
+                            Ok(i),
+                            "task {} was stranded",
+                            i
+                        );
+                    }
+                });
+            }
+        });
+        assert_eq!(pool.tasks_per_worker().iter().sum::<u64>(), 8_000);
+    }
+
+    #[test]
+    fn tasks_survive_resizes_racing_with_submissions() {
+        let pool = ThreadPool::new(4).unwrap();
+        let ran = Arc::new(AtomicUsize::new(0));
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                for size in [1, 6, 2, 8, 1, 3].iter().cycle().take(60) {
+                    pool.resize(*size).unwrap();
+                }
+            });
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    for _ in 0..5_000 {
+                        let ran = Arc::clone(&ran);
+                        pool.execute(move || {
+                            ran.fetch_add(1, Ordering::SeqCst);
+                        })
+                        .unwrap();
+                    }
+                });
+            }
+        });
+
+        eventually("every task to run", || ran.load(Ordering::SeqCst) == 10_000);
+        assert_eq!(pool.pending_tasks(), 0);
+        assert_eq!(pool.worker_count(), 3);
+    }
+
+    #[test]
+    fn shutdown_races_with_idle_workers_leaving_on_their_own() {
+        for _ in 0..50 {
+            let pool = ThreadPool::new(4).unwrap();
+            pool.set_keep_alive(1, Some(Duration::from_millis(1)));
+            let ran = Arc::new(AtomicUsize::new(0));
+            for _ in 0..20 {
+                let ran = Arc::clone(&ran);
+                pool.execute(move || {
+                    ran.fetch_add(1, Ordering::SeqCst);
+                })
+                .unwrap();
+            }
+            thread::sleep(Duration::from_millis(1));
+
+            // Every accepted task runs, whoever ends up running it.
+            assert!(pool.shutdown().is_ok());
+            assert_eq!(ran.load(Ordering::SeqCst), 20);
+        }
+    }
+
+    #[test]
+    fn keep_alive_reuses_workers_under_oscillating_load_and_settles_at_the_minimum() {
+        let pool = ThreadPool::new(4).unwrap();
+        pool.set_keep_alive(2, Some(Duration::from_millis(100)));
+
+        // Bursts that come back within the keep-alive find every worker
+        // still there.
+        for _ in 0..5 {
+            let gate = Arc::new(Gate::default());
+            occupy(&pool, 4, &gate);
+            gate.open();
+            thread::sleep(Duration::from_millis(10));
+            assert_eq!(pool.worker_count(), 4);
+        }
+
+        eventually("the idle workers to exit", || {
+            let count = pool.worker_count();
+            assert!(count >= 2, "pool shrank to {} workers", count);
+            count == 2
+        });
+        for _ in 0..20 {
+            thread::sleep(Duration::from_millis(10));
+            assert_eq!(pool.worker_count(), 2);
+        }
+        pool.resize(1).unwrap();
+        assert_eq!(pool.worker_count(), 2);
+        // The workers that stayed still run tasks.
+        let gate = Arc::new(Gate::default());
+        occupy(&pool, 2, &gate);
+        gate.open();
+    }
+
+    // Counts the tasks dropped without running.
+    struct Dropped(Arc<AtomicUsize>);
+
+    impl Drop for Dropped {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn drop_oldest_accounts_for_every_task_under_contention() {
+        let mut pool = ThreadPool::with_capacity(2, 8).unwrap();
+        pool.set_overflow_policy(OverflowPolicy::DropOldest);
+        let (ran, dropped) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..2_000 {
+                        let (ran, guard) = (Arc::clone(&ran), Dropped(Arc::clone(&dropped)));
+                        pool.execute(move || {
+                            ran.fetch_add(1, Ordering::SeqCst);
+                            mem::forget(guard);
+                        })
+                        .unwrap();
+                        assert!(pool.pending_tasks() <= 8);
+                    }
+                });
+            }
+        });
+
+        let settled = || ran.load(Ordering::SeqCst) + dropped.load(Ordering::SeqCst) == 8_000;
+        eventually("every task to run or be dropped", settled);
+        assert_eq!(pool.pending_tasks(), 0);
+    }
+
+    #[test]
+    fn reject_hands_the_newest_task_back_when_full() {
+        let pool = ThreadPool::with_capacity(1, 1).unwrap();
+        let gate = Arc::new(Gate::default());
+        occupy(&pool, 1, &gate);
+        let (ran, runs) = mpsc::channel();
+        let queued = ran.clone();
+        pool.execute(move || queued.send("queued").unwrap())
+            .unwrap();
+
+        let e = pool
+            .execute(move || ran.send("newest").unwrap())
+            .unwrap_err();
+
+        assert!(e.is_full());
+        drop(e);
+        gate.open();
+        assert_eq!(runs.recv_timeout(TIMEOUT), Ok("queued"));
+        // The rejected task was dropped with its sender.
+        assert!(runs.recv_timeout(TIMEOUT).is_err());
+    }
+
+    #[test]
+    fn drop_oldest_drops_the_task_queued_the_longest() {
+        let mut pool = ThreadPool::with_capacity(1, 2).unwrap();
+        pool.set_overflow_policy(OverflowPolicy::DropOldest);
+        let gate = Arc::new(Gate::default());
+        occupy(&pool, 1, &gate);
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let (ran, runs) = mpsc::channel();
+        for name in &["oldest", "second", "newest"] {
+            let (ran, guard) = (ran.clone(), Dropped(Arc::clone(&dropped)));
+            pool.execute(move || {
+                ran.send(*name).unwrap();
+                mem::forget(guard);
+            })
+            .unwrap();
+        }
+
+        // Dropped on the submitting thread, which closes a connection.
+        assert_eq!(dropped.load(Ordering::SeqCst), 1);
+        assert_eq!(pool.pending_tasks(), 2);
+        gate.open();
+        assert_eq!(runs.recv_timeout(TIMEOUT), Ok("second"));
+        assert_eq!(runs.recv_timeout(TIMEOUT), Ok("newest"));
+    }
+
+    #[test]
+    fn drop_oldest_never_drops_a_high_priority_task() {
+        let mut pool = ThreadPool::with_capacity(1, 1).unwrap();
+        pool.set_overflow_policy(OverflowPolicy::DropOldest);
+        let gate = Arc::new(Gate::default());
+        occupy(&pool, 1, &gate);
+        pool.execute_with_priority(Priority::High, || {}).unwrap();
+
+        assert!(pool.execute(|| {}).unwrap_err().is_full());
+        gate.open();
+    }
+
+    #[test]
+    fn block_holds_the_caller_until_a_slot_frees() {
+        let mut pool = ThreadPool::with_capacity(1, 1).unwrap();
+        pool.set_overflow_policy(OverflowPolicy::Block);
+        let gate = Arc::new(Gate::default());
+        occupy(&pool, 1, &gate);
+        pool.execute(|| {}).unwrap();
+
+        thread::scope(|scope| {
+            let blocked = scope.spawn(|| pool.execute_with_result(|| 42).unwrap());
+            thread::sleep(Duration::from_millis(50));
+            assert!(!blocked.is_finished());
+            gate.open();
+            let result = blocked.join().unwrap();
+            assert_eq!(result.recv_timeout(TIMEOUT), Ok(42));
+        });
+    }
+
+    #[test]
+    fn tasks_per_worker_adds_up_to_the_tasks_submitted() {
+        let pool = ThreadPool::new(4).unwrap();
+        let ran = Arc::new(AtomicUsize::new(0));
+        for _ in 0..1_000 {
+            let ran = Arc::clone(&ran);
+            pool.execute(move || {
+                ran.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+        }
+        eventually("every task to run", || ran.load(Ordering::SeqCst) == 1_000);
+        assert_eq!(pool.tasks_per_worker().len(), 4);
+        assert_eq!(pool.tasks_per_worker().iter().sum::<u64>(), 1_000);
+
+        // Terminated workers stay listed until the pool joins them.
+        for _ in 0..4 {
+            assert!(pool.shared.push(Operation::Terminate).is_ok());
+        }
+        eventually("every worker to stop", || pool.worker_count() == 0);
+        assert_eq!(pool.tasks_per_worker().iter().sum::<u64>(), 1_000);
+    }
+
+    #[test]
+    fn workers_keep_processing_after_a_pool_lock_is_poisoned() {
+        let pool = ThreadPool::new(2).unwrap();
+        thread::scope(|scope| {
+            let poisoning = scope.spawn(|| {
+                let _idle = pool.shared.lock_idle();
+                let _queues = pool.shared.queues.write().unwrap();
+                panic!("poisoning the pool locks");
+            });
+            assert!(poisoning.join().is_err());
+        });
+        assert!(pool.shared.idle.is_poisoned());
+        assert!(pool.shared.queues.is_poisoned());
+
+        for i in 0..100 {
+            let result = pool.execute_with_result(move || i).unwrap();
+            assert_eq!(result.recv_timeout(TIMEOUT), Ok(i));
+        }
+        pool.resize(4).unwrap();
+        let gate = Arc::new(Gate::default());
+        occupy(&pool, 4, &gate);
+        gate.open();
+    }
+
+    #[test]
+    fn workers_exit_when_the_pool_is_dropped_without_terminating_them() {
+        let pool = ThreadPool::new(3).unwrap();
+        let result = pool.execute_with_result(|| 42).unwrap();
+        assert_eq!(result.recv_timeout(TIMEOUT), Ok(42));
+        let shared = Arc::clone(&pool.shared);
+        // With no workers listed, dropping the pool sends no terminates.
+        let workers = mem::take(&mut *shared.lock_workers());
+
+        drop(pool);
+
+        eventually("every worker to exit", || {
+            shared.live.load(Ordering::SeqCst) == 0
+        });
+        let mut panics = Vec::new();
+        for worker in workers {
+            worker.join(&mut panics);
+        }
+        assert!(panics.is_empty());
+    }
+
+    #[test]
+    fn execute_with_result_yields_the_value_or_a_disconnect_on_panic() {
+        let pool = ThreadPool::new(1).unwrap();
+
+        let sum = pool.execute_with_result(|| 2 + 2).unwrap();
+        let failed = pool
+            .execute_with_result(|| -> u32 { panic!("task failed") })
+            .unwrap();
+
+        assert_eq!(sum.recv_timeout(TIMEOUT), Ok(4));
+        assert_eq!(
+            failed.recv_timeout(TIMEOUT),
+            Err(mpsc::RecvTimeoutError::Disconnected)
+        );
+    }
+
+    #[test]
+    fn worker_threads_are_named_after_their_worker_id() {
+        let pool = ThreadPool::new(2).unwrap();
+
+        for _ in 0..4 {
+            let (name, id) = pool
+                .execute_with_result(|| {
+                    let name = thread::current().name().map(String::from);
+                    (name, ThreadPool::current_worker_id())
+                })
+                .unwrap()
+                .recv_timeout(TIMEOUT)
+                .unwrap();
+
+            let id = id.expect("the task ran outside a worker");
+            assert_eq!(name, Some(format!("echo-worker-{}", id)));
+        }
+    }
+
+    #[test]
+    fn large_stack_runs_a_task_that_would_overflow_the_default_one() {
+        const STACK_SIZE: usize = 64 * 1024 * 1024;
+        let pool = ThreadPool::with_stack_size(1, None, STACK_SIZE).unwrap();
+
+        // Far more than the 2 MiB std gives spawned threads by default, even
+        // before debug builds copy it around.
+        let sum = pool
+            .execute_with_result(|| {
+                let buffer = std::hint::black_box([1u8; 8 * 1024 * 1024]);
+                buffer.iter().map(|&byte| u64::from(byte)).sum::<u64>()
+            })
+            .unwrap();
+
+        assert_eq!(sum.recv_timeout(TIMEOUT), Ok(8 * 1024 * 1024));
+    }
+
+    #[test]
+    #[should_panic]
+    fn stack_below_the_minimum_is_refused() {
+        let _ = ThreadPool::with_stack_size(1, None, MIN_STACK_SIZE - 1);
+    }
+
+    // The cores the calling thread may run on, as the kernel lists them.
+    #[cfg(target_os = "linux")]
+    fn allowed_cpus() -> String {
+        let status = std::fs::read_to_string("/proc/thread-self/status").unwrap();
+        let line = status
+            .lines()
+            .find(|line| line.starts_with("Cpus_allowed_list:"))
+            .unwrap();
+        line["Cpus_allowed_list:".len()..].trim().to_string()
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn pinned_workers_run_only_on_the_core_they_were_given() {
+        let pool = ThreadPool::new(2).unwrap();
+        let core = core_affinity::get_core_ids().unwrap().last().unwrap().id;
+        pool.pin_workers(&[core]);
+
+        let on_core = || {
+            pool.execute_with_result(allowed_cpus)
+                .unwrap()
+                .recv_timeout(TIMEOUT)
+                .unwrap()
+                == core.to_string()
+        };
+        // Idle workers pin themselves once they wake up to the change.
+        eventually("a worker to run on the pinned core", on_core);
+        for _ in 0..8 {
+            assert!(on_core());
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use std::io::{self, BufRead, ErrorKind, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str;
+
+// The longest header the PROXY protocol v1 specification allows, including
+// the trailing CRLF.
+const MAX_HEADER_LEN: u64 = 107;
+
+/// Reads a PROXY protocol v1 header such as
+/// `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n` from the start of
+/// `stream`, consuming exactly the header bytes.
+///
+/// Returns the address of the client the proxy accepted the connection
+/// from, or `None` when the proxy reports the connection as `UNKNOWN`.
+pub fn read_header<R: BufRead>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    let mut header = Vec::new();
+    Read::take(&mut *stream, MAX_HEADER_LEN).read_until(b'\n', &mut header)?;
+    parse_header(&header).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+}
+
+fn parse_header(header: &[u8]) -> Result<Option<SocketAddr>, String> {
+    let line = header
+        .strip_suffix(b"\r\n")
+        .ok_or_else(|| String::from("header is not terminated by CRLF"))?;
+    let line = str::from_utf8(line).map_err(|_| String::from("header is not ASCII"))?;
+
+    let mut fields = line.split(' ');
+    if fields.next() != Some("PROXY") {
+        return Err(format!("not a PROXY protocol header: {:?}", line));
+    }
+
+    let parse_ip: fn(&str) -> Option<IpAddr> = match fields.next() {
+        Some("TCP4") => |ip| ip.parse::<Ipv4Addr>().ok().map(IpAddr::V4),
+        Some("TCP6") => |ip| ip.parse::<Ipv6Addr>().ok().map(IpAddr::V6),
+        // The proxy could not tell where the connection came from, and the
+        // rest of the line carries no meaning.
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(format!("unsupported protocol in header {:?}", line)),
+    };
+
+    let (source, destination, source_port, destination_port) = match (
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+    ) {
+        (Some(source), Some(destination), Some(source_port), Some(destination_port), None) => {
+            (source, destination, source_port, destination_port)
+        }
+        _ => return Err(format!("wrong number of fields in header {:?}", line)),
+    };
+
+    match (
+        parse_ip(source),
+        parse_ip(destination),
+        source_port.parse::<u16>(),
+        destination_port.parse::<u16>(),
+    ) {
+        (Some(ip), Some(_), Ok(port), Ok(_)) => Ok(Some(SocketAddr::new(ip, port))),
+        _ => Err(format!("invalid addresses in header {:?}", line)),
+    }
+}
@@ -0,0 +1,154 @@
+use crate::connection::HalfClose;
+use log::warn;
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Received bytes a connection holds on to before appending them to the file.
+const BLOCK_SIZE: usize = 64 * 1024;
+
+/// Appends everything received on each connection to one file.
+///
+/// A connection's bytes are written in blocks, each starting with a line
+/// like `=== connection 3 from 127.0.0.1:51234 at 2024-05-01T12:00:00.000Z, 5 bytes ===`
+/// and followed by exactly that many bytes and a newline. A block is written
+/// once a connection has buffered 64 KiB and when it closes, in one write
+/// under a lock, so blocks of concurrent connections never interleave.
+pub struct Recorder {
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    pub fn open(path: &Path) -> io::Result<Recorder> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Recorder {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Starts recording connection `id`, from `peer`, that was accepted just
+    /// now. The id is the one its log lines carry, see [`crate::span`].
+    pub fn start(self: &Arc<Self>, id: u64, peer: impl Display) -> Recording {
+        Recording {
+            recorder: Arc::clone(self),
+            header: format!("connection {} from {} at {}", id, peer, timestamp()),
+            buffer: Vec::new(),
+        }
+    }
+
+    fn append(&self, header: &str, bytes: &[u8]) {
+        let mut block = format!("=== {}, {} bytes ===\n", header, bytes.len()).into_bytes();
+        block.extend_from_slice(bytes);
+        block.push(b'\n');
+
+        let mut file = self
+            .file
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Err(e) = file.write_all(&block) {
+            warn!("Could not record connection traffic due to: {:?}", e);
+        }
+    }
+}
+
+/// The bytes received on one connection that were not written out yet. They
+/// are written when it is dropped.
+pub struct Recording {
+    recorder: Arc<Recorder>,
+    header: String,
+    buffer: Vec<u8>,
+}
+
+impl Recording {
+    pub(crate) fn record(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+        if self.buffer.len() >= BLOCK_SIZE {
+            self.recorder.append(&self.header, &self.buffer);
+            self.buffer.clear();
+        }
+    }
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        self.recorder.append(&self.header, &self.buffer);
+    }
+}
+
+/// A stream whose received bytes are passed to a `Recording`, if it has one.
+pub struct Recorded<S> {
+    stream: S,
+    recording: Option<Recording>,
+}
+
+impl<S> Recorded<S> {
+    pub fn new(stream: S, recording: Option<Recording>) -> Recorded<S> {
+        Recorded { stream, recording }
+    }
+}
+
+impl<S: Read> Read for Recorded<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read_bytes = self.stream.read(buf)?;
+        if let Some(recording) = &mut self.recording {
+            recording.record(&buf[..read_bytes]);
+        }
+        Ok(read_bytes)
+    }
+}
+
+impl<S: Write> Write for Recorded<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<S: HalfClose> HalfClose for Recorded<S> {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.stream.shutdown_write()
+    }
+}
+
+// The current time in UTC as RFC 3339 with milliseconds.
+fn timestamp() -> String {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = ((secs / 86_400) as i64, secs % 86_400);
+
+    // Converts days since the epoch to a proleptic Gregorian date, from
+    // Howard Hinnant's `civil_from_days`.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
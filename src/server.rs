@@ -0,0 +1,1930 @@
+use crate::access::AccessList;
+use crate::broadcast::Room;
+use crate::connection::{configure_tcp_stream, handle, handle_duplex, ConnectionStats};
+use crate::control;
+use crate::datagram::handle_datagram;
+#[cfg(unix)]
+use crate::event_loop;
+use crate::events::{Callback, Events};
+use crate::gzip::GzipDirection;
+use crate::handler::HandlerFactory;
+use crate::http::HeadLimits;
+use crate::limit::{ConnectionLimit, ConnectionPermit, IpPermit, PerIpLimit, RateLimit};
+use crate::metrics;
+use crate::mirror::{Mirror, Mirrored};
+use crate::pool::{self, OverflowPolicy, ThreadPool};
+use crate::record::{Recorded, Recorder};
+use crate::shutdown::{Shutdown, ShutdownMode};
+use crate::socket::{self, Keepalive, ListenerOptions};
+use crate::span;
+use crate::stats::{Outcome, ServerStats};
+use crate::tls;
+use crate::transform::{self, Transform};
+use ipnet::IpNet;
+use log::{debug, error, info, warn};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::fmt::Display;
+#[cfg(unix)]
+use std::fs;
+use std::io::{self, ErrorKind, Write};
+use std::net::{self, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, UdpSocket};
+use std::num::NonZeroU8;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+pub const DEFAULT_PORT: u16 = 8080;
+// Every connection owns one buffer, so with all workers busy the server
+// holds roughly `buffer_size * pool size` bytes of echo buffers.
+pub const DEFAULT_BUFFER_SIZE: usize = 1024;
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_POOL_SIZE: usize = 8;
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1 << 20;
+pub const DEFAULT_MIN_THROUGHPUT_WINDOW: Duration = Duration::from_secs(10);
+// Healthy as long as no more connections wait for a worker than there are
+// workers, which a pool catching up after a burst clears quickly.
+pub const DEFAULT_HEALTH_MAX_PENDING_PER_WORKER: usize = 1;
+const BUSY_RESPONSE: &[u8] = b"server busy\r\n";
+
+/// Transport the server listens on when no Unix socket path is configured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Protocol, String> {
+        match s {
+            "tcp" => Ok(Protocol::Tcp),
+            "udp" => Ok(Protocol::Udp),
+            other => Err(format!(
+                "unsupported protocol {:?}, expected tcp or udp",
+                other
+            )),
+        }
+    }
+}
+
+/// How accepted TCP connections are served.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Runtime {
+    /// Serves each connection on a pool worker with blocking reads and
+    /// writes, so every connection being served occupies a thread.
+    Threads,
+    /// Multiplexes connections on one non-blocking event loop per accept
+    /// thread, so an open connection costs a socket and its pending echo
+    /// rather than a worker. Only raw mode over plain TCP is supported, and
+    /// only on Unix.
+    EventLoop,
+}
+
+impl FromStr for Runtime {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Runtime, String> {
+        match s {
+            "threads" => Ok(Runtime::Threads),
+            "event-loop" => Ok(Runtime::EventLoop),
+            other => Err(format!(
+                "unsupported runtime {:?}, expected threads or event-loop",
+                other
+            )),
+        }
+    }
+}
+
+/// How stream connections split incoming bytes before echoing them. UDP
+/// always echoes whole datagrams.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Echoes bytes as soon as they are read.
+    Raw,
+    /// Like `Raw`, but echoes every chunk read with its bytes in reverse
+    /// order, after the transform has run. To reverse line by line instead,
+    /// use `Line` with the `reverse` transform.
+    Reverse,
+    /// Like `Raw`, but follows every echoed chunk with the big-endian CRC32
+    /// of its bytes, so clients can check the data was not corrupted.
+    Checksum,
+    /// Echoes the base64 encoding of the received bytes, as one continuous
+    /// encoding per connection.
+    Base64Encode,
+    /// Decodes received base64 and echoes the bytes it stands for, closing
+    /// the connection on malformed input.
+    Base64Decode,
+    /// Buffers until a newline and echoes complete lines.
+    Line,
+    /// Like `Line`, but messages end with the configured delimiter byte and
+    /// one growing past the maximum frame size closes the connection.
+    Delimited,
+    /// Echoes frames made of a 4-byte big-endian length and its payload.
+    Framed,
+    /// Answers each HTTP/1.x request with the request itself as the body.
+    Http,
+    /// Completes a WebSocket handshake, then echoes every data frame.
+    WebSocket,
+    /// Compresses echoed chunks into one gzip stream, or decompresses gzip
+    /// input and echoes the plain bytes, depending on the gzip direction.
+    Gzip,
+    /// Reads and drops everything, writing nothing back, like the discard
+    /// protocol of RFC 863.
+    Discard,
+    /// Writes the rotating pattern of printable characters of RFC 864 until
+    /// the client goes away, without reading what it sends.
+    Chargen,
+    /// Sends what any connection sends to every connection currently open,
+    /// the sender included, through the `broadcast::Room` the server sets
+    /// up. Not supported over TLS. A connection served by `handle` outside
+    /// a server has no room and discards what it reads.
+    Broadcast,
+    /// Answers every chunk read, or only the first one of a connection, with
+    /// the configured reply instead of echoing it; the input is dropped.
+    Reply,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Mode, String> {
+        match s {
+            "raw" => Ok(Mode::Raw),
+            "reverse" => Ok(Mode::Reverse),
+            "checksum" => Ok(Mode::Checksum),
+            "base64-encode" => Ok(Mode::Base64Encode),
+            "base64-decode" => Ok(Mode::Base64Decode),
+            "line" => Ok(Mode::Line),
+            "delimited" => Ok(Mode::Delimited),
+            "framed" => Ok(Mode::Framed),
+            "http" => Ok(Mode::Http),
+            "websocket" => Ok(Mode::WebSocket),
+            "gzip" => Ok(Mode::Gzip),
+            "discard" => Ok(Mode::Discard),
+            "chargen" => Ok(Mode::Chargen),
+            "broadcast" => Ok(Mode::Broadcast),
+            "reply" => Ok(Mode::Reply),
+            other => Err(format!(
+                "unsupported mode {:?}, expected raw, reverse, checksum, base64-encode, base64-decode, line, delimited, framed, http, websocket, gzip, discard, chargen, broadcast or reply",
+                other
+            )),
+        }
+    }
+}
+
+/// Settings shared by the accept loop and every connection it serves.
+///
+/// Built with [`ServerConfig::builder`]; anything left unset keeps the
+/// defaults of the command line server.
+#[derive(Clone)]
+pub struct ServerConfig {
+    pub(crate) addrs: Vec<SocketAddr>,
+    pub(crate) protocol: Protocol,
+    pub(crate) unix: Option<PathBuf>,
+    pub(crate) listener: ListenerOptions,
+    pub(crate) tls: Option<TlsFiles>,
+    pub(crate) client_ca: Option<PathBuf>,
+    pub(crate) pool_size: usize,
+    pub(crate) accept_threads: usize,
+    pub(crate) runtime: Runtime,
+    pub(crate) min_workers: Option<usize>,
+    pub(crate) worker_keep_alive: Option<Duration>,
+    pub(crate) worker_cores: Option<Vec<usize>>,
+    pub(crate) worker_stack_size: Option<usize>,
+    pub(crate) queue_capacity: Option<usize>,
+    pub(crate) queue_overflow: OverflowPolicy,
+    pub(crate) queue_timeout: Option<Duration>,
+    pub(crate) log_tasks: bool,
+    pub(crate) shutdown_timeout: Option<Duration>,
+    pub(crate) shutdown_mode: ShutdownMode,
+    pub(crate) mode: Mode,
+    pub(crate) max_frame_size: usize,
+    pub(crate) http_limits: HeadLimits,
+    pub(crate) delimiter: u8,
+    pub(crate) gzip_direction: GzipDirection,
+    pub(crate) reply: Vec<u8>,
+    pub(crate) reply_once: bool,
+    pub(crate) proxy_protocol: bool,
+    pub(crate) banner: Option<String>,
+    pub(crate) buffer_size: usize,
+    pub(crate) read_timeout: Option<Duration>,
+    pub(crate) write_timeout: Option<Duration>,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) max_lifetime: Option<Duration>,
+    pub(crate) min_throughput: Option<u64>,
+    pub(crate) min_throughput_window: Duration,
+    pub(crate) nodelay: bool,
+    pub(crate) recv_buffer_size: Option<usize>,
+    pub(crate) send_buffer_size: Option<usize>,
+    pub(crate) ttl: Option<NonZeroU8>,
+    pub(crate) linger: Option<Duration>,
+    pub(crate) keepalive: Option<Keepalive>,
+    pub(crate) access: AccessList,
+    pub(crate) max_connections: Option<usize>,
+    pub(crate) busy_message: Option<String>,
+    pub(crate) max_connections_per_ip: Option<usize>,
+    pub(crate) max_connection_rate: u32,
+    pub(crate) max_connection_bytes: Option<u64>,
+    pub(crate) max_bytes_per_second: u64,
+    pub(crate) transform: Arc<Transform>,
+    pub(crate) handler: Option<Arc<HandlerFactory>>,
+    pub(crate) duplex: bool,
+    pub(crate) mirror: Option<SocketAddr>,
+    pub(crate) echo_delay: Duration,
+    pub(crate) echo_jitter: Option<(Duration, Duration)>,
+    pub(crate) drop_rate: f64,
+    pub(crate) events: Events,
+    pub(crate) record: Option<PathBuf>,
+    pub(crate) recorder: Option<Arc<Recorder>>,
+    pub(crate) room: Option<Arc<Room>>,
+    pub(crate) stats_interval: Option<Duration>,
+    pub(crate) control: Option<SocketAddr>,
+    pub(crate) metrics: Option<SocketAddr>,
+    pub(crate) health_max_pending_per_worker: usize,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct TlsFiles {
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+impl ServerConfig {
+    pub fn builder() -> ServerConfigBuilder {
+        ServerConfigBuilder {
+            config: ServerConfig {
+                addrs: vec![SocketAddr::new(Ipv4Addr::LOCALHOST.into(), DEFAULT_PORT)],
+                protocol: Protocol::Tcp,
+                unix: None,
+                listener: ListenerOptions::default(),
+                tls: None,
+                client_ca: None,
+                pool_size: DEFAULT_POOL_SIZE,
+                accept_threads: 1,
+                runtime: Runtime::Threads,
+                min_workers: None,
+                worker_keep_alive: None,
+                worker_cores: None,
+                worker_stack_size: None,
+                queue_capacity: None,
+                queue_overflow: OverflowPolicy::Reject,
+                queue_timeout: None,
+                log_tasks: false,
+                shutdown_timeout: None,
+                shutdown_mode: ShutdownMode::Drain,
+                mode: Mode::Raw,
+                max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+                http_limits: HeadLimits::default(),
+                delimiter: 0,
+                gzip_direction: GzipDirection::Compress,
+                reply: Vec::new(),
+                reply_once: false,
+                proxy_protocol: false,
+                banner: None,
+                buffer_size: DEFAULT_BUFFER_SIZE,
+                read_timeout: Some(DEFAULT_READ_TIMEOUT),
+                write_timeout: Some(DEFAULT_WRITE_TIMEOUT),
+                idle_timeout: None,
+                max_lifetime: None,
+                min_throughput: None,
+                min_throughput_window: DEFAULT_MIN_THROUGHPUT_WINDOW,
+                nodelay: true,
+                recv_buffer_size: None,
+                send_buffer_size: None,
+                ttl: None,
+                linger: None,
+                keepalive: None,
+                access: AccessList::default(),
+                max_connections: None,
+                busy_message: None,
+                max_connections_per_ip: None,
+                max_connection_rate: 0,
+                max_connection_bytes: None,
+                max_bytes_per_second: 0,
+                transform: Arc::new(transform::identity),
+                handler: None,
+                duplex: false,
+                mirror: None,
+                echo_delay: Duration::ZERO,
+                echo_jitter: None,
+                drop_rate: 0.0,
+                events: Events::default(),
+                record: None,
+                recorder: None,
+                room: None,
+                stats_interval: None,
+                control: None,
+                metrics: None,
+                health_max_pending_per_worker: DEFAULT_HEALTH_MAX_PENDING_PER_WORKER,
+            },
+        }
+    }
+
+    /// Addresses the server listens on.
+    pub fn addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    pub fn pool_size(&self) -> usize {
+        self.pool_size
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout
+    }
+
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.write_timeout
+    }
+
+    // The read timeout applied to accepted sockets, shortened to the idle
+    // timeout and the maximum lifetime so a silent client is noticed without
+    // waiting out the full read timeout.
+    fn socket_read_timeout(&self) -> Option<Duration> {
+        [self.read_timeout, self.idle_timeout, self.max_lifetime]
+            .iter()
+            .flatten()
+            .min()
+            .copied()
+    }
+}
+
+/// Builder for [`ServerConfig`].
+pub struct ServerConfigBuilder {
+    config: ServerConfig,
+}
+
+impl ServerConfigBuilder {
+    /// Listens on `addr` only, replacing any previously set addresses.
+    pub fn addr(mut self, addr: SocketAddr) -> ServerConfigBuilder {
+        self.config.addrs = vec![addr];
+        self
+    }
+
+    /// Listens on every address in `addrs`, each with its own accept loop
+    /// feeding the same thread pool. UDP only binds the first address.
+    pub fn addrs(mut self, addrs: Vec<SocketAddr>) -> ServerConfigBuilder {
+        self.config.addrs = addrs;
+        self
+    }
+
+    pub fn protocol(mut self, protocol: Protocol) -> ServerConfigBuilder {
+        self.config.protocol = protocol;
+        self
+    }
+
+    /// Listens on a Unix domain socket at `path` instead of `addr`. Unix
+    /// sockets are always served as plain streams, without TLS.
+    pub fn unix(mut self, path: PathBuf) -> ServerConfigBuilder {
+        self.config.unix = Some(path);
+        self
+    }
+
+    pub fn listener(mut self, listener: ListenerOptions) -> ServerConfigBuilder {
+        self.config.listener = listener;
+        self
+    }
+
+    /// Serves TCP connections over TLS using a PEM certificate chain and a
+    /// PEM private key, loaded when the server starts.
+    pub fn tls(mut self, cert: PathBuf, key: PathBuf) -> ServerConfigBuilder {
+        self.config.tls = Some(TlsFiles { cert, key });
+        self
+    }
+
+    /// Requires TLS clients to present a certificate issued by one of the
+    /// PEM CA certificates in `path`, failing the handshake of clients that
+    /// present none or an untrusted one. Has no effect without `tls`.
+    pub fn client_ca(mut self, path: PathBuf) -> ServerConfigBuilder {
+        self.config.client_ca = Some(path);
+        self
+    }
+
+    pub fn pool_size(mut self, pool_size: usize) -> ServerConfigBuilder {
+        self.config.pool_size = pool_size;
+        self
+    }
+
+    /// Keeps the pool from shrinking below `min_workers`, whether through a
+    /// `resize` or through idle workers exiting; see
+    /// `ThreadPool::set_keep_alive`. Defaults to the pool size when only a
+    /// keep-alive is set.
+    pub fn min_workers(mut self, min_workers: usize) -> ServerConfigBuilder {
+        self.config.min_workers = Some(min_workers);
+        self
+    }
+
+    /// Lets workers beyond the minimum exit once they have gone `keep_alive`
+    /// without a connection to serve, so a pool grown at runtime shrinks
+    /// back on its own. Idle workers are kept when unset.
+    pub fn worker_keep_alive(mut self, keep_alive: Option<Duration>) -> ServerConfigBuilder {
+        self.config.worker_keep_alive = keep_alive;
+        self
+    }
+
+    /// Runs `threads` accept loops on every TCP listener, each on its own
+    /// clone of the listening socket, so accepting and setting up
+    /// connections keeps up with heavy connection churn. One by default.
+    ///
+    /// The clones share the listener's single accept queue in the kernel,
+    /// so each connection is handed to exactly one loop: whichever polls the
+    /// queue first, which spreads connections over the loops that are not
+    /// busy setting one up. Unlike `SO_REUSEPORT` this does not split the
+    /// queue itself. Every loop stops once shutdown is requested.
+    pub fn accept_threads(mut self, threads: usize) -> ServerConfigBuilder {
+        self.config.accept_threads = threads;
+        self
+    }
+
+    /// Selects how TCP connections are served, on the thread pool by
+    /// default. With `Runtime::EventLoop` every accept thread runs its own
+    /// event loop and the pool stays idle; binding fails if the
+    /// configuration needs anything only the pool supports, such as TLS or
+    /// a mode other than raw.
+    pub fn runtime(mut self, runtime: Runtime) -> ServerConfigBuilder {
+        self.config.runtime = runtime;
+        self
+    }
+
+    /// Pins each pool worker to one of `cores`, or of all available cores
+    /// if empty; see `ThreadPool::pin_workers`.
+    pub fn pin_workers(mut self, cores: Vec<usize>) -> ServerConfigBuilder {
+        self.config.worker_cores = Some(cores);
+        self
+    }
+
+    /// Runs pool workers on stacks of `bytes`, for transforms that need more
+    /// than the platform default or pools large enough that a smaller one
+    /// saves memory; see `ThreadPool::with_stack_size`. Panics below
+    /// `pool::MIN_STACK_SIZE`.
+    pub fn worker_stack_size(mut self, bytes: usize) -> ServerConfigBuilder {
+        assert!(bytes >= pool::MIN_STACK_SIZE);
+        self.config.worker_stack_size = Some(bytes);
+        self
+    }
+
+    /// Bounds the number of accepted connections waiting for a worker; the
+    /// queue is unbounded when unset.
+    pub fn queue_capacity(mut self, capacity: usize) -> ServerConfigBuilder {
+        self.config.queue_capacity = Some(capacity);
+        self
+    }
+
+    /// What happens to a connection accepted while the bounded queue is
+    /// full; see `OverflowPolicy`. By default it is turned away with a busy
+    /// response. With `OverflowPolicy::Block` the accept thread waits for a
+    /// slot, so a shutdown request is only noticed once one frees up.
+    pub fn queue_overflow(mut self, policy: OverflowPolicy) -> ServerConfigBuilder {
+        self.config.queue_overflow = policy;
+        self
+    }
+
+    /// Closes connections that waited longer than `timeout` for a worker
+    /// without serving them, since their clients have likely given up by
+    /// then; see `ThreadPool::set_queue_timeout`. Unset by default.
+    pub fn queue_timeout(mut self, timeout: Option<Duration>) -> ServerConfigBuilder {
+        self.config.queue_timeout = timeout;
+        self
+    }
+
+    /// Logs a line for every connection a worker starts serving; see
+    /// `ThreadPool::set_task_logging`. Off by default.
+    pub fn log_tasks(mut self, enabled: bool) -> ServerConfigBuilder {
+        self.config.log_tasks = enabled;
+        self
+    }
+
+    /// Bounds how long shutting down waits for in-flight connections once
+    /// accepting has stopped; see `ThreadPool::set_shutdown_timeout`. Waits
+    /// for every connection when unset.
+    ///
+    /// In drain mode the connections still open when the timeout runs out
+    /// are closed, and their handlers get the same timeout again to notice.
+    pub fn shutdown_timeout(mut self, timeout: Option<Duration>) -> ServerConfigBuilder {
+        self.config.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Whether shutting down lets open connections finish or closes them
+    /// right away; `ShutdownMode::Drain` by default.
+    pub fn shutdown_mode(mut self, mode: ShutdownMode) -> ServerConfigBuilder {
+        self.config.shutdown_mode = mode;
+        self
+    }
+
+    pub fn mode(mut self, mode: Mode) -> ServerConfigBuilder {
+        self.config.mode = mode;
+        self
+    }
+
+    /// Closes connections in framed mode that announce a frame with a
+    /// payload larger than `size` bytes. The same limit applies to HTTP
+    /// request bodies in http mode, WebSocket frames in websocket mode and
+    /// messages in delimited mode.
+    pub fn max_frame_size(mut self, size: usize) -> ServerConfigBuilder {
+        self.config.max_frame_size = size;
+        self
+    }
+
+    /// Answers requests in http mode whose request line and headers take
+    /// more than `size` bytes with a `431`, 8 KiB by default. Also applies
+    /// to the handshake in websocket mode.
+    pub fn max_header_size(mut self, size: usize) -> ServerConfigBuilder {
+        assert!(size > 0);
+        self.config.http_limits.max_len = size;
+        self
+    }
+
+    /// Answers requests in http mode with more than `count` header fields
+    /// with a `431`, 100 by default. Also applies to the handshake in
+    /// websocket mode.
+    pub fn max_headers(mut self, count: usize) -> ServerConfigBuilder {
+        self.config.http_limits.max_headers = count;
+        self
+    }
+
+    /// Byte that ends each message in delimited mode; NUL by default.
+    pub fn delimiter(mut self, delimiter: u8) -> ServerConfigBuilder {
+        self.config.delimiter = delimiter;
+        self
+    }
+
+    /// Bytes reply mode answers with, written as they are without passing
+    /// through the transform; empty by default, which answers nothing.
+    pub fn reply(mut self, reply: Vec<u8>) -> ServerConfigBuilder {
+        self.config.reply = reply;
+        self
+    }
+
+    /// Makes reply mode answer only the first chunk of every connection
+    /// rather than each of them, like a greeting in response to the first
+    /// request.
+    pub fn reply_once(mut self, once: bool) -> ServerConfigBuilder {
+        self.config.reply_once = once;
+        self
+    }
+
+    /// Whether gzip mode compresses echoed bytes or decompresses gzip
+    /// input; compresses by default.
+    pub fn gzip_direction(mut self, direction: GzipDirection) -> ServerConfigBuilder {
+        self.config.gzip_direction = direction;
+        self
+    }
+
+    /// Expects every stream connection to start with a PROXY protocol v1
+    /// header, which is logged and not echoed.
+    pub fn proxy_protocol(mut self, enabled: bool) -> ServerConfigBuilder {
+        self.config.proxy_protocol = enabled;
+        self
+    }
+
+    /// Greets every stream connection with `banner` and a CRLF before
+    /// echoing anything; an empty banner sends nothing.
+    pub fn banner(mut self, banner: String) -> ServerConfigBuilder {
+        self.config.banner = Some(banner).filter(|banner| !banner.is_empty());
+        self
+    }
+
+    pub fn buffer_size(mut self, buffer_size: usize) -> ServerConfigBuilder {
+        self.config.buffer_size = buffer_size;
+        self
+    }
+
+    /// `None` lets a connection wait for data forever.
+    pub fn read_timeout(mut self, timeout: Option<Duration>) -> ServerConfigBuilder {
+        self.config.read_timeout = timeout;
+        self
+    }
+
+    /// `None` lets a connection wait forever for the client to read.
+    pub fn write_timeout(mut self, timeout: Option<Duration>) -> ServerConfigBuilder {
+        self.config.write_timeout = timeout;
+        self
+    }
+
+    /// Closes a connection once `timeout` passes without a complete read,
+    /// even if bytes keep trickling in often enough to satisfy the read
+    /// timeout. `None`, the default, disables it.
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> ServerConfigBuilder {
+        self.config.idle_timeout = timeout;
+        self
+    }
+
+    /// Closes a stream connection once it has been served for `lifetime`,
+    /// however active it is, after the echo in progress. The lifetime counts
+    /// from when a worker starts serving the connection. `None`, the
+    /// default, lets connections live as long as they stay open.
+    pub fn max_lifetime(mut self, lifetime: Option<Duration>) -> ServerConfigBuilder {
+        self.config.max_lifetime = lifetime;
+        self
+    }
+
+    /// Closes a stream connection that echoes fewer than `bytes` within a
+    /// window of `min_throughput_window`, 10 seconds by default. Unlike the
+    /// idle timeout this also catches clients that keep a worker busy by
+    /// trickling in a byte now and then. The rate is checked whenever a
+    /// read returns, so a client sending nothing at all is left to the idle
+    /// and read timeouts.
+    pub fn min_throughput(mut self, bytes: u64) -> ServerConfigBuilder {
+        self.config.min_throughput = Some(bytes);
+        self
+    }
+
+    pub fn min_throughput_window(mut self, window: Duration) -> ServerConfigBuilder {
+        self.config.min_throughput_window = window;
+        self
+    }
+
+    pub fn nodelay(mut self, nodelay: bool) -> ServerConfigBuilder {
+        self.config.nodelay = nodelay;
+        self
+    }
+
+    /// Admits TCP peers in `net`. Once any range is allowed, peers outside
+    /// all allowed ranges are refused.
+    pub fn allow(mut self, net: IpNet) -> ServerConfigBuilder {
+        self.config.access.allow(net);
+        self
+    }
+
+    /// Refuses TCP peers in `net`, even if they are also allowed.
+    pub fn deny(mut self, net: IpNet) -> ServerConfigBuilder {
+        self.config.access.deny(net);
+        self
+    }
+
+    /// Requests an `SO_RCVBUF` of `size` bytes on accepted TCP connections.
+    pub fn recv_buffer_size(mut self, size: usize) -> ServerConfigBuilder {
+        self.config.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Requests an `SO_SNDBUF` of `size` bytes on accepted TCP connections.
+    pub fn send_buffer_size(mut self, size: usize) -> ServerConfigBuilder {
+        self.config.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets the TTL, or IPv6 hop limit, of packets sent on accepted TCP
+    /// connections.
+    pub fn ttl(mut self, ttl: NonZeroU8) -> ServerConfigBuilder {
+        self.config.ttl = Some(ttl);
+        self
+    }
+
+    /// Sets `SO_LINGER` on accepted TCP connections; see
+    /// `socket::set_linger`. A zero `linger` makes closing reset the
+    /// connection, which is handy for testing how clients cope with one;
+    /// connections that end cleanly still shut down their write side first,
+    /// so the peer reads end of stream before the reset. Unset, closing
+    /// returns right away and the system sends what is left in the
+    /// background.
+    pub fn linger(mut self, linger: Duration) -> ServerConfigBuilder {
+        self.config.linger = Some(linger);
+        self
+    }
+
+    /// Probes accepted TCP connections that have gone idle and resets those
+    /// whose peer stopped answering, so a half-open connection does not
+    /// hold a worker until the idle timeout; see `socket::set_keepalive`.
+    /// Off by default.
+    pub fn keepalive(mut self, keepalive: Keepalive) -> ServerConfigBuilder {
+        self.config.keepalive = Some(keepalive);
+        self
+    }
+
+    pub fn max_connections(mut self, max: usize) -> ServerConfigBuilder {
+        self.config.max_connections = Some(max);
+        self
+    }
+
+    /// Writes `message` and a CRLF to connections turned away because
+    /// `max_connections` are already active, before closing them, so
+    /// clients can tell a full server from a broken one. An empty message,
+    /// the default, closes them without a word. Not sent over TLS, whose
+    /// clients expect a handshake instead.
+    pub fn busy_message(mut self, message: String) -> ServerConfigBuilder {
+        self.config.busy_message = Some(message).filter(|message| !message.is_empty());
+        self
+    }
+
+    /// Caps how many TCP connections a single source IP may have open at the
+    /// same time; further ones are closed as soon as they are accepted.
+    pub fn max_connections_per_ip(mut self, max: usize) -> ServerConfigBuilder {
+        self.config.max_connections_per_ip = Some(max);
+        self
+    }
+
+    /// Caps accepted connections per second; zero means unlimited.
+    pub fn max_connection_rate(mut self, per_second: u32) -> ServerConfigBuilder {
+        self.config.max_connection_rate = per_second;
+        self
+    }
+
+    /// Closes a connection once it has echoed `max` bytes in total. Frames
+    /// are never split, so in framed mode the last frame may cross the limit.
+    pub fn max_connection_bytes(mut self, max: u64) -> ServerConfigBuilder {
+        self.config.max_connection_bytes = Some(max);
+        self
+    }
+
+    /// Caps the echo rate of each connection on its own, by sleeping after
+    /// an echo that got ahead of the rate; zero means unlimited.
+    pub fn max_bytes_per_second(mut self, max: u64) -> ServerConfigBuilder {
+        self.config.max_bytes_per_second = max;
+        self
+    }
+
+    pub fn transform(mut self, transform: Arc<Transform>) -> ServerConfigBuilder {
+        self.config.transform = transform;
+        self
+    }
+
+    /// Serves every connection with an `EchoHandler` of its own made by
+    /// `factory`, in place of raw mode's echo and the transform. Binding
+    /// fails unless the mode is raw, over TCP or a Unix socket, on the
+    /// thread pool runtime.
+    pub fn handler(mut self, factory: Arc<HandlerFactory>) -> ServerConfigBuilder {
+        self.config.handler = Some(factory);
+        self
+    }
+
+    /// Gives every connection a writer thread of its own, so reading and
+    /// echoing no longer take turns and a client that sends and receives
+    /// at the same time is served at full rate in both directions; see
+    /// `handle_duplex`. That costs a thread per connection on top of the
+    /// worker serving it. Binding fails unless the mode is raw, without a
+    /// custom handler, over plain TCP or a Unix socket, on the thread pool
+    /// runtime.
+    pub fn duplex(mut self, duplex: bool) -> ServerConfigBuilder {
+        self.config.duplex = duplex;
+        self
+    }
+
+    /// Copies everything stream connections receive to `upstream`, over one
+    /// connection to it per client, while still echoing to the client; see
+    /// `Mirror`. That costs a thread per connection. The bytes are copied as
+    /// received, before any transform, and after TLS is decrypted.
+    pub fn mirror(mut self, upstream: SocketAddr) -> ServerConfigBuilder {
+        self.config.mirror = Some(upstream);
+        self
+    }
+
+    /// Calls `callback` for every TCP connection that passes the access list
+    /// and the connection limits, before it is queued for a worker.
+    pub fn on_accept(mut self, callback: Arc<Callback>) -> ServerConfigBuilder {
+        self.config.events.on_accept = Some(callback);
+        self
+    }
+
+    /// Calls `callback` once an accepted TCP connection is closed, however
+    /// it ended, including when a full queue turned it away.
+    pub fn on_close(mut self, callback: Arc<Callback>) -> ServerConfigBuilder {
+        self.config.events.on_close = Some(callback);
+        self
+    }
+
+    /// Calls `callback`, ahead of `on_close`, when an accepted TCP connection
+    /// ends because of an error, a timeout or a failed TLS handshake rather
+    /// than the client closing it.
+    pub fn on_error(mut self, callback: Arc<Callback>) -> ServerConfigBuilder {
+        self.config.events.on_error = Some(callback);
+        self
+    }
+
+    /// Holds back every echo by `delay` to emulate a slow server.
+    ///
+    /// The delay is a `thread::sleep` on the worker serving the connection,
+    /// and a worker stays with its connection until it closes, so delayed
+    /// connections take longer to release their workers. Keep the pool at
+    /// least as large as the number of clients expected at once, or later
+    /// connections will wait in the queue on top of the delay.
+    pub fn echo_delay(mut self, delay: Duration) -> ServerConfigBuilder {
+        self.config.echo_delay = delay;
+        self
+    }
+
+    /// Holds back every echo by a random duration between `min` and `max`
+    /// on top of the echo delay, to emulate a network with varying latency.
+    /// It ties up workers just like the echo delay does. `min` must not
+    /// exceed `max`.
+    pub fn echo_jitter(mut self, min: Duration, max: Duration) -> ServerConfigBuilder {
+        assert!(min <= max);
+        self.config.echo_jitter = Some((min, max));
+        self
+    }
+
+    /// Reads but silently drops a random `rate` of the chunks received in
+    /// raw mode instead of echoing them, between 0, the default, and 1, to
+    /// test how clients cope with lost data. Dropped bytes are counted in
+    /// `ConnectionStats::bytes_dropped` rather than as echoed. Binding fails
+    /// with a drop rate in any other mode.
+    pub fn drop_rate(mut self, rate: f64) -> ServerConfigBuilder {
+        assert!((0.0..=1.0).contains(&rate));
+        self.config.drop_rate = rate;
+        self
+    }
+
+    /// Appends everything received on stream connections to the file at
+    /// `path`, as described for [`Recorder`], while still echoing it.
+    pub fn record(mut self, path: PathBuf) -> ServerConfigBuilder {
+        self.config.record = Some(path);
+        self
+    }
+
+    /// Logs the aggregate `ServerStats` every `interval`; `None` disables the
+    /// report.
+    pub fn stats_interval(mut self, interval: Option<Duration>) -> ServerConfigBuilder {
+        self.config.stats_interval = interval;
+        self
+    }
+
+    /// Accepts runtime commands such as `stats`, `resize` and `shutdown` on
+    /// a separate TCP listener at `addr`. See [`control::serve_control`].
+    pub fn control(mut self, addr: SocketAddr) -> ServerConfigBuilder {
+        self.config.control = Some(addr);
+        self
+    }
+
+    /// Serves Prometheus metrics over HTTP at `addr`. See
+    /// [`metrics::serve_metrics`].
+    pub fn metrics(mut self, addr: SocketAddr) -> ServerConfigBuilder {
+        self.config.metrics = Some(addr);
+        self
+    }
+
+    /// Reports the server as unhealthy on `/healthz` once more than `max`
+    /// tasks per worker are queued.
+    pub fn health_max_pending_per_worker(mut self, max: usize) -> ServerConfigBuilder {
+        self.config.health_max_pending_per_worker = max;
+        self
+    }
+
+    pub fn build(self) -> ServerConfig {
+        self.config
+    }
+}
+
+/// Binds the listeners described by `config` and serves them; see
+/// `Server::run`.
+pub fn run(config: ServerConfig) -> io::Result<()> {
+    Server::bind(config)?.run()
+}
+
+/// A server whose listeners are bound but not yet accepting connections.
+///
+/// Binding separately from serving lets an embedder listen on port 0 and
+/// look up the port the OS picked before any client needs it.
+pub struct Server {
+    config: Arc<ServerConfig>,
+    listeners: Listeners,
+    stats: Arc<ServerStats>,
+    shutdown: Arc<Shutdown>,
+}
+
+enum Listeners {
+    Tcp(Vec<(TcpListener, SocketAddr)>),
+    Udp(UdpSocket, SocketAddr),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Server {
+    /// Binds every address in `config`, or its Unix socket path.
+    pub fn bind(mut config: ServerConfig) -> io::Result<Server> {
+        if config.runtime == Runtime::EventLoop {
+            #[cfg(unix)]
+            event_loop::check(&config)?;
+            #[cfg(not(unix))]
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "the event loop runtime is only supported on Unix",
+            ));
+        }
+
+        if config.handler.is_some()
+            && (config.mode != Mode::Raw || config.protocol == Protocol::Udp)
+        {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "a custom echo handler requires raw mode over TCP or a Unix socket",
+            ));
+        }
+
+        if config.drop_rate > 0.0 && (config.mode != Mode::Raw || config.protocol == Protocol::Udp)
+        {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "a drop rate requires raw mode over TCP or a Unix socket",
+            ));
+        }
+
+        if config.duplex
+            && (config.mode != Mode::Raw
+                || config.handler.is_some()
+                || config.tls.is_some()
+                || config.protocol == Protocol::Udp)
+        {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "duplex connections require raw mode without a custom echo handler, over plain TCP or a Unix socket",
+            ));
+        }
+
+        if let Some(path) = &config.record {
+            let recorder = Recorder::open(path).map_err(|e| {
+                context(e, format!("Could not open record file {}", path.display()))
+            })?;
+            config.recorder = Some(Arc::new(recorder));
+        }
+
+        if config.mode == Mode::Broadcast {
+            // Broadcasts are written to a clone of the plain socket.
+            if config.tls.is_some() {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "broadcast mode is not supported over TLS",
+                ));
+            }
+            config.room = Some(Arc::new(Room::default()));
+        }
+
+        let listeners = match (&config.unix, config.protocol) {
+            (Some(path), _) => bind_unix(path)?,
+            (None, Protocol::Tcp) => Listeners::Tcp(bind_tcp_listeners(&config)?),
+            (None, Protocol::Udp) => bind_udp(&config)?,
+        };
+
+        Ok(Server {
+            config: Arc::new(config),
+            listeners,
+            stats: Arc::new(ServerStats::default()),
+            shutdown: Arc::new(Shutdown::default()),
+        })
+    }
+
+    /// Counters of the connections served so far, shared with the running
+    /// server so they can be read while `run` blocks on another thread.
+    pub fn stats(&self) -> Arc<ServerStats> {
+        Arc::clone(&self.stats)
+    }
+
+    /// Lets another thread stop `run` the way a signal would, with
+    /// `Shutdown::request`.
+    pub fn shutdown(&self) -> Arc<Shutdown> {
+        Arc::clone(&self.shutdown)
+    }
+
+    /// Address the server is bound to, with the port the OS assigned when
+    /// port 0 was configured. With several TCP listeners this is the first
+    /// one that could be bound. Fails for a server on a Unix socket.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match &self.listeners {
+            Listeners::Tcp(listeners) => listeners
+                .first()
+                .map(|(_, local_addr)| *local_addr)
+                .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "no TCP listener is bound")),
+            Listeners::Udp(_, local_addr) => Ok(*local_addr),
+            #[cfg(unix)]
+            Listeners::Unix(..) => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "a Unix socket has no socket address",
+            )),
+        }
+    }
+
+    /// Every address the server is bound to, in the order they were
+    /// configured, leaving out TCP addresses that could not be bound. Empty
+    /// for a server on a Unix socket.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        match &self.listeners {
+            Listeners::Tcp(listeners) => listeners
+                .iter()
+                .map(|(_, local_addr)| *local_addr)
+                .collect(),
+            Listeners::Udp(_, local_addr) => vec![*local_addr],
+            #[cfg(unix)]
+            Listeners::Unix(..) => Vec::new(),
+        }
+    }
+
+    /// Serves connections until SIGINT or SIGTERM is received or a
+    /// `shutdown` control command arrives, then waits for in-flight
+    /// connections to finish.
+    pub fn run(self) -> io::Result<()> {
+        let Server {
+            config,
+            listeners,
+            stats,
+            shutdown,
+        } = self;
+        let mut thread_pool = match (config.worker_stack_size, config.queue_capacity) {
+            (Some(stack_size), capacity) => {
+                ThreadPool::with_stack_size(config.pool_size, capacity, stack_size)
+            }
+            (None, Some(capacity)) => ThreadPool::with_capacity(config.pool_size, capacity),
+            (None, None) => ThreadPool::new(config.pool_size),
+        }
+        .map_err(|e| context(e, String::from("Could not start thread pool")))?;
+        thread_pool.set_shutdown_timeout(config.shutdown_timeout);
+        thread_pool.set_overflow_policy(config.queue_overflow);
+        thread_pool.set_queue_timeout(config.queue_timeout);
+        thread_pool.set_task_logging(config.log_tasks);
+        if config.min_workers.is_some() || config.worker_keep_alive.is_some() {
+            let min_workers = config.min_workers.unwrap_or(config.pool_size);
+            thread_pool.set_keep_alive(min_workers, config.worker_keep_alive);
+        }
+        if let Some(cores) = &config.worker_cores {
+            thread_pool.pin_workers(cores);
+        }
+        spawn_signal_handler(Arc::clone(&shutdown))?;
+
+        let control_listener = match config.control {
+            Some(addr) => Some(bind_auxiliary("control", addr)?),
+            None => None,
+        };
+        let metrics_listener = match config.metrics {
+            Some(addr) => Some(bind_auxiliary("metrics", addr)?),
+            None => None,
+        };
+
+        thread::scope(|scope| {
+            // Dropping the sender once serving is over stops the reporter.
+            let (stop_reporter, stopped) = mpsc::channel::<()>();
+            if let Some(interval) = config.stats_interval {
+                let (stats, thread_pool) = (&stats, &thread_pool);
+                scope.spawn(move || report_stats(interval, stats, thread_pool, &stopped));
+            }
+
+            if let Some(listener) = control_listener {
+                let (stats, thread_pool, shutdown) = (&stats, &thread_pool, &shutdown);
+                let read_timeout = config.read_timeout;
+                scope.spawn(move || {
+                    control::serve_control(listener, thread_pool, stats, shutdown, read_timeout)
+                });
+            }
+
+            if let Some(listener) = metrics_listener {
+                let (stats, thread_pool, shutdown) = (&stats, &thread_pool, &shutdown);
+                let read_timeout = config.read_timeout;
+                let max_pending = config.health_max_pending_per_worker;
+                scope.spawn(move || {
+                    metrics::serve_metrics(
+                        listener,
+                        thread_pool,
+                        stats,
+                        shutdown,
+                        read_timeout,
+                        max_pending,
+                    )
+                });
+            }
+
+            let result = match listeners {
+                Listeners::Tcp(listeners) => {
+                    serve_tcp(listeners, &config, &thread_pool, &shutdown, &stats)
+                }
+                Listeners::Udp(socket, local_addr) => {
+                    serve_udp(socket, local_addr, &config, &thread_pool, &shutdown, &stats)
+                }
+                #[cfg(unix)]
+                Listeners::Unix(listener, path) => {
+                    serve_unix(listener, &path, &config, &thread_pool, &shutdown, &stats)
+                }
+            };
+            drop(stop_reporter);
+            // Serving may also end because loading TLS failed, in which case
+            // the control and metrics loops still have to be stopped before
+            // the scope can end.
+            shutdown.request();
+            result
+        })?;
+
+        let in_flight = shutdown.open_connections();
+        let still_open = match config.shutdown_mode {
+            ShutdownMode::Drain => {
+                info!(
+                    "Stopped accepting connections, waiting for {} in-flight connection(s) to finish",
+                    in_flight
+                );
+                shutdown.wait_for_connections(config.shutdown_timeout)
+            }
+            ShutdownMode::Immediate => {
+                info!(
+                    "Stopped accepting connections, closing {} in-flight connection(s)",
+                    in_flight
+                );
+                in_flight
+            }
+        };
+        let force_closed = if still_open > 0 {
+            shutdown.close_connections()
+        } else {
+            0
+        };
+        info!(
+            "Drained {} connection(s), force-closed {}",
+            in_flight.saturating_sub(force_closed),
+            force_closed
+        );
+
+        if let Err(panics) = thread_pool.shutdown() {
+            error!("{} worker(s) panicked during shutdown", panics.len());
+        }
+        Ok(())
+    }
+}
+
+// Binds the listener of a side service like the control socket, which
+// polls for shutdown between connections like the main listeners.
+fn bind_auxiliary(name: &str, addr: SocketAddr) -> io::Result<TcpListener> {
+    let listener = TcpListener::bind(addr)
+        .and_then(|listener| {
+            listener.set_nonblocking(true)?;
+            Ok(listener)
+        })
+        .map_err(|e| context(e, format!("Could not bind {} socket to {}", name, addr)))?;
+    let local_addr = listener
+        .local_addr()
+        .map_err(|e| context(e, String::from("Could not resolve bound address")))?;
+    info!("Serving {} on: {}", name, local_addr);
+    Ok(listener)
+}
+
+fn report_stats(
+    interval: Duration,
+    stats: &ServerStats,
+    thread_pool: &ThreadPool,
+    stopped: &mpsc::Receiver<()>,
+) {
+    while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+        info!(
+            "Stats: {} connections accepted, {} active, {} closed, {} disconnected, {} failed, {} bytes echoed, {} tasks pending",
+            stats.accepted(),
+            stats.active(),
+            stats.closed(),
+            stats.disconnects(),
+            stats.errors(),
+            stats.bytes_echoed(),
+            thread_pool.pending_tasks()
+        );
+    }
+}
+
+fn serve_tcp(
+    listeners: Vec<(TcpListener, SocketAddr)>,
+    config: &Arc<ServerConfig>,
+    thread_pool: &ThreadPool,
+    shutdown: &Arc<Shutdown>,
+    stats: &Arc<ServerStats>,
+) -> io::Result<()> {
+    for (listener, local_addr) in &listeners {
+        listener
+            .set_nonblocking(true)
+            .map_err(|e| context(e, format!("Could not configure listener on {}", local_addr)))?;
+    }
+
+    let tls = match &config.tls {
+        Some(tls) => Some(
+            tls::load_server_config(&tls.cert, &tls.key, config.client_ca.as_deref())
+                .map_err(|e| context(e, String::from("Could not load TLS configuration")))?,
+        ),
+        None => None,
+    };
+
+    let mut accept_loops = Vec::new();
+    for (listener, local_addr) in listeners {
+        for _ in 1..config.accept_threads {
+            let clone = listener.try_clone().map_err(|e| {
+                context(
+                    e,
+                    format!("Could not start accept thread on {}", local_addr),
+                )
+            })?;
+            accept_loops.push(clone);
+        }
+        accept_loops.push(listener);
+    }
+
+    let admission = Admission::new(config);
+    #[cfg(unix)]
+    if config.runtime == Runtime::EventLoop {
+        return event_loop::serve(accept_loops, config, shutdown, stats, admission)
+            .map_err(|e| context(e, String::from("Could not start event loop")));
+    }
+    thread::scope(|scope| {
+        for listener in accept_loops {
+            let (tls, admission) = (&tls, &admission);
+            scope.spawn(move || {
+                accept_tcp(
+                    listener,
+                    config,
+                    thread_pool,
+                    shutdown,
+                    stats,
+                    tls,
+                    admission,
+                )
+            });
+        }
+    });
+    Ok(())
+}
+
+// Binds every configured address. An address that cannot be bound is
+// reported and skipped as long as at least one other listener comes up.
+fn bind_tcp_listeners(config: &ServerConfig) -> io::Result<Vec<(TcpListener, SocketAddr)>> {
+    let mut listeners = Vec::new();
+    let mut last_error = None;
+
+    for &addr in &config.addrs {
+        let bound = socket::bind_tcp_listener(addr, &config.listener).and_then(|listener| {
+            let local_addr = listener.local_addr()?;
+            Ok((listener, local_addr))
+        });
+
+        match bound {
+            Ok((listener, local_addr)) => {
+                info!(
+                    "Listening on: {} ({})",
+                    local_addr,
+                    address_family(local_addr, config.listener.dual_stack)
+                );
+                listeners.push((listener, local_addr));
+            }
+            Err(e) => {
+                let e = context(e, format!("Could not bind to {}", addr));
+                if config.addrs.len() > 1 {
+                    error!("{}", e);
+                }
+                last_error = Some(e);
+            }
+        }
+    }
+
+    match (listeners.is_empty(), last_error) {
+        (true, Some(e)) => Err(e),
+        (true, None) => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "no address to listen on",
+        )),
+        (false, Some(_)) => {
+            warn!(
+                "Continuing with {} of {} listeners",
+                listeners.len(),
+                config.addrs.len()
+            );
+            Ok(listeners)
+        }
+        (false, None) => Ok(listeners),
+    }
+}
+
+fn accept_tcp(
+    listener: TcpListener,
+    config: &Arc<ServerConfig>,
+    thread_pool: &ThreadPool,
+    shutdown: &Shutdown,
+    stats: &Arc<ServerStats>,
+    tls: &Option<Arc<rustls::ServerConfig>>,
+    admission: &Admission,
+) {
+    while let Some(tcp) = shutdown.accept(|| socket::accept_blocking(&listener)) {
+        match tcp {
+            Ok(stream) => {
+                let id = span::next_id();
+                let peer = stream.peer_addr();
+                let _span = span::enter_with_peer(id, peer.as_ref().ok());
+                let peer = match peer {
+                    Ok(peer) => Some(peer),
+                    Err(e) => {
+                        debug!("Could not read the address of connection: {}", e);
+                        None
+                    }
+                };
+                let name = peer_name(peer);
+                // A peer whose address is unknown cannot be checked against
+                // the access list, so it only gets in if the list is empty.
+                let allowed = match peer {
+                    Some(peer) => config.access.is_allowed(peer.ip()),
+                    None => config.access.is_empty(),
+                };
+                if !allowed {
+                    debug!("Refusing connection from {}, denied by access list", name);
+                    continue;
+                }
+
+                let permit = match admission.admit(peer.map(|peer| peer.ip()), &stream) {
+                    Some(permit) => permit,
+                    None => continue,
+                };
+                info!("Accepted connection from {}", name);
+                // The callbacks are given the address, so they only hear of
+                // peers whose address is known.
+                let accepted = peer.map(|peer| config.events.accepted(peer));
+
+                if let Err(e) = configure_tcp_stream(
+                    &stream,
+                    config.socket_read_timeout(),
+                    config.write_timeout,
+                    config.nodelay,
+                ) {
+                    warn!("Could not configure connection due to: {:?}", e);
+                    continue;
+                }
+                socket::set_buffer_sizes(&stream, config.recv_buffer_size, config.send_buffer_size);
+                if let Some(ttl) = config.ttl {
+                    socket::set_ttl(&stream, ttl);
+                }
+                if let Some(linger) = config.linger {
+                    socket::set_linger(&stream, linger);
+                }
+                if let Some(keepalive) = &config.keepalive {
+                    socket::set_keepalive(&stream, keepalive);
+                }
+
+                let busy_stream = match config.queue_capacity {
+                    Some(_) => stream.try_clone().ok(),
+                    None => None,
+                };
+
+                let tracked = match stream.try_clone() {
+                    Ok(closer) => shutdown.track(move || {
+                        let _ = closer.shutdown(net::Shutdown::Both);
+                    }),
+                    Err(e) => {
+                        warn!("Could not configure connection due to: {:?}", e);
+                        continue;
+                    }
+                };
+
+                let subscription = match &config.room {
+                    Some(room) => {
+                        let subscribed = stream.try_clone().and_then(|writer| {
+                            let closer = stream.try_clone()?;
+                            room.subscribe(id, writer, move || {
+                                let _ = closer.shutdown(net::Shutdown::Both);
+                            })
+                        });
+                        match subscribed {
+                            Ok(subscription) => Some(subscription),
+                            Err(e) => {
+                                warn!("Could not configure connection due to: {:?}", e);
+                                continue;
+                            }
+                        }
+                    }
+                    None => None,
+                };
+
+                let recording = config
+                    .recorder
+                    .as_ref()
+                    .map(|recorder| recorder.start(id, &name));
+                let config = Arc::clone(config);
+                let server_stats = Arc::clone(stats);
+                let active = stats.connection_opened();
+                let tls = tls.clone();
+                dispatch(thread_pool, busy_stream, move || {
+                    let _span = span::enter_with_peer(id, peer);
+                    let mirror = start_mirror(&config, id);
+                    let stats = match &tls {
+                        Some(tls_config) => match tls::accept(tls_config, stream) {
+                            Ok(stream) => Some(handle(
+                                Recorded::new(Mirrored::new(stream, mirror), recording),
+                                &config,
+                                &server_stats,
+                            )),
+                            Err(e) => {
+                                warn!("TLS handshake with {} failed due to: {:?}", name, e);
+                                None
+                            }
+                        },
+                        // The writer needs a handle to write to and one to shut
+                        // the socket down with.
+                        None if config.duplex => match stream
+                            .try_clone()
+                            .and_then(|writer| Ok((writer, stream.try_clone()?)))
+                        {
+                            Ok((writer, closer)) => Some(handle_duplex(
+                                Recorded::new(Mirrored::new(stream, mirror), recording),
+                                writer,
+                                move || {
+                                    let _ = closer.shutdown(net::Shutdown::Both);
+                                },
+                                &config,
+                                &server_stats,
+                            )),
+                            Err(e) => {
+                                warn!("Could not configure connection due to: {:?}", e);
+                                None
+                            }
+                        },
+                        None => Some(handle(
+                            Recorded::new(Mirrored::new(stream, mirror), recording),
+                            &config,
+                            &server_stats,
+                        )),
+                    };
+                    let failed = match &stats {
+                        Some(stats) => {
+                            log_closed(&name, stats);
+                            stats.error.is_some()
+                        }
+                        None => {
+                            server_stats.connection_ended(Outcome::Failed);
+                            true
+                        }
+                    };
+                    if let (true, Some(peer)) = (failed, &peer) {
+                        config.events.failed(peer);
+                    }
+                    drop(subscription);
+                    drop(accepted);
+                    drop(active);
+                    drop(permit);
+                    drop(tracked);
+                });
+            }
+            Err(e) => {
+                error!("Could not establish connection due to: {:?}", e);
+            }
+        }
+    }
+}
+
+// The connection limits of one server, shared by all of its accept loops.
+pub(crate) struct Admission {
+    connection_limit: ConnectionLimit,
+    per_ip_limit: Option<PerIpLimit>,
+    connection_rate: RateLimit,
+    // Written to connections rejected by the connection limit, CRLF
+    // included.
+    busy_message: Option<Vec<u8>>,
+}
+
+// The slots a connection holds until it is done, released when dropped.
+pub(crate) struct Permit {
+    _connection: ConnectionPermit,
+    _ip: Option<IpPermit>,
+}
+
+impl Admission {
+    fn new(config: &ServerConfig) -> Admission {
+        Admission {
+            connection_limit: ConnectionLimit::new(config.max_connections.unwrap_or(usize::MAX)),
+            per_ip_limit: config.max_connections_per_ip.map(PerIpLimit::new),
+            connection_rate: RateLimit::new(config.max_connection_rate),
+            busy_message: match (&config.busy_message, &config.tls) {
+                (Some(message), None) => Some(format!("{}\r\n", message).into_bytes()),
+                _ => None,
+            },
+        }
+    }
+
+    // Checks a freshly accepted connection from `ip`, if it has one, against
+    // the rate, per-address and concurrency limits; a rejected connection is
+    // closed by dropping it, after writing the busy message to `stream` if
+    // it ran into the concurrency limit.
+    pub(crate) fn admit<W: Write>(&self, ip: Option<IpAddr>, mut stream: W) -> Option<Permit> {
+        if !self.connection_rate.try_acquire() {
+            warn!("Rejecting connection, connection rate limit exceeded");
+            return None;
+        }
+
+        let ip_permit = match (&self.per_ip_limit, ip) {
+            (Some(limit), Some(ip)) => match limit.try_acquire(ip) {
+                Some(permit) => Some(permit),
+                None => {
+                    warn!(
+                        "Rejecting connection from {}, {} connections already open from it",
+                        ip,
+                        limit.active(ip)
+                    );
+                    return None;
+                }
+            },
+            _ => None,
+        };
+
+        match self.connection_limit.try_acquire() {
+            Some(permit) => Some(Permit {
+                _connection: permit,
+                _ip: ip_permit,
+            }),
+            None => {
+                warn!(
+                    "Rejecting connection, {} connections already active",
+                    self.connection_limit.active()
+                );
+                if let Some(message) = &self.busy_message {
+                    // The socket was just accepted, so its send buffer has
+                    // room for a short message even when it is blocking.
+                    if let Err(e) = stream.write_all(message) {
+                        debug!("Could not send the busy message due to: {:?}", e);
+                    }
+                }
+                None
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> io::Result<Listeners> {
+    remove_stale_socket(path)?;
+
+    let listener = UnixListener::bind(path)
+        .map_err(|e| context(e, format!("Could not bind to {}", path.display())))?;
+    info!("Listening on: {} (unix)", path.display());
+    Ok(Listeners::Unix(listener, path.to_path_buf()))
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_path: &Path) -> io::Result<Listeners> {
+    Err(io::Error::new(
+        ErrorKind::Unsupported,
+        "Unix sockets are only supported on unix platforms",
+    ))
+}
+
+#[cfg(unix)]
+fn serve_unix(
+    listener: UnixListener,
+    path: &Path,
+    config: &Arc<ServerConfig>,
+    thread_pool: &ThreadPool,
+    shutdown: &Shutdown,
+    stats: &Arc<ServerStats>,
+) -> io::Result<()> {
+    listener.set_nonblocking(true).map_err(|e| {
+        context(
+            e,
+            format!("Could not configure listener on {}", path.display()),
+        )
+    })?;
+
+    let admission = Admission::new(config);
+    let accept = || -> io::Result<UnixStream> {
+        let (stream, _) = listener.accept()?;
+        stream.set_nonblocking(false)?;
+        Ok(stream)
+    };
+
+    while let Some(unix) = shutdown.accept(&accept) {
+        match unix {
+            Ok(stream) => {
+                let id = span::next_id();
+                let peer = unix_peer(&stream);
+                let _span = span::enter_with_peer(id, Some(&peer));
+                let permit = match admission.admit(None, &stream) {
+                    Some(permit) => permit,
+                    None => continue,
+                };
+                info!("Accepted connection from {} on {}", peer, path.display());
+
+                let configured = stream
+                    .set_read_timeout(config.socket_read_timeout())
+                    .and_then(|()| stream.set_write_timeout(config.write_timeout));
+                if let Err(e) = configured {
+                    warn!("Could not configure connection due to: {:?}", e);
+                    continue;
+                }
+
+                let busy_stream = match config.queue_capacity {
+                    Some(_) => stream.try_clone().ok(),
+                    None => None,
+                };
+
+                let tracked = match stream.try_clone() {
+                    Ok(closer) => shutdown.track(move || {
+                        let _ = closer.shutdown(net::Shutdown::Both);
+                    }),
+                    Err(e) => {
+                        warn!("Could not configure connection due to: {:?}", e);
+                        continue;
+                    }
+                };
+
+                let subscription = match &config.room {
+                    Some(room) => {
+                        let subscribed = stream.try_clone().and_then(|writer| {
+                            let closer = stream.try_clone()?;
+                            room.subscribe(id, writer, move || {
+                                let _ = closer.shutdown(net::Shutdown::Both);
+                            })
+                        });
+                        match subscribed {
+                            Ok(subscription) => Some(subscription),
+                            Err(e) => {
+                                warn!("Could not configure connection due to: {:?}", e);
+                                continue;
+                            }
+                        }
+                    }
+                    None => None,
+                };
+
+                let recording = config
+                    .recorder
+                    .as_ref()
+                    .map(|recorder| recorder.start(id, path.display()));
+                let config = Arc::clone(config);
+                let server_stats = Arc::clone(stats);
+                let active = stats.connection_opened();
+                dispatch(thread_pool, busy_stream, move || {
+                    let _span = span::enter_with_peer(id, Some(&peer));
+                    let mirror = start_mirror(&config, id);
+                    let stats = if config.duplex {
+                        match stream
+                            .try_clone()
+                            .and_then(|writer| Ok((writer, stream.try_clone()?)))
+                        {
+                            Ok((writer, closer)) => handle_duplex(
+                                Recorded::new(Mirrored::new(stream, mirror), recording),
+                                writer,
+                                move || {
+                                    let _ = closer.shutdown(net::Shutdown::Both);
+                                },
+                                &config,
+                                &server_stats,
+                            ),
+                            Err(e) => {
+                                warn!("Could not configure connection due to: {:?}", e);
+                                server_stats.connection_ended(Outcome::Failed);
+                                return;
+                            }
+                        }
+                    } else {
+                        handle(
+                            Recorded::new(Mirrored::new(stream, mirror), recording),
+                            &config,
+                            &server_stats,
+                        )
+                    };
+                    log_closed(&peer, &stats);
+                    drop(subscription);
+                    drop(active);
+                    drop(permit);
+                    drop(tracked);
+                });
+            }
+            Err(e) => {
+                error!("Could not establish connection due to: {:?}", e);
+            }
+        }
+    }
+
+    drop(listener);
+    if let Err(e) = fs::remove_file(path) {
+        warn!("Could not remove {} due to: {:?}", path.display(), e);
+    }
+    Ok(())
+}
+
+// A socket file nobody accepts on is left behind by a previous run that did
+// not shut down cleanly; one that still accepts belongs to a live server.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+
+    if UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(
+            ErrorKind::AddrInUse,
+            format!("{} is already in use", path.display()),
+        ));
+    }
+
+    info!("Removing stale socket {}", path.display());
+    fs::remove_file(path).map_err(|e| context(e, format!("Could not remove {}", path.display())))
+}
+
+// Runs `task` on the pool. The task owns the stream, so `busy_stream` is a
+// second handle kept around to tell the client why it is being turned away
+// if the queue is full.
+fn dispatch<F, W>(thread_pool: &ThreadPool, busy_stream: Option<W>, task: F)
+where
+    F: FnOnce() + Send + 'static,
+    W: Write,
+{
+    if let Err(e) = thread_pool.execute(task) {
+        warn!("Rejecting connection, reason: {}", e);
+
+        if let (true, Some(mut stream)) = (e.is_full(), busy_stream) {
+            let _ = stream.write_all(BUSY_RESPONSE);
+        }
+    }
+}
+
+// Starts copying what connection `id` receives to the mirror upstream, if
+// there is one. Mirroring is best effort, so a failure is only logged.
+pub(crate) fn start_mirror(config: &ServerConfig, id: u64) -> Option<Mirror> {
+    let upstream = config.mirror?;
+    match Mirror::start(id, upstream) {
+        Ok(mirror) => Some(mirror),
+        Err(e) => {
+            warn!("Could not start mirroring to {} due to: {:?}", upstream, e);
+            None
+        }
+    }
+}
+
+pub(crate) fn log_closed(peer: impl Display, stats: &ConnectionStats) {
+    if stats.bytes_dropped > 0 {
+        info!(
+            "Connection from {} closed after echoing {} bytes and dropping {} in {} reads",
+            peer, stats.bytes_echoed, stats.bytes_dropped, stats.reads
+        );
+    } else {
+        info!(
+            "Connection from {} closed after echoing {} bytes in {} reads",
+            peer, stats.bytes_echoed, stats.reads
+        );
+    }
+}
+
+// How log lines name a TCP peer whose address may not have been readable.
+fn peer_name(peer: Option<SocketAddr>) -> String {
+    match peer {
+        Some(peer) => peer.to_string(),
+        None => String::from("unknown"),
+    }
+}
+
+// The path a Unix socket client bound to, which most leave unnamed.
+#[cfg(unix)]
+fn unix_peer(stream: &UnixStream) -> String {
+    stream
+        .peer_addr()
+        .ok()
+        .and_then(|addr| addr.as_pathname().map(|path| path.display().to_string()))
+        .unwrap_or_else(|| String::from("unknown"))
+}
+
+fn bind_udp(config: &ServerConfig) -> io::Result<Listeners> {
+    let addr = *config
+        .addrs
+        .first()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "no address to listen on"))?;
+    let socket =
+        UdpSocket::bind(addr).map_err(|e| context(e, format!("Could not bind to {}", addr)))?;
+    let local_addr = socket
+        .local_addr()
+        .map_err(|e| context(e, String::from("Could not resolve bound address")))?;
+    info!(
+        "Listening on: {} (udp, {})",
+        local_addr,
+        address_family(local_addr, false)
+    );
+    Ok(Listeners::Udp(socket, local_addr))
+}
+
+fn serve_udp(
+    socket: UdpSocket,
+    local_addr: SocketAddr,
+    config: &Arc<ServerConfig>,
+    thread_pool: &ThreadPool,
+    shutdown: &Shutdown,
+    stats: &Arc<ServerStats>,
+) -> io::Result<()> {
+    let wake_addr = loopback_for(local_addr);
+    shutdown.on_request(move || {
+        let unspecified = match wake_addr {
+            SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+            SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+        };
+        let _ = UdpSocket::bind(unspecified).and_then(|socket| socket.send_to(&[], wake_addr));
+    });
+
+    // A datagram longer than the buffer is truncated by `recv_from`, so only
+    // its first `buffer_size` bytes are echoed back.
+    let socket = Arc::new(socket);
+    let mut buffer = vec![0u8; config.buffer_size];
+
+    loop {
+        let received = socket.recv_from(&mut buffer);
+        if shutdown.is_requested() {
+            break;
+        }
+
+        match received {
+            Ok((len, peer)) => {
+                let datagram = buffer[..len].to_vec();
+                let socket = Arc::clone(&socket);
+                let config = Arc::clone(config);
+                let stats = Arc::clone(stats);
+                let result = thread_pool.execute(move || {
+                    handle_datagram(&socket, &datagram, peer, &*config.transform);
+                    stats.add_bytes_echoed(datagram.len() as u64);
+                });
+
+                if let Err(e) = result {
+                    warn!("Dropping datagram from {}, reason: {}", peer, e);
+                }
+            }
+            Err(e) => {
+                error!("Could not receive datagram due to: {:?}", e);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Waits for SIGINT/SIGTERM on a dedicated thread and requests shutdown on
+// the first signal.
+fn spawn_signal_handler(shutdown: Arc<Shutdown>) -> io::Result<()> {
+    let mut signals = Signals::new([SIGINT, SIGTERM])
+        .map_err(|e| context(e, String::from("Could not install signal handlers")))?;
+
+    thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            info!("Received signal {}, shutting down", signal);
+            shutdown.request();
+        }
+    });
+    Ok(())
+}
+
+fn address_family(addr: SocketAddr, dual_stack: bool) -> &'static str {
+    match addr {
+        SocketAddr::V4(_) => "IPv4",
+        SocketAddr::V6(_) if dual_stack => "IPv6, dual-stack",
+        SocketAddr::V6(_) => "IPv6",
+    }
+}
+
+fn loopback_for(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port())
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            SocketAddr::new(Ipv6Addr::LOCALHOST.into(), addr.port())
+        }
+        _ => addr,
+    }
+}
+
+fn context(e: io::Error, message: String) -> io::Error {
+    io::Error::new(e.kind(), format!("{} due to: {:?}", message, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_defaults_serve_plain_tcp_echo_on_port_8080() {
+        let config = ServerConfig::builder().build();
+
+        assert_eq!(
+            config.addrs,
+            vec![SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 8080)]
+        );
+        assert_eq!(config.protocol, Protocol::Tcp);
+        assert!(config.unix.is_none() && config.tls.is_none());
+        assert_eq!(config.pool_size, 8);
+        assert_eq!(config.runtime, Runtime::Threads);
+        assert_eq!(config.mode, Mode::Raw);
+        assert_eq!(config.buffer_size, 1024);
+        assert_eq!(config.read_timeout, Some(DEFAULT_READ_TIMEOUT));
+        assert_eq!(config.write_timeout, Some(DEFAULT_WRITE_TIMEOUT));
+        assert!(config.nodelay);
+        assert_eq!(config.max_connections, None);
+        assert_eq!(config.max_connection_rate, 0);
+        assert_eq!(config.max_connection_bytes, None);
+        assert_eq!(config.echo_delay, Duration::ZERO);
+        assert_eq!(&*(config.transform)(b"unchanged"), b"unchanged");
+    }
+
+    #[test]
+    fn builder_overrides_only_the_fields_it_is_given() {
+        let defaults = ServerConfig::builder().build();
+        let config = ServerConfig::builder()
+            .pool_size(2)
+            .buffer_size(4096)
+            .read_timeout(None)
+            .build();
+
+        assert_eq!(config.pool_size, 2);
+        assert_eq!(config.buffer_size, 4096);
+        assert_eq!(config.read_timeout, None);
+        assert_eq!(config.write_timeout, defaults.write_timeout);
+        assert_eq!(config.addrs, defaults.addrs);
+        assert_eq!(config.mode, defaults.mode);
+        assert_eq!(config.nodelay, defaults.nodelay);
+    }
+}
@@ -0,0 +1,298 @@
+use log::warn;
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long an accept loop sleeps when no connection is waiting before it
+/// checks the shutdown flag again. Long enough that an idle server barely
+/// uses any CPU, short enough that neither shutdown nor the first connection
+/// after a quiet spell is noticeably delayed.
+pub const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Longest an accept loop waits before retrying an accept that keeps
+/// failing, such as on running out of file descriptors.
+pub const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+type Waker = Box<dyn FnOnce() + Send>;
+type Closer = Box<dyn Fn() + Send>;
+
+/// What happens to the connections still open once the server stops
+/// accepting new ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownMode {
+    /// Lets open connections finish their echoes, closing the ones still
+    /// open when the shutdown timeout runs out.
+    Drain,
+    /// Closes every open connection right away; handlers stop at their
+    /// next read or write.
+    Immediate,
+}
+
+impl FromStr for ShutdownMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ShutdownMode, String> {
+        match s {
+            "drain" => Ok(ShutdownMode::Drain),
+            "immediate" => Ok(ShutdownMode::Immediate),
+            other => Err(format!(
+                "unsupported shutdown mode {:?}, expected drain or immediate",
+                other
+            )),
+        }
+    }
+}
+
+/// Coordinates a graceful shutdown between whoever requests it, such as a
+/// signal or a control command, and the loops that have to stop.
+///
+/// Accept loops put their listener in non-blocking mode and go through
+/// `accept`, which checks the flag between connections. Loops that block
+/// elsewhere, like `recv_from` on a UDP socket, only check `is_requested`
+/// once that returns, so they register a waker that unblocks them instead.
+///
+/// It also tracks the open connections, so they can be drained or closed
+/// once accepting has stopped.
+#[derive(Default)]
+pub struct Shutdown {
+    requested: AtomicBool,
+    wakers: Mutex<Vec<Waker>>,
+    connections: Arc<Connections>,
+}
+
+impl Shutdown {
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Raises the shutdown flag and runs every registered waker. Only the
+    /// first call has any effect.
+    pub fn request(&self) {
+        let wakers = {
+            let mut wakers = self.wakers.lock().unwrap_or_else(PoisonError::into_inner);
+            if self.requested.swap(true, Ordering::SeqCst) {
+                return;
+            }
+            mem::take(&mut *wakers)
+        };
+
+        for wake in wakers {
+            wake();
+        }
+    }
+
+    /// Calls `accept` on a non-blocking listener until it yields a connection
+    /// or fails with an error that only concerns the connection being
+    /// accepted, sleeping for `ACCEPT_POLL_INTERVAL` in between. Returns
+    /// `None` once shutdown is requested, so an accept loop stops without
+    /// anyone connecting to it.
+    ///
+    /// Any other error, like running out of file descriptors, would most
+    /// likely fail the next accept straight away too, so it is logged and
+    /// retried after a pause that doubles with every failure in a row, up to
+    /// `MAX_ACCEPT_BACKOFF`.
+    pub(crate) fn accept<T, F>(&self, mut accept: F) -> Option<io::Result<T>>
+    where
+        F: FnMut() -> io::Result<T>,
+    {
+        let mut backoff = ACCEPT_POLL_INTERVAL;
+        while !self.is_requested() {
+            match accept() {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL_INTERVAL),
+                Err(e) if !is_transient(&e) => {
+                    warn!(
+                        "Could not accept connections due to: {:?}, retrying in {:?}",
+                        e, backoff
+                    );
+                    let deadline = Instant::now() + backoff;
+                    while !self.is_requested() && Instant::now() < deadline {
+                        thread::sleep(ACCEPT_POLL_INTERVAL);
+                    }
+                    backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                }
+                result => return Some(result),
+            }
+        }
+        None
+    }
+
+    /// Registers `wake` to run once shutdown is requested, or runs it right
+    /// away if that already happened.
+    pub fn on_request<F>(&self, wake: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut wakers = self.wakers.lock().unwrap_or_else(PoisonError::into_inner);
+        if self.is_requested() {
+            drop(wakers);
+            wake();
+        } else {
+            wakers.push(Box::new(wake));
+        }
+    }
+
+    /// Tracks an open connection until the returned guard is dropped, so
+    /// `close_connections` can end it with `close`, typically by shutting
+    /// down a clone of its socket.
+    pub(crate) fn track<F>(&self, close: F) -> Tracked
+    where
+        F: Fn() + Send + 'static,
+    {
+        let id = self.connections.next_id.fetch_add(1, Ordering::Relaxed);
+        self.connections.lock_open().insert(id, Box::new(close));
+        Tracked {
+            connections: Arc::clone(&self.connections),
+            id,
+        }
+    }
+
+    pub(crate) fn open_connections(&self) -> usize {
+        self.connections.lock_open().len()
+    }
+
+    /// Blocks until every tracked connection has ended or `timeout` runs
+    /// out, returning how many are still open.
+    pub(crate) fn wait_for_connections(&self, timeout: Option<Duration>) -> usize {
+        let connections = &self.connections;
+        let open = connections.lock_open();
+        let open = match timeout {
+            Some(timeout) => {
+                connections
+                    .ended
+                    .wait_timeout_while(open, timeout, |open| !open.is_empty())
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .0
+            }
+            None => connections
+                .ended
+                .wait_while(open, |open| !open.is_empty())
+                .unwrap_or_else(PoisonError::into_inner),
+        };
+        open.len()
+    }
+
+    /// Closes every tracked connection that is still open and returns how
+    /// many there were. Their handlers notice on their next read or write.
+    pub(crate) fn close_connections(&self) -> usize {
+        let open = self.connections.lock_open();
+        for close in open.values() {
+            close();
+        }
+        open.len()
+    }
+}
+
+// Errors of accept that are about the connection at hand, such as one the
+// client reset before it was accepted, rather than about the listener or
+// the process. Linux also reports pending network errors of the new
+// connection this way.
+pub(crate) fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionAborted
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::Interrupted
+            | ErrorKind::TimedOut
+            | ErrorKind::PermissionDenied
+            | ErrorKind::HostUnreachable
+            | ErrorKind::NetworkUnreachable
+            | ErrorKind::NetworkDown
+    )
+}
+
+#[derive(Default)]
+struct Connections {
+    next_id: AtomicU64,
+    open: Mutex<HashMap<u64, Closer>>,
+    ended: Condvar,
+}
+
+impl Connections {
+    fn lock_open(&self) -> MutexGuard<'_, HashMap<u64, Closer>> {
+        self.open.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Keeps a connection tracked by `Shutdown::track` while alive.
+pub(crate) struct Tracked {
+    connections: Arc<Connections>,
+    id: u64,
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        let mut open = self.connections.lock_open();
+        open.remove(&self.id);
+        if open.is_empty() {
+            self.connections.ended.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn idle_accept_loop_sleeps_and_exits_soon_after_shutdown_is_requested() {
+        const IDLE: Duration = Duration::from_millis(200);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let (shutdown, polls) = (Shutdown::default(), AtomicUsize::new(0));
+
+        thread::scope(|scope| {
+            let accepting = scope.spawn(|| {
+                shutdown.accept(|| {
+                    polls.fetch_add(1, Ordering::SeqCst);
+                    listener.accept()
+                })
+            });
+            thread::sleep(IDLE);
+            let requested = Instant::now();
+            shutdown.request();
+
+            assert!(accepting.join().unwrap().is_none());
+            assert!(requested.elapsed() < ACCEPT_POLL_INTERVAL * 10);
+        });
+        // Sleeping between polls keeps an idle loop to a few wakeups.
+        let polls = polls.into_inner() as u128;
+        let max_polls = IDLE.as_millis() / ACCEPT_POLL_INTERVAL.as_millis() + 5;
+        assert!(polls <= max_polls, "polled {} times in {:?}", polls, IDLE);
+    }
+
+    #[test]
+    fn failing_accept_backs_off_while_transient_errors_are_returned_at_once() {
+        const FAILING: Duration = Duration::from_millis(500);
+        let (shutdown, attempts) = (Shutdown::default(), AtomicUsize::new(0));
+
+        thread::scope(|scope| {
+            let accepting = scope.spawn(|| {
+                shutdown.accept(|| -> io::Result<()> {
+                    attempts.fetch_add(1, Ordering::SeqCst);
+                    Err(io::Error::other("too many open files"))
+                })
+            });
+            thread::sleep(FAILING);
+            shutdown.request();
+            assert!(accepting.join().unwrap().is_none());
+        });
+        // Pauses of 10, 20, 40, 80, 160 and 320ms fill the half second.
+        let attempts = attempts.into_inner();
+        assert!((3..=8).contains(&attempts), "{} attempts", attempts);
+
+        let aborted = Shutdown::default()
+            .accept(|| -> io::Result<()> { Err(io::Error::from(ErrorKind::ConnectionAborted)) });
+        assert_eq!(
+            aborted.unwrap().unwrap_err().kind(),
+            ErrorKind::ConnectionAborted
+        );
+    }
+}
@@ -0,0 +1,313 @@
+use log::{debug, info, warn};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+#[cfg(target_os = "linux")]
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::num::NonZeroU8;
+use std::time::Duration;
+
+pub const DEFAULT_BACKLOG: u32 = 128;
+
+/// Socket options applied to a TCP listener before it starts listening.
+#[derive(Debug, Clone)]
+pub struct ListenerOptions {
+    pub backlog: u32,
+    /// Clears `IPV6_V6ONLY` on IPv6 listeners so IPv4 clients can connect
+    /// through IPv4-mapped addresses. Only useful when binding to `::`.
+    pub dual_stack: bool,
+    /// Sets `SO_REUSEPORT`, so several server processes can bind the same
+    /// address and the kernel spreads incoming connections over them.
+    /// Binding fails on platforms without it.
+    pub reuse_port: bool,
+}
+
+impl Default for ListenerOptions {
+    fn default() -> ListenerOptions {
+        ListenerOptions {
+            backlog: DEFAULT_BACKLOG,
+            dual_stack: false,
+            reuse_port: false,
+        }
+    }
+}
+
+/// Binds a TCP listener with `SO_REUSEADDR` set, so a restarted server can
+/// bind its port while connections of the previous process sit in TIME_WAIT.
+///
+/// On Unix `SO_REUSEADDR` only relaxes the TIME_WAIT check; it does not let
+/// two live sockets share a port, which is what `SO_REUSEPORT` is for, set
+/// when the options ask for it. On Windows the same option would allow
+/// another process to steal an active port, so it is left unset there and
+/// the default exclusive bind is used.
+///
+/// The backlog bounds the kernel queue of connections that completed the
+/// handshake but were not accepted yet. It is independent of `SO_REUSEADDR`,
+/// which only matters at bind time, and is clamped to the platform maximum.
+pub fn bind_tcp_listener(addr: SocketAddr, options: &ListenerOptions) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+    if options.reuse_port {
+        set_reuse_port(&socket)?;
+    }
+
+    if addr.is_ipv6() {
+        socket.set_only_v6(!options.dual_stack)?;
+    } else if options.dual_stack {
+        warn!(
+            "Dual-stack listening requires an IPv6 address, {} is IPv4",
+            addr
+        );
+    }
+
+    socket.bind(&addr.into())?;
+    socket.listen(clamp_backlog(options.backlog))?;
+    Ok(socket.into())
+}
+
+#[cfg(all(
+    unix,
+    not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+))]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(all(
+    unix,
+    not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+)))]
+fn set_reuse_port(_socket: &Socket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is not supported on this platform",
+    ))
+}
+
+/// Sets `SO_RCVBUF` and `SO_SNDBUF` on an accepted stream, leaving the kernel
+/// default for sizes that are `None`.
+///
+/// The kernel may round the requested size, and Linux doubles it to make
+/// room for bookkeeping, so the effective sizes are read back and logged.
+/// Failures only produce a warning, since the connection still works with
+/// the default buffers.
+///
+/// The TCP window scale is negotiated during the handshake, before the
+/// stream is accepted, so on some platforms a receive buffer grown here
+/// cannot be fully advertised to the peer.
+pub fn set_buffer_sizes(stream: &TcpStream, recv: Option<usize>, send: Option<usize>) {
+    let socket = SockRef::from(stream);
+
+    if let Some(size) = recv {
+        match socket
+            .set_recv_buffer_size(size)
+            .and_then(|()| socket.recv_buffer_size())
+        {
+            Ok(applied) => info!("Requested SO_RCVBUF {}, applied {}", size, applied),
+            Err(e) => warn!("Could not configure SO_RCVBUF due to: {:?}", e),
+        }
+    }
+
+    if let Some(size) = send {
+        match socket
+            .set_send_buffer_size(size)
+            .and_then(|()| socket.send_buffer_size())
+        {
+            Ok(applied) => info!("Requested SO_SNDBUF {}, applied {}", size, applied),
+            Err(e) => warn!("Could not configure SO_SNDBUF due to: {:?}", e),
+        }
+    }
+}
+
+/// Sets the TTL of packets sent on an accepted stream, as the hop limit on
+/// IPv6 connections. Failures are logged and leave the system default.
+pub fn set_ttl(stream: &TcpStream, ttl: NonZeroU8) {
+    let ttl = u32::from(ttl.get());
+    let socket = SockRef::from(stream);
+
+    let applied = match stream.local_addr() {
+        Ok(SocketAddr::V6(addr)) if addr.ip().to_ipv4_mapped().is_none() => socket
+            .set_unicast_hops_v6(ttl)
+            .and_then(|()| socket.unicast_hops_v6()),
+        Ok(_) => stream.set_ttl(ttl).and_then(|()| stream.ttl()),
+        Err(e) => Err(e),
+    };
+    match applied {
+        Ok(applied) => debug!("Requested TTL {}, applied {}", ttl, applied),
+        Err(e) => warn!("Could not configure TTL due to: {:?}", e),
+    }
+}
+
+/// Sets `SO_LINGER` on an accepted stream. Closing it then blocks for up to
+/// `linger` until unsent data is acknowledged, or with a zero `linger`
+/// discards unsent data and resets the connection instead of closing it
+/// gracefully. Without it, the system default closes in the background.
+/// Failures are logged and leave the system default.
+pub fn set_linger(stream: &TcpStream, linger: Duration) {
+    if let Err(e) = SockRef::from(stream).set_linger(Some(linger)) {
+        warn!("Could not configure SO_LINGER due to: {:?}", e);
+    }
+}
+
+/// TCP keepalive probing of idle connections, see `set_keepalive`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    /// How long a connection has to be idle before the first probe.
+    pub idle: Duration,
+    /// Time between unanswered probes, the system default when unset.
+    pub interval: Option<Duration>,
+    /// Unanswered probes after which the connection is reset, the system
+    /// default when unset.
+    pub retries: Option<u32>,
+}
+
+/// Enables `SO_KEEPALIVE` on an accepted stream, so the system probes a
+/// connection that has been idle for `keepalive.idle` and resets it once the
+/// peer stops answering, which fails a blocked read with an error.
+///
+/// The idle time can be set on every platform. The interval can be set on
+/// Linux, Android, the BSDs, macOS and Windows, the retry count on all of
+/// those but Windows; elsewhere they are logged and left at the system
+/// default. Failures are logged and leave keepalive as it was.
+pub fn set_keepalive(stream: &TcpStream, keepalive: &Keepalive) {
+    let mut params = TcpKeepalive::new().with_time(keepalive.idle);
+    if let Some(interval) = keepalive.interval {
+        #[cfg(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "ios",
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "netbsd",
+            windows
+        ))]
+        {
+            params = params.with_interval(interval);
+        }
+        #[cfg(not(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "ios",
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "netbsd",
+            windows
+        )))]
+        warn!(
+            "Not setting a keepalive interval of {:?}, it is not supported on this platform",
+            interval
+        );
+    }
+    if let Some(retries) = keepalive.retries {
+        #[cfg(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "ios",
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "netbsd"
+        ))]
+        {
+            params = params.with_retries(retries);
+        }
+        #[cfg(not(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "ios",
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "netbsd"
+        )))]
+        warn!(
+            "Not setting {} keepalive retries, it is not supported on this platform",
+            retries
+        );
+    }
+
+    if let Err(e) = SockRef::from(stream).set_tcp_keepalive(&params) {
+        warn!("Could not configure TCP keepalive due to: {:?}", e);
+    }
+}
+
+// Accepts from a listener set to non-blocking mode, handing out the stream
+// in blocking mode: some platforms let accepted sockets inherit the flag.
+pub(crate) fn accept_blocking(listener: &TcpListener) -> io::Result<TcpStream> {
+    let (stream, _) = listener.accept()?;
+    stream.set_nonblocking(false)?;
+    Ok(stream)
+}
+
+fn clamp_backlog(backlog: u32) -> i32 {
+    let max = max_backlog();
+    if backlog > max as u32 {
+        warn!(
+            "Backlog {} exceeds the platform maximum, using {}",
+            backlog, max
+        );
+        max
+    } else {
+        backlog as i32
+    }
+}
+
+// Linux silently truncates the backlog to `net.core.somaxconn`, so read the
+// effective limit to report it; elsewhere only the API bound applies.
+#[cfg(target_os = "linux")]
+fn max_backlog() -> i32 {
+    fs::read_to_string("/proc/sys/net/core/somaxconn")
+        .ok()
+        .and_then(|max| max.trim().parse().ok())
+        .unwrap_or(i32::MAX)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn max_backlog() -> i32 {
+    i32::MAX
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A loopback connection, as the client's and the server's stream.
+    fn connected() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        (client, accepted)
+    }
+
+    #[test]
+    fn ttl_is_applied_to_the_accepted_stream() {
+        let (_client, accepted) = connected();
+
+        set_ttl(&accepted, NonZeroU8::new(7).unwrap());
+        assert_eq!(accepted.ttl().unwrap(), 7);
+        set_ttl(&accepted, NonZeroU8::new(255).unwrap());
+        assert_eq!(accepted.ttl().unwrap(), 255);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn keepalive_idle_interval_and_retries_are_applied_to_the_accepted_stream() {
+        let (_client, accepted) = connected();
+        let socket = SockRef::from(&accepted);
+        assert!(!socket.keepalive().unwrap());
+
+        set_keepalive(
+            &accepted,
+            &Keepalive {
+                idle: Duration::from_secs(30),
+                interval: Some(Duration::from_secs(5)),
+                retries: Some(3),
+            },
+        );
+
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(30));
+        assert_eq!(socket.keepalive_interval().unwrap(), Duration::from_secs(5));
+        assert_eq!(socket.keepalive_retries().unwrap(), 3);
+    }
+}
@@ -0,0 +1,93 @@
+use std::cell::{Cell, RefCell};
+use std::fmt::Display;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static CURRENT: Cell<Option<u64>> = const { Cell::new(None) };
+    static PEER: RefCell<Option<Arc<str>>> = const { RefCell::new(None) };
+}
+
+/// Allocates a connection id that no other connection of this process got.
+pub fn next_id() -> u64 {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Id of the connection this thread is working on, if any, so a logger can
+/// tag every line with the connection it is about. The server's own
+/// logger writes it as `conn=<id>`.
+pub fn current() -> Option<u64> {
+    CURRENT.with(Cell::get)
+}
+
+/// Address of the peer of the connection this thread is working on, if it
+/// was entered with [`enter_with_peer`], so its log lines can name it.
+pub fn peer() -> Option<Arc<str>> {
+    PEER.with(|peer| peer.borrow().clone())
+}
+
+/// Makes `id` the connection this thread is working on until the returned
+/// guard is dropped, which restores whatever was current before.
+pub fn enter(id: u64) -> Entered {
+    enter_peer(id, None)
+}
+
+/// Like [`enter`], but also makes `peer` the address [`peer`] returns, or
+/// `unknown` for a peer whose address could not be read.
+pub fn enter_with_peer(id: u64, peer: Option<impl Display>) -> Entered {
+    enter_peer(
+        id,
+        Some(match peer {
+            Some(peer) => Arc::from(peer.to_string()),
+            None => Arc::from("unknown"),
+        }),
+    )
+}
+
+fn enter_peer(id: u64, peer: Option<Arc<str>>) -> Entered {
+    Entered {
+        previous: CURRENT.with(|current| current.replace(Some(id))),
+        previous_peer: PEER.with(|current| current.replace(peer)),
+        _thread: PhantomData,
+    }
+}
+
+/// Keeps a connection id current on this thread while alive.
+pub struct Entered {
+    previous: Option<u64>,
+    previous_peer: Option<Arc<str>>,
+    // The id belongs to the thread it was entered on.
+    _thread: PhantomData<*const ()>,
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.set(self.previous));
+        PEER.with(|current| *current.borrow_mut() = self.previous_peer.take());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn unreadable_peer_is_named_unknown_until_the_guard_drops() {
+        {
+            let _span = enter_with_peer(1, None::<SocketAddr>);
+            assert_eq!(current(), Some(1));
+            assert_eq!(peer().as_deref(), Some("unknown"));
+            {
+                let _inner = enter(2);
+                assert_eq!(peer(), None);
+            }
+            assert_eq!(peer().as_deref(), Some("unknown"));
+        }
+        assert_eq!(current(), None);
+        assert_eq!(peer(), None);
+    }
+}
@@ -0,0 +1,153 @@
+use crate::pool::ThreadPool;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+
+/// How a connection ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The client closed the connection, or the server did once it asked to
+    /// or a byte limit was reached.
+    Closed,
+    /// The client went away without a clean close, such as with a reset.
+    Disconnected,
+    /// An I/O error, a timeout or a protocol violation ended the connection.
+    Failed,
+}
+
+/// Number of connections that ended with each `Outcome`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Outcomes {
+    pub closed: u64,
+    pub disconnected: u64,
+    pub failed: u64,
+}
+
+/// Counters aggregated over every connection served by one server.
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    accepted: AtomicU64,
+    active: AtomicU64,
+    bytes_echoed: AtomicU64,
+    outcomes: OutcomeCounters,
+    // Keyed by the id of the pool worker that served the connection.
+    workers: RwLock<BTreeMap<usize, OutcomeCounters>>,
+}
+
+impl ServerStats {
+    /// Counts a newly dispatched connection as accepted and active. It stays
+    /// active until the returned guard is dropped, including when the
+    /// connection handler unwinds.
+    pub fn connection_opened(self: &Arc<ServerStats>) -> ActiveConnection {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        self.active.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection {
+            stats: Arc::clone(self),
+        }
+    }
+
+    pub fn add_bytes_echoed(&self, bytes: u64) {
+        self.bytes_echoed.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts how a connection ended, both overall and for the pool worker
+    /// running the calling thread, if any.
+    pub fn connection_ended(&self, outcome: Outcome) {
+        self.outcomes.add(outcome);
+
+        let id = match ThreadPool::current_worker_id() {
+            Some(id) => id,
+            None => return,
+        };
+        let workers = self.workers.read().unwrap_or_else(PoisonError::into_inner);
+        if let Some(counters) = workers.get(&id) {
+            counters.add(outcome);
+            return;
+        }
+        drop(workers);
+        self.workers
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(id)
+            .or_default()
+            .add(outcome);
+    }
+
+    pub fn accepted(&self) -> u64 {
+        self.accepted.load(Ordering::Relaxed)
+    }
+
+    pub fn active(&self) -> u64 {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_echoed(&self) -> u64 {
+        self.bytes_echoed.load(Ordering::Relaxed)
+    }
+
+    /// Connections that ended with `Outcome::Failed`; clients that merely
+    /// disconnected abruptly are counted by `disconnects` instead.
+    pub fn errors(&self) -> u64 {
+        self.outcomes.failed.load(Ordering::Relaxed)
+    }
+
+    pub fn closed(&self) -> u64 {
+        self.outcomes.closed.load(Ordering::Relaxed)
+    }
+
+    pub fn disconnects(&self) -> u64 {
+        self.outcomes.disconnected.load(Ordering::Relaxed)
+    }
+
+    pub fn outcomes(&self) -> Outcomes {
+        self.outcomes.snapshot()
+    }
+
+    /// Outcomes of the connections served by each pool worker, by worker id.
+    /// Workers that have not finished a connection yet are left out.
+    pub fn worker_outcomes(&self) -> Vec<(usize, Outcomes)> {
+        self.workers
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(&id, counters)| (id, counters.snapshot()))
+            .collect()
+    }
+}
+
+#[derive(Debug, Default)]
+struct OutcomeCounters {
+    closed: AtomicU64,
+    disconnected: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl OutcomeCounters {
+    fn add(&self, outcome: Outcome) {
+        let counter = match outcome {
+            Outcome::Closed => &self.closed,
+            Outcome::Disconnected => &self.disconnected,
+            Outcome::Failed => &self.failed,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> Outcomes {
+        Outcomes {
+            closed: self.closed.load(Ordering::Relaxed),
+            disconnected: self.disconnected.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Keeps a connection counted in `ServerStats::active` while alive.
+pub struct ActiveConnection {
+    stats: Arc<ServerStats>,
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.stats.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,110 @@
+//! A scripted stream standing in for a client socket in unit tests.
+
+use crate::connection::HalfClose;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Read, Write};
+
+/// A client that sends its chunks one read at a time, in order, and collects
+/// everything written to it in `output`. Reads past the last chunk see end
+/// of stream.
+pub(crate) struct MockStream {
+    reads: VecDeque<io::Result<Vec<u8>>>,
+    write_errors: VecDeque<io::Error>,
+    pub(crate) output: Vec<u8>,
+    pub(crate) write_shut: bool,
+}
+
+impl MockStream {
+    pub(crate) fn new(chunks: &[&[u8]]) -> MockStream {
+        MockStream {
+            reads: chunks.iter().map(|chunk| Ok(chunk.to_vec())).collect(),
+            write_errors: VecDeque::new(),
+            output: Vec::new(),
+            write_shut: false,
+        }
+    }
+
+    /// Fails the read after the chunks so far with `e`.
+    pub(crate) fn then_fail_read(mut self, e: io::Error) -> MockStream {
+        self.reads.push_back(Err(e));
+        self
+    }
+
+    /// Sends `chunk` after the reads scripted so far.
+    pub(crate) fn then_send(mut self, chunk: &[u8]) -> MockStream {
+        self.reads.push_back(Ok(chunk.to_vec()));
+        self
+    }
+
+    /// Fails the first write that has not failed yet with `e`.
+    pub(crate) fn fail_write(mut self, e: io::Error) -> MockStream {
+        self.write_errors.push_back(e);
+        self
+    }
+
+    fn skip_empty(&mut self) {
+        while matches!(self.reads.front(), Some(Ok(chunk)) if chunk.is_empty()) {
+            self.reads.pop_front();
+        }
+    }
+}
+
+impl Read for MockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl BufRead for MockStream {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.skip_empty();
+        if matches!(self.reads.front(), Some(Err(_))) {
+            if let Some(Err(e)) = self.reads.pop_front() {
+                return Err(e);
+            }
+        }
+        match self.reads.front() {
+            Some(Ok(chunk)) => Ok(chunk),
+            _ => Ok(&[]),
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if let Some(Ok(chunk)) = self.reads.front_mut() {
+            chunk.drain(..amt);
+        }
+        self.skip_empty();
+    }
+}
+
+impl Write for MockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(e) = self.write_errors.pop_front() {
+            return Err(e);
+        }
+        self.output.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl HalfClose for MockStream {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.write_shut = true;
+        Ok(())
+    }
+}
+
+// Lets tests hand a stream to `handle` and look at its output afterwards.
+impl HalfClose for &mut MockStream {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        (**self).shutdown_write()
+    }
+}
@@ -0,0 +1,175 @@
+use crate::connection::HalfClose;
+use log::info;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+use std::convert::TryFrom;
+use std::fmt::{Display, Write as _};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Builds a rustls server configuration from a PEM certificate chain and a
+/// PEM private key. With `client_ca_path`, clients must present a
+/// certificate issued by one of the PEM CA certificates it holds.
+pub fn load_server_config(
+    cert_path: &Path,
+    key_path: &Path,
+    client_ca_path: Option<&Path>,
+) -> io::Result<Arc<ServerConfig>> {
+    let certs = load_certs(cert_path)?;
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| invalid_data(key_path, e))?;
+
+    let verifier = match client_ca_path {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots.add(cert).map_err(|e| invalid_data(path, e))?;
+            }
+            WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .map_err(|e| invalid_data(path, e))?
+        }
+        None => WebPkiClientVerifier::no_client_auth(),
+    };
+
+    let config = ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs, key)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    Ok(Arc::new(config))
+}
+
+/// Completes the TLS handshake on an accepted stream.
+pub fn accept(config: &Arc<ServerConfig>, mut stream: TcpStream) -> io::Result<TlsStream> {
+    let mut connection = ServerConnection::new(Arc::clone(config))
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+
+    while connection.is_handshaking() {
+        connection.complete_io(&mut stream)?;
+    }
+
+    // Only present when client certificates are required, in which case the
+    // handshake already verified it.
+    if let Some(cert) = connection
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+    {
+        info!("Verified client certificate of {}", client_subject(cert));
+    }
+    Ok(TlsStream(StreamOwned::new(connection, stream)))
+}
+
+/// An established TLS session that sends `close_notify` when dropped, so the
+/// peer can tell a clean close from a truncated stream.
+pub struct TlsStream(StreamOwned<ServerConnection, TcpStream>);
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+// `close_notify` is TLS's own end of stream, so it goes out ahead of the TCP
+// FIN; sending it again on drop is a no-op.
+impl HalfClose for TlsStream {
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.0.conn.send_close_notify();
+        self.0.flush()?;
+        self.0.sock.shutdown(Shutdown::Write)
+    }
+}
+
+impl Drop for TlsStream {
+    fn drop(&mut self) {
+        self.0.conn.send_close_notify();
+        let _ = self.0.flush();
+    }
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid_data(path, e))
+}
+
+// Renders the subject of a certificate like `CN=client, O=Example`, keeping
+// the attributes of common types that hold a string.
+fn client_subject(cert: &CertificateDer<'_>) -> String {
+    let cert = match webpki::EndEntityCert::try_from(cert) {
+        Ok(cert) => cert,
+        Err(_) => return String::from("an unparsable certificate"),
+    };
+
+    let mut subject = String::new();
+    let mut name = cert.subject();
+    // A name is a sequence of sets holding (type, value) sequences.
+    while let Some((0x31, mut set, rest)) = der_element(name) {
+        name = rest;
+        while let Some((0x30, attribute, rest)) = der_element(set) {
+            set = rest;
+            let (oid, value) = match der_element(attribute) {
+                Some((0x06, oid, value)) => (oid, value),
+                _ => continue,
+            };
+            let label = match oid {
+                [0x55, 0x04, 0x03] => "CN",
+                [0x55, 0x04, 0x06] => "C",
+                [0x55, 0x04, 0x07] => "L",
+                [0x55, 0x04, 0x08] => "ST",
+                [0x55, 0x04, 0x0a] => "O",
+                [0x55, 0x04, 0x0b] => "OU",
+                _ => continue,
+            };
+            // UTF8String, PrintableString, TeletexString or IA5String.
+            if let Some((0x0c | 0x13 | 0x14 | 0x16, value, _)) = der_element(value) {
+                if !subject.is_empty() {
+                    subject.push_str(", ");
+                }
+                let _ = write!(subject, "{}={}", label, String::from_utf8_lossy(value));
+            }
+        }
+    }
+
+    if subject.is_empty() {
+        subject.push_str("an empty subject");
+    }
+    subject
+}
+
+// Splits the DER element at the start of `input` into its tag, its
+// contents and whatever follows it.
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&first, input) = input.split_first()?;
+    let (len, input) = match first {
+        0..=0x7f => (usize::from(first), input),
+        0x81 => (usize::from(*input.first()?), &input[1..]),
+        0x82 => (
+            usize::from(u16::from_be_bytes([*input.first()?, *input.get(1)?])),
+            input.get(2..)?,
+        ),
+        _ => return None,
+    };
+    if input.len() < len {
+        return None;
+    }
+    let (contents, rest) = input.split_at(len);
+    Some((tag, contents, rest))
+}
+
+fn invalid_data<E: Display>(path: &Path, e: E) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+}
@@ -0,0 +1,60 @@
+use std::borrow::Cow;
+use std::sync::Arc;
+
+/// Rewrites a chunk of received bytes before it is echoed back.
+///
+/// Transforms see each chunk exactly as it was read, not whole messages,
+/// except in `Mode::Line` and `Mode::Delimited`, which pass them each
+/// message without its line ending or delimiter and echo that after the
+/// output. They may return output of a different length than their input.
+/// Returning `Cow::Borrowed` avoids copying when the input is echoed
+/// unchanged.
+pub type Transform = dyn Fn(&[u8]) -> Cow<'_, [u8]> + Send + Sync;
+
+/// Names accepted by [`by_name`].
+pub const NAMES: &[&str] = &["identity", "uppercase", "lowercase", "reverse", "rot13"];
+
+/// Looks up one of the built-in transforms by name.
+pub fn by_name(name: &str) -> Option<Arc<Transform>> {
+    let transform: Arc<Transform> = match name {
+        "identity" => Arc::new(identity),
+        "uppercase" => Arc::new(|bytes: &[u8]| Cow::Owned(bytes.to_ascii_uppercase())),
+        "lowercase" => Arc::new(|bytes: &[u8]| Cow::Owned(bytes.to_ascii_lowercase())),
+        "reverse" => Arc::new(|bytes: &[u8]| Cow::Owned(bytes.iter().rev().copied().collect())),
+        "rot13" => Arc::new(|bytes: &[u8]| Cow::Owned(bytes.iter().map(|&b| rot13(b)).collect())),
+        _ => return None,
+    };
+    Some(transform)
+}
+
+/// Runs `transform` and reverses the bytes of its output, which is how
+/// `Mode::Reverse` echoes every chunk.
+pub fn reversed(transform: Arc<Transform>) -> Arc<Transform> {
+    Arc::new(move |bytes: &[u8]| Cow::Owned(transform(bytes).iter().rev().copied().collect()))
+}
+
+/// Echoes bytes back unchanged.
+pub fn identity(bytes: &[u8]) -> Cow<'_, [u8]> {
+    Cow::Borrowed(bytes)
+}
+
+fn rot13(byte: u8) -> u8 {
+    match byte {
+        b'a'..=b'z' => (byte - b'a' + 13) % 26 + b'a',
+        b'A'..=b'Z' => (byte - b'A' + 13) % 26 + b'A',
+        _ => byte,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reverse_reverses_the_whole_chunk() {
+        let reverse = by_name("reverse").unwrap();
+
+        assert_eq!(&*reverse(b"abc"), b"cba");
+        assert_eq!(&*reverse(b"abc\n"), b"\ncba");
+    }
+}
@@ -0,0 +1,251 @@
+use crate::connection::{delay_echo, read_full, truncated_frame, EchoError};
+use crate::http::{self, HeadLimits, Rejection, Request};
+use crate::transform::Transform;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use std::convert::TryFrom;
+use std::io::{self, BufRead, ErrorKind, Read, Write};
+use std::time::Duration;
+
+// Appended to the client's key before hashing it into `Sec-WebSocket-Accept`,
+// as fixed by RFC 6455.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const FIN: u8 = 0x80;
+const RESERVED: u8 = 0x70;
+const OPCODE: u8 = 0x0f;
+const MASKED: u8 = 0x80;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+const PROTOCOL_ERROR: u16 = 1002;
+const MESSAGE_TOO_BIG: u16 = 1009;
+
+/// Reads the HTTP Upgrade request that opens a WebSocket connection and
+/// answers it with `101 Switching Protocols`. Returns `false` if the peer
+/// closed the connection before sending a request.
+///
+/// A request that is not a valid version 13 handshake is answered with a
+/// `400`, one whose head exceeds `limits` with a `431`, and either fails
+/// with `ErrorKind::InvalidData`.
+pub fn accept<S: BufRead + Write>(
+    stream: &mut S,
+    request: &mut Vec<u8>,
+    limits: HeadLimits,
+) -> Result<bool, EchoError> {
+    request.clear();
+    let head_len =
+        match http::read_head(stream, request, limits.max_len).map_err(EchoError::Read)? {
+            None => return Ok(false),
+            Some(Ok(head_len)) => head_len,
+            Some(Err(rejection)) => return Err(http::reject(stream, rejection)),
+        };
+
+    let accept_key = match http::parse_request(&request[..head_len], limits.max_headers)
+        .and_then(|request| handshake_key(&request).map(accept_key))
+    {
+        Ok(accept_key) => accept_key,
+        Err(rejection) => return Err(http::reject(stream, rejection)),
+    };
+
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key
+    );
+    stream
+        .write_all(response.as_bytes())
+        .map_err(EchoError::Write)?;
+    Ok(true)
+}
+
+/// Reads one WebSocket frame from `stream` and answers it: data frames are
+/// echoed back unmasked with `transform`'s output as their payload after
+/// sleeping for `delay`, keeping their opcode and fragmentation, pings are
+/// answered with a pong and pongs are ignored. Returns the number of bytes
+/// read, or 0 once the peer's close frame has been answered or the peer
+/// closed the connection between frames.
+///
+/// A frame breaking the protocol or carrying more than `max_payload_size`
+/// bytes is answered with a close frame and fails with
+/// `ErrorKind::InvalidData`.
+pub fn echo_frame<S: Read + Write>(
+    stream: &mut S,
+    payload: &mut Vec<u8>,
+    max_payload_size: usize,
+    transform: &Transform,
+    delay: Duration,
+) -> Result<usize, EchoError> {
+    let mut header = [0u8; 2];
+    match read_full(stream, &mut header).map_err(EchoError::Read)? {
+        0 => return Ok(0),
+        2 => {}
+        read => return Err(EchoError::Read(truncated_frame(read))),
+    }
+    let mut read = header.len();
+
+    let opcode = header[0] & OPCODE;
+    if header[0] & RESERVED != 0 {
+        return Err(fail(stream, PROTOCOL_ERROR, "reserved bits are set"));
+    }
+    // Clients must mask every frame, so a proxy cannot be tricked into
+    // caching attacker-chosen bytes.
+    if header[1] & MASKED == 0 {
+        return Err(fail(stream, PROTOCOL_ERROR, "client frame is not masked"));
+    }
+
+    let len = match header[1] & !MASKED {
+        126 => {
+            let mut len = [0u8; 2];
+            read += read_part(stream, &mut len, read)?;
+            u64::from(u16::from_be_bytes(len))
+        }
+        127 => {
+            let mut len = [0u8; 8];
+            read += read_part(stream, &mut len, read)?;
+            u64::from_be_bytes(len)
+        }
+        len => u64::from(len),
+    };
+
+    let is_control = opcode & 0x8 != 0;
+    if is_control && (header[0] & FIN == 0 || len > 125) {
+        return Err(fail(
+            stream,
+            PROTOCOL_ERROR,
+            "control frames must fit in a single frame of at most 125 bytes",
+        ));
+    }
+    if len > max_payload_size as u64 {
+        let reason = format!(
+            "frame of {} bytes exceeds the limit of {} bytes",
+            len, max_payload_size
+        );
+        return Err(fail(stream, MESSAGE_TOO_BIG, &reason));
+    }
+
+    let mut mask = [0u8; 4];
+    read += read_part(stream, &mut mask, read)?;
+    payload.resize(len as usize, 0);
+    read += read_part(stream, payload, read)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % mask.len()];
+    }
+
+    match opcode {
+        CONTINUATION | TEXT | BINARY => {
+            delay_echo(delay);
+            write_frame(stream, header[0], &transform(payload))?;
+        }
+        PING => write_frame(stream, FIN | PONG, payload)?,
+        PONG => {}
+        CLOSE => {
+            // Answering with the peer's status code completes the closing
+            // handshake.
+            write_frame(stream, FIN | CLOSE, &payload[..payload.len().min(2)])?;
+            return Ok(0);
+        }
+        _ => return Err(fail(stream, PROTOCOL_ERROR, "unknown opcode")),
+    }
+    Ok(read)
+}
+
+fn handshake_key<'a>(request: &Request<'a>) -> Result<&'a str, Rejection> {
+    if request.method != "GET" || request.version != "HTTP/1.1" {
+        return Err(Rejection::bad_request(
+            "WebSocket handshake must be a GET over HTTP/1.1",
+        ));
+    }
+    if !request.has_token("upgrade", "websocket") || !request.has_token("connection", "upgrade") {
+        return Err(Rejection::bad_request("missing WebSocket upgrade headers"));
+    }
+    if request.header("sec-websocket-version") != Some("13") {
+        return Err(Rejection::bad_request("unsupported WebSocket version"));
+    }
+
+    request
+        .header("sec-websocket-key")
+        .filter(|key| !key.is_empty())
+        .ok_or_else(|| Rejection::bad_request("missing Sec-WebSocket-Key"))
+}
+
+fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(ACCEPT_GUID.as_bytes());
+    STANDARD.encode(hasher.finalize())
+}
+
+// Fills `buf`, failing with a truncated frame error that counts the `read`
+// bytes of the frame already consumed.
+fn read_part<R: Read>(reader: &mut R, buf: &mut [u8], read: usize) -> Result<usize, EchoError> {
+    let filled = read_full(reader, buf).map_err(EchoError::Read)?;
+    if filled < buf.len() {
+        return Err(EchoError::Read(truncated_frame(read + filled)));
+    }
+    Ok(filled)
+}
+
+// Server frames are never masked.
+fn write_frame<W: Write>(stream: &mut W, first: u8, payload: &[u8]) -> Result<(), EchoError> {
+    let len = payload.len();
+    let mut header = vec![first];
+    if len < 126 {
+        header.push(len as u8);
+    } else if let Ok(len) = u16::try_from(len) {
+        header.push(126);
+        header.extend_from_slice(&len.to_be_bytes());
+    } else {
+        header.push(127);
+        header.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+
+    stream
+        .write_all(&header)
+        .and_then(|()| stream.write_all(payload))
+        .map_err(EchoError::Write)
+}
+
+// Closes the connection with `code` after a protocol violation and reports
+// it as the error that ended the connection.
+fn fail<W: Write>(stream: &mut W, code: u16, reason: &str) -> EchoError {
+    let mut payload = code.to_be_bytes().to_vec();
+    // A close frame is a control frame, so its payload is capped at 125 bytes.
+    payload.extend(reason.bytes().take(123));
+    if let Err(e) = write_frame(stream, FIN | CLOSE, &payload) {
+        return e;
+    }
+
+    EchoError::Read(io::Error::new(
+        ErrorKind::InvalidData,
+        format!("closed WebSocket connection, {}", reason),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockStream;
+
+    #[test]
+    fn handshake_head_filling_the_limit_gets_a_431() {
+        let head = b"GET / HTTP/1.1\r\nUpgrade: websocket\r\n";
+        let limits = HeadLimits {
+            max_len: head.len(),
+            ..HeadLimits::default()
+        };
+        let mut stream = MockStream::new(&[head, b"Connection: Upgrade\r\n\r\n"]);
+
+        let result = accept(&mut stream, &mut Vec::new(), limits);
+
+        assert!(result.is_err());
+        assert!(stream
+            .output
+            .starts_with(b"HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+    }
+}
@@ -0,0 +1,134 @@
+//! Tests running the server binary the way a script would.
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Command, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_echo-server-rs"))
+        .args(args)
+        .output()
+        .expect("could not run the server binary")
+}
+
+// Checks that the server gave up with a message naming `needle` instead of
+// panicking.
+fn assert_clean_failure(output: &Output, needle: &str) {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains(needle), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+}
+
+#[test]
+fn port_in_use_fails_with_a_clean_error() {
+    let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = taken.local_addr().unwrap().port().to_string();
+
+    let output = run(&["--port", &port]);
+
+    assert_clean_failure(
+        &output,
+        &format!("Error: Could not bind to 127.0.0.1:{}", port),
+    );
+}
+
+#[test]
+fn privileged_port_without_permission_fails_with_a_clean_error() {
+    // Nothing to provoke when the tests themselves may bind the port.
+    if TcpListener::bind("127.0.0.1:1").is_ok() {
+        return;
+    }
+
+    let output = run(&["--port", "1"]);
+
+    assert_clean_failure(&output, "Error: Could not bind to 127.0.0.1:1");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("ermission denied"));
+}
+
+#[test]
+fn invalid_arguments_fail_with_a_clean_error() {
+    let output = run(&["--port", "not-a-port"]);
+
+    assert_clean_failure(&output, "Error: Invalid arguments:");
+}
+
+#[test]
+fn zero_buffer_size_is_rejected() {
+    let output = run(&["--buffer-size", "0"]);
+
+    assert_clean_failure(&output, "buffer size must be at least 1 byte");
+}
+
+#[test]
+fn zero_max_headers_is_rejected() {
+    let output = run(&["--max-headers", "0"]);
+
+    assert_clean_failure(&output, "max headers must be at least 1");
+}
+
+#[cfg(unix)]
+#[test]
+fn sigterm_shuts_the_server_down_cleanly() {
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut server = Command::new(env!("CARGO_BIN_EXE_echo-server-rs"))
+        .args(["--port", &port.to_string()])
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("could not run the server binary");
+
+    let started = Instant::now();
+    let mut client = loop {
+        match TcpStream::connect(("127.0.0.1", port)) {
+            Ok(client) => break client,
+            Err(_) if started.elapsed() < Duration::from_secs(5) => {
+                thread::sleep(Duration::from_millis(10))
+            }
+            Err(e) => panic!("the server never started listening: {}", e),
+        }
+    };
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    client.write_all(b"hello").unwrap();
+    let mut echoed = [0; 5];
+    client.read_exact(&mut echoed).unwrap();
+
+    let killed = Command::new("kill")
+        .args(["-TERM", &server.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(killed.success());
+    // Open connections are drained, so this one is still served until the
+    // client closes it.
+    thread::sleep(Duration::from_millis(100));
+    client.write_all(b"again").unwrap();
+    client.read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b"again");
+    drop(client);
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while server.try_wait().unwrap().is_none() {
+        assert!(Instant::now() < deadline, "the server did not exit");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let output = server.wait_with_output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr);
+    assert!(stderr.contains("shutting down"), "{}", stderr);
+    assert!(stderr.contains("Stopped: Echo Server!"), "{}", stderr);
+}
+
+#[test]
+fn unix_socket_and_port_cannot_be_combined() {
+    let output = run(&["--unix", "/tmp/echo.sock", "--port", "7878"]);
+
+    assert_clean_failure(&output, "--unix cannot be combined with --port");
+}
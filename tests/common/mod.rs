@@ -0,0 +1,139 @@
+//! Helpers shared by the integration tests.
+
+#![allow(dead_code)]
+
+use echo_server_rs::{span, Server, ServerConfigBuilder, ServerStats, Shutdown};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex, Once};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How long a test client waits for the server before failing.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A server running on a thread of its own, on an OS-assigned loopback
+/// port, which is shut down and joined when dropped.
+pub struct TestServer {
+    pub addr: SocketAddr,
+    pub stats: Arc<ServerStats>,
+    pub shutdown: Arc<Shutdown>,
+    thread: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl TestServer {
+    pub fn start(config: ServerConfigBuilder) -> TestServer {
+        let config = config.addr("127.0.0.1:0".parse().unwrap()).build();
+        TestServer::bind(Server::bind(config).expect("could not bind the test server"))
+    }
+
+    pub fn bind(server: Server) -> TestServer {
+        let addr = server.local_addr().unwrap();
+        let (stats, shutdown) = (server.stats(), server.shutdown());
+        TestServer {
+            addr,
+            stats,
+            shutdown,
+            thread: Some(thread::spawn(move || server.run())),
+        }
+    }
+
+    pub fn connect(&self) -> TcpStream {
+        connect(self.addr)
+    }
+
+    /// Requests shutdown and waits for `run` to return.
+    pub fn stop(mut self) -> io::Result<()> {
+        self.shutdown.request();
+        self.thread.take().unwrap().join().unwrap()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.shutdown.request();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+pub fn connect(addr: SocketAddr) -> TcpStream {
+    let stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    stream.set_write_timeout(Some(TIMEOUT)).unwrap();
+    stream
+}
+
+/// Sends `payload` and reads back as many bytes.
+pub fn round_trip(stream: &mut TcpStream, payload: &[u8]) -> Vec<u8> {
+    stream.write_all(payload).unwrap();
+    let mut echoed = vec![0; payload.len()];
+    stream.read_exact(&mut echoed).unwrap();
+    echoed
+}
+
+/// Reads until the server closes the connection.
+pub fn read_to_end(stream: &mut TcpStream) -> Vec<u8> {
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).unwrap();
+    received
+}
+
+/// Polls `condition` until it holds, failing the test after `TIMEOUT`.
+pub fn eventually<F: FnMut() -> bool>(what: &str, mut condition: F) {
+    let deadline = Instant::now() + TIMEOUT;
+    while !condition() {
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+static LOGGER: CaptureLogger = CaptureLogger {
+    lines: Mutex::new(Vec::new()),
+};
+static INSTALL: Once = Once::new();
+
+/// Collects every log line of the test binary, tagged like the server's
+/// own logger tags them, `LEVEL target conn=<id>] message`.
+struct CaptureLogger {
+    lines: Mutex<Vec<String>>,
+}
+
+impl Log for CaptureLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= Level::Debug
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        let mut line = format!("{} {}", record.level(), record.target());
+        if let Some(id) = span::current() {
+            line.push_str(&format!(" conn={}", id));
+        }
+        line.push_str(&format!("] {}", record.args()));
+        self.lines.lock().unwrap().push(line);
+    }
+
+    fn flush(&self) {}
+}
+
+/// Starts capturing log lines, if no test of this binary did yet.
+pub fn capture_logs() {
+    INSTALL.call_once(|| {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(LevelFilter::Debug);
+    });
+}
+
+/// The log lines captured so far that contain `needle`.
+pub fn logged(needle: &str) -> Vec<String> {
+    LOGGER
+        .lines
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|line| line.contains(needle))
+        .cloned()
+        .collect()
+}
@@ -0,0 +1,29 @@
+//! Tests that run the process out of file descriptors, which would fail
+//! whatever else ran alongside them, so they get a binary of their own.
+
+mod common;
+
+use common::{capture_logs, connect, eventually, logged, round_trip, TestServer};
+use echo_server_rs::{Runtime, ServerConfig};
+use std::fs::File;
+
+#[test]
+fn event_loop_accepts_a_waiting_connection_once_descriptors_free_up() {
+    capture_logs();
+    let server = TestServer::start(ServerConfig::builder().runtime(Runtime::EventLoop));
+
+    let mut hoard = Vec::new();
+    while let Ok(file) = File::open("/dev/null") {
+        hoard.push(file);
+    }
+    // Leaves one descriptor for the client, and none to accept it with.
+    hoard.pop();
+    let mut client = connect(server.addr);
+    eventually("the accept to fail", || {
+        !logged("Could not accept connections due to").is_empty()
+    });
+    drop(hoard);
+
+    // Nobody else connects, so only the retry can accept the client.
+    assert_eq!(round_trip(&mut client, b"hello"), b"hello");
+}
@@ -0,0 +1,358 @@
+//! Tests asserting on what the server logs, which share this binary's
+//! capturing logger.
+
+mod common;
+
+use common::{capture_logs, eventually, logged, round_trip, TestServer};
+use echo_server_rs::{pool, Mode, Runtime, ServerConfig};
+use socket2::SockRef;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn peer_address_appears_in_open_close_and_error_lines() {
+    capture_logs();
+    let server =
+        TestServer::start(ServerConfig::builder().read_timeout(Some(Duration::from_millis(200))));
+
+    let mut client = server.connect();
+    let peer = client.local_addr().unwrap().to_string();
+    assert_eq!(round_trip(&mut client, b"hello"), b"hello");
+    // Going quiet trips the read timeout, which ends the connection.
+    eventually("the connection to close", || {
+        !logged(&format!("Connection from {} closed after", peer)).is_empty()
+    });
+
+    assert_eq!(
+        logged(&format!("Accepted connection from {}", peer)).len(),
+        1
+    );
+    assert_eq!(
+        logged(&format!(
+            "Closing connection from {}, no data received within the read timeout",
+            peer
+        ))
+        .len(),
+        1
+    );
+    assert_eq!(
+        logged(&format!(
+            "Connection from {} closed after echoing 5 bytes",
+            peer
+        ))
+        .len(),
+        1
+    );
+}
+
+#[test]
+fn concurrent_connections_log_under_distinct_consistent_ids() {
+    capture_logs();
+    let server = TestServer::start(ServerConfig::builder());
+
+    let mut first = server.connect();
+    let mut second = server.connect();
+    let peers = [first.local_addr().unwrap(), second.local_addr().unwrap()];
+    for _ in 0..3 {
+        assert_eq!(round_trip(&mut first, b"one"), b"one");
+        assert_eq!(round_trip(&mut second, b"two"), b"two");
+    }
+    drop((first, second));
+    eventually("both connections to close", || {
+        peers
+            .iter()
+            .all(|peer| !logged(&format!("Connection from {} closed after", peer)).is_empty())
+    });
+
+    let ids: Vec<String> = peers
+        .iter()
+        .map(|peer| {
+            let lines = logged(&peer.to_string());
+            assert!(lines.len() >= 2, "{:?}", lines);
+            let id = |line: &String| {
+                let start = line.find(" conn=").expect("a line without an id") + 6;
+                line[start..].split(']').next().unwrap().to_string()
+            };
+            let ids: Vec<String> = lines.iter().map(id).collect();
+            assert!(ids.iter().all(|other| *other == ids[0]), "{:?}", lines);
+            ids[0].clone()
+        })
+        .collect();
+    assert_ne!(ids[0], ids[1]);
+}
+
+#[test]
+fn per_task_line_is_only_logged_when_turned_on() {
+    capture_logs();
+    let quiet = TestServer::start(ServerConfig::builder());
+    let mut client = quiet.connect();
+    assert_eq!(round_trip(&mut client, b"hello"), b"hello");
+    drop(client);
+    quiet.stop().unwrap();
+    assert!(logged("starts processing new request").is_empty());
+
+    let verbose = TestServer::start(ServerConfig::builder().log_tasks(true));
+    let mut client = verbose.connect();
+    assert_eq!(round_trip(&mut client, b"hello"), b"hello");
+    let lines = logged("starts processing new request");
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].starts_with(&format!("INFO {}", pool::TASK_LOG_TARGET)));
+}
+
+#[test]
+fn applied_buffer_sizes_are_logged_at_info() {
+    capture_logs();
+    // Only that the request goes through is portable; the kernel is free to
+    // round or double the size it applies.
+    let server = TestServer::start(
+        ServerConfig::builder()
+            .recv_buffer_size(1 << 20)
+            .send_buffer_size(1 << 20),
+    );
+    let mut client = server.connect();
+    assert_eq!(round_trip(&mut client, b"hello"), b"hello");
+
+    for option in &["SO_RCVBUF", "SO_SNDBUF"] {
+        let lines = logged(&format!("Requested {} {}, applied", option, 1 << 20));
+        assert_eq!(lines.len(), 1, "{:?}", lines);
+        assert!(lines[0].starts_with("INFO "), "{}", lines[0]);
+        assert!(logged(&format!("Could not configure {}", option)).is_empty());
+    }
+}
+
+#[test]
+fn client_that_stops_receiving_is_a_clean_disconnect() {
+    capture_logs();
+    let server = TestServer::start(ServerConfig::builder());
+    let mut client = server.connect();
+    let peer = client.local_addr().unwrap().to_string();
+
+    // Never reading the echoes fills up both directions until the server
+    // blocks writing one and this write times out.
+    client
+        .set_write_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    assert!(client.write_all(&vec![0; 64 << 20]).is_err());
+    // Closing with echoes left unread resets the connection under the
+    // server's pending write.
+    drop(client);
+
+    eventually("the connection to end", || server.stats.disconnects() == 1);
+    assert_eq!(server.stats.errors(), 0);
+    assert_eq!(
+        logged(&format!(
+            "Closing connection from {}, client stopped receiving echoes",
+            peer
+        ))
+        .len(),
+        1
+    );
+    let alarming: Vec<_> = logged(&peer)
+        .into_iter()
+        .filter(|line| line.starts_with("WARN") || line.starts_with("ERROR"))
+        .collect();
+    assert!(alarming.is_empty(), "{:?}", alarming);
+}
+
+#[test]
+fn connection_reset_mid_stream_is_logged_as_a_disconnect() {
+    capture_logs();
+    let server = TestServer::start(ServerConfig::builder());
+    let mut client = server.connect();
+    let peer = client.local_addr().unwrap().to_string();
+    assert_eq!(round_trip(&mut client, b"hello"), b"hello");
+
+    // Closing with a zero linger time sends a reset instead of a FIN.
+    SockRef::from(&client)
+        .set_linger(Some(Duration::ZERO))
+        .unwrap();
+    drop(client);
+
+    eventually("the reset to end the connection", || {
+        server.stats.disconnects() == 1
+    });
+    let lines = logged(&format!("Client {} disconnected abruptly", peer));
+    assert_eq!(lines.len(), 1, "{:?}", lines);
+    assert!(lines[0].starts_with("DEBUG "), "{}", lines[0]);
+    let alarming: Vec<_> = logged(&peer)
+        .into_iter()
+        .filter(|line| line.starts_with("WARN") || line.starts_with("ERROR"))
+        .collect();
+    assert!(alarming.is_empty(), "{:?}", alarming);
+    assert_eq!(server.stats.errors(), 0);
+}
+
+#[test]
+fn client_that_stops_reading_trips_the_write_timeout() {
+    capture_logs();
+    let server =
+        TestServer::start(ServerConfig::builder().write_timeout(Some(Duration::from_millis(200))));
+    let mut client = server.connect();
+    let peer = client.local_addr().unwrap().to_string();
+
+    // Sending without ever reading fills up both directions until the
+    // server's echo blocks, and then this write.
+    client
+        .set_write_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    assert!(client.write_all(&vec![0; 64 << 20]).is_err());
+
+    eventually("the write timeout to close the connection", || {
+        !logged(&format!(
+            "Closing connection from {}, client stopped reading and the write timed out",
+            peer
+        ))
+        .is_empty()
+    });
+    // A timeout is not mistaken for a failing socket.
+    assert!(logged(&format!(
+        "Stopping further processing of stream from {}",
+        peer
+    ))
+    .is_empty());
+}
+
+#[test]
+fn connection_over_its_byte_limit_is_closed_with_the_reason_logged() {
+    capture_logs();
+    let server = TestServer::start(ServerConfig::builder().max_connection_bytes(10));
+    let mut client = server.connect();
+    let peer = client.local_addr().unwrap().to_string();
+
+    assert_eq!(round_trip(&mut client, b"hello"), b"hello");
+    assert_eq!(round_trip(&mut client, b"world"), b"world");
+    // Nothing past the limit is echoed; whether the server's close reads as
+    // end of stream or a reset depends on when these bytes reached it.
+    let _ = client.write_all(b"more");
+    assert!(!matches!(client.read(&mut [0; 4]), Ok(read) if read > 0));
+
+    eventually("the connection to close", || server.stats.closed() == 1);
+    assert_eq!(server.stats.bytes_echoed(), 10);
+    assert_eq!(
+        logged(&format!(
+            "Closing connection from {}, it reached the limit of 10 echoed bytes",
+            peer
+        ))
+        .len(),
+        1
+    );
+}
+
+#[test]
+fn stats_reporter_totals_catch_up_with_the_connections_served() {
+    capture_logs();
+    let server =
+        TestServer::start(ServerConfig::builder().stats_interval(Some(Duration::from_millis(50))));
+
+    for _ in 0..3 {
+        let mut client = server.connect();
+        assert_eq!(round_trip(&mut client, b"hello"), b"hello");
+    }
+
+    eventually("a report of all three connections", || {
+        !logged(
+            "Stats: 3 connections accepted, 0 active, 3 closed, 0 disconnected, 0 failed, \
+             15 bytes echoed, 0 tasks pending",
+        )
+        .is_empty()
+    });
+}
+
+#[test]
+fn client_trickling_a_line_is_closed_by_the_idle_timeout() {
+    capture_logs();
+    let server = TestServer::start(
+        ServerConfig::builder()
+            .mode(Mode::Line)
+            .idle_timeout(Some(Duration::from_millis(300))),
+    );
+    let mut client = server.connect();
+    client.set_nodelay(true).unwrap();
+    let peer = client.local_addr().unwrap().to_string();
+
+    // A byte every 50ms satisfies any read timeout, but the line is never
+    // finished. Sending all of it would take two seconds.
+    let start = Instant::now();
+    let mut writer = client.try_clone().unwrap();
+    let trickling = thread::spawn(move || {
+        for _ in 0..40 {
+            if writer.write_all(b"x").is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    });
+    assert!(!matches!(client.read(&mut [0; 16]), Ok(read) if read > 0));
+    let closed_after = start.elapsed();
+    trickling.join().unwrap();
+
+    assert!(
+        closed_after >= Duration::from_millis(300) && closed_after < Duration::from_millis(1500),
+        "closed after {:?}",
+        closed_after
+    );
+    assert_eq!(
+        logged(&format!(
+            "Closing connection from {}, no complete read within the idle timeout",
+            peer
+        ))
+        .len(),
+        1
+    );
+}
+
+#[test]
+fn proxy_header_is_not_echoed_and_the_real_client_is_logged() {
+    capture_logs();
+    let server = TestServer::start(ServerConfig::builder().proxy_protocol(true));
+    let mut client = server.connect();
+
+    client
+        .write_all(b"PROXY TCP4 203.0.113.7 198.51.100.1 56324 443\r\nhello")
+        .unwrap();
+    let mut echoed = [0; 5];
+    client.read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b"hello");
+    assert_eq!(round_trip(&mut client, b" again"), b" again");
+    assert_eq!(
+        logged("Serving connection proxied for 203.0.113.7:56324").len(),
+        1
+    );
+
+    let mut malformed = server.connect();
+    let peer = malformed.local_addr().unwrap().to_string();
+    malformed
+        .write_all(b"PROXY TCP9 nonsense\r\nhello")
+        .unwrap();
+    assert!(!matches!(malformed.read(&mut [0; 5]), Ok(read) if read > 0));
+    eventually("the malformed header to be logged", || {
+        logged(&format!(
+            "Closing connection from {}, invalid PROXY protocol header",
+            peer
+        ))
+        .iter()
+        .any(|line| line.starts_with("ERROR "))
+    });
+}
+
+#[test]
+fn refused_connection_is_not_logged_as_accepted() {
+    capture_logs();
+    for runtime in [Runtime::Threads, Runtime::EventLoop] {
+        let server = TestServer::start(ServerConfig::builder().runtime(runtime).max_connections(1));
+        let mut admitted = server.connect();
+        assert_eq!(round_trip(&mut admitted, b"hello"), b"hello");
+
+        let mut refused = server.connect();
+        assert!(!matches!(refused.read(&mut [0; 1]), Ok(read) if read > 0));
+
+        let accepted = |client: &TcpStream| {
+            let peer = client.local_addr().unwrap();
+            logged(&format!("Accepted connection from {}", peer)).len()
+        };
+        assert_eq!(accepted(&admitted), 1, "{:?}", runtime);
+        assert_eq!(accepted(&refused), 0, "{:?}", runtime);
+    }
+}
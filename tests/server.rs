@@ -0,0 +1,1104 @@
+//! End-to-end tests of serving over real sockets.
+
+mod common;
+
+use common::{eventually, read_to_end, round_trip, TestServer};
+use echo_server_rs::socket::ListenerOptions;
+use echo_server_rs::{
+    BenchConfig, EchoHandler, Mode, Protocol, Runtime, Server, ServerConfig, ShutdownMode,
+};
+use flate2::write::GzDecoder;
+use ipnet::IpNet;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+#[test]
+fn servers_with_reuse_port_share_a_port() {
+    let options = ListenerOptions {
+        reuse_port: true,
+        ..ListenerOptions::default()
+    };
+    let bind = |addr: SocketAddr| {
+        let config = ServerConfig::builder()
+            .addr(addr)
+            .listener(options.clone())
+            .build();
+        TestServer::bind(Server::bind(config).expect("could not bind with SO_REUSEPORT"))
+    };
+    let first = bind("127.0.0.1:0".parse().unwrap());
+    let second = bind(first.addr);
+    assert_eq!(second.addr, first.addr);
+
+    // The kernel picks a listener per connection by hashing its addresses,
+    // so keep connecting until both got one.
+    eventually("both servers to accept a connection", || {
+        let mut client = first.connect();
+        assert_eq!(round_trip(&mut client, b"hello"), b"hello");
+        first.stats.accepted() > 0 && second.stats.accepted() > 0
+    });
+}
+
+#[test]
+fn echo_delay_holds_back_every_echo() {
+    let delay = Duration::from_millis(100);
+    let server = TestServer::start(ServerConfig::builder().echo_delay(delay));
+    let mut client = server.connect();
+
+    for _ in 0..2 {
+        let sent = Instant::now();
+        assert_eq!(round_trip(&mut client, b"hello"), b"hello");
+        assert!(sent.elapsed() >= delay, "echoed after {:?}", sent.elapsed());
+    }
+}
+
+#[test]
+fn echo_jitter_lands_every_round_trip_within_its_range_on_top_of_the_delay() {
+    let delay = Duration::from_millis(20);
+    let (min, max) = (Duration::from_millis(40), Duration::from_millis(60));
+    let server = TestServer::start(
+        ServerConfig::builder()
+            .echo_delay(delay)
+            .echo_jitter(min, max),
+    );
+    let mut client = server.connect();
+
+    for _ in 0..10 {
+        let sent = Instant::now();
+        assert_eq!(round_trip(&mut client, b"hello"), b"hello");
+        let elapsed = sent.elapsed();
+        // Loopback and scheduling add a little on top of the upper bound.
+        assert!(elapsed >= delay + min, "echoed after {:?}", elapsed);
+        assert!(elapsed < delay + max * 2, "echoed after {:?}", elapsed);
+    }
+}
+
+// Counts the chunks it rot13s, to show a handler keeps state per connection.
+#[derive(Default)]
+struct Rot13 {
+    chunks: usize,
+}
+
+impl EchoHandler for Rot13 {
+    fn process(&mut self, input: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        self.chunks += 1;
+        out.extend(input.iter().map(|&byte| match byte {
+            b'a'..=b'z' => (byte - b'a' + 13) % 26 + b'a',
+            b'A'..=b'Z' => (byte - b'A' + 13) % 26 + b'A',
+            _ => byte,
+        }));
+        out.extend_from_slice(format!(" #{}", self.chunks).as_bytes());
+        Ok(())
+    }
+}
+
+#[test]
+fn custom_handler_transforms_the_echo() {
+    let server =
+        TestServer::start(ServerConfig::builder().handler(Arc::new(|| Box::new(Rot13::default()))));
+
+    // Every connection starts counting afresh.
+    for _ in 0..2 {
+        let mut client = server.connect();
+        for (sent, echoed) in &[(&b"Hello"[..], &b"Uryyb #1"[..]), (b"abc", b"nop #2")] {
+            client.write_all(sent).unwrap();
+            let mut received = vec![0; echoed.len()];
+            client.read_exact(&mut received).unwrap();
+            assert_eq!(&received[..], *echoed);
+        }
+    }
+}
+
+#[test]
+fn port_zero_resolves_to_the_assigned_port_before_serving_starts() {
+    let config = ServerConfig::builder()
+        .addr("127.0.0.1:0".parse().unwrap())
+        .build();
+    let server = Server::bind(config).unwrap();
+    let addr = server.local_addr().unwrap();
+    assert_ne!(addr.port(), 0);
+
+    // The listener is bound already, so this waits in its backlog until
+    // the accept loop runs.
+    let mut client = common::connect(addr);
+    let server = TestServer::bind(server);
+    assert_eq!(round_trip(&mut client, b"hello"), b"hello");
+    drop(client);
+    server.stop().unwrap();
+}
+
+#[test]
+fn banner_is_read_first_and_echoing_follows() {
+    let server = TestServer::start(ServerConfig::builder().banner(String::from("welcome to echo")));
+    let mut client = server.connect();
+
+    let mut banner = [0; 17];
+    client.read_exact(&mut banner).unwrap();
+    assert_eq!(&banner, b"welcome to echo\r\n");
+    assert_eq!(round_trip(&mut client, b"hello"), b"hello");
+}
+
+#[test]
+fn half_closed_client_reads_every_echo_and_then_a_clean_end_of_stream() {
+    let server = TestServer::start(ServerConfig::builder());
+    let mut client = server.connect();
+
+    client.write_all(b"last words").unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+
+    // Reading to the end fails on a reset, so this only passes once the
+    // server shut down its own write side after the echo.
+    assert_eq!(read_to_end(&mut client), b"last words");
+    eventually("the connection to count as closed", || {
+        server.stats.closed() == 1
+    });
+}
+
+#[test]
+fn large_payload_is_echoed_byte_for_byte_under_backpressure() {
+    // A small send buffer makes the server's writes partial.
+    let server = TestServer::start(ServerConfig::builder().send_buffer_size(4096));
+    let mut client = server.connect();
+    let payload: Vec<u8> = (0..=255).cycle().take(2 << 20).collect();
+
+    let mut writer = client.try_clone().unwrap();
+    let sent = payload.clone();
+    let sending = thread::spawn(move || {
+        writer.write_all(&sent).unwrap();
+        writer.shutdown(Shutdown::Write).unwrap();
+    });
+    let echoed = read_to_end(&mut client);
+    sending.join().unwrap();
+
+    assert_eq!(echoed.len(), payload.len());
+    assert!(echoed == payload, "the echo differs from what was sent");
+}
+
+#[test]
+fn framed_mode_joins_a_frame_sent_in_two_writes() {
+    let server = TestServer::start(
+        ServerConfig::builder()
+            .mode(Mode::Framed)
+            .max_frame_size(16),
+    );
+    let mut client = server.connect();
+    client.set_nodelay(true).unwrap();
+
+    client.write_all(&5u32.to_be_bytes()).unwrap();
+    thread::sleep(Duration::from_millis(20));
+    client.write_all(b"hello").unwrap();
+    let mut echoed = [0; 9];
+    client.read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b"\0\0\0\x05hello");
+
+    // A frame over the limit closes the connection unanswered.
+    client.write_all(&17u32.to_be_bytes()).unwrap();
+    assert!(read_to_end(&mut client).is_empty());
+    eventually("the connection to count as failed", || {
+        server.stats.errors() == 1
+    });
+}
+
+#[test]
+fn line_mode_echoes_two_lines_sent_in_one_write_as_separate_lines() {
+    let server = TestServer::start(ServerConfig::builder().mode(Mode::Line));
+    let client = server.connect();
+    let mut lines = BufReader::new(client.try_clone().unwrap());
+
+    (&client).write_all(b"first line\nsecond line\n").unwrap();
+    for expected in &[&b"first line\n"[..], b"second line\n"] {
+        let mut line = Vec::new();
+        lines.read_until(b'\n', &mut line).unwrap();
+        assert_eq!(&line[..], *expected);
+    }
+
+    // A trailing partial line still comes back once the client is done.
+    (&client).write_all(b"no newline").unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    let mut rest = Vec::new();
+    lines.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest, b"no newline");
+}
+
+#[test]
+fn nul_delimited_messages_sent_together_are_echoed_one_by_one() {
+    let server = TestServer::start(
+        ServerConfig::builder()
+            .mode(Mode::Delimited)
+            .delimiter(0)
+            .max_frame_size(16),
+    );
+    let client = server.connect();
+    let mut messages = BufReader::new(client.try_clone().unwrap());
+
+    (&client).write_all(b"first\0second\0").unwrap();
+    for expected in &[&b"first\0"[..], b"second\0"] {
+        let mut message = Vec::new();
+        messages.read_until(0, &mut message).unwrap();
+        assert_eq!(&message[..], *expected);
+    }
+
+    // A message running past the limit without a delimiter closes the
+    // connection.
+    (&client).write_all(&[b'x'; 17]).unwrap();
+    let mut rest = Vec::new();
+    let _ = messages.read_to_end(&mut rest);
+    assert!(rest.is_empty());
+    eventually("the connection to count as failed", || {
+        server.stats.errors() == 1
+    });
+}
+
+#[test]
+fn checksum_mode_appends_the_reference_crc32_of_the_chunk() {
+    let server = TestServer::start(ServerConfig::builder().mode(Mode::Checksum));
+    let mut client = server.connect();
+
+    client.write_all(b"123456789").unwrap();
+    let mut echoed = [0; 13];
+    client.read_exact(&mut echoed).unwrap();
+
+    assert_eq!(&echoed[..9], b"123456789");
+    // The CRC-32 check value, as published with the algorithm's parameters.
+    assert_eq!(echoed[9..], 0xCBF4_3926u32.to_be_bytes());
+}
+
+#[test]
+fn gzip_mode_echo_decompresses_to_the_input_as_it_arrives() {
+    let server = TestServer::start(ServerConfig::builder().mode(Mode::Gzip));
+    let mut client = server.connect();
+    let mut decoder = GzDecoder::new(Vec::new());
+
+    // Every chunk's echo is flushed, so it decodes before the stream ends.
+    let mut sent = Vec::new();
+    for chunk in &[&b"hello, gzip"[..], b" and again"] {
+        client.write_all(chunk).unwrap();
+        sent.extend_from_slice(chunk);
+        let mut compressed = [0; 256];
+        while decoder.get_ref().len() < sent.len() {
+            let read = client.read(&mut compressed).unwrap();
+            assert_ne!(read, 0, "the echo ended early");
+            decoder.write_all(&compressed[..read]).unwrap();
+            decoder.flush().unwrap();
+        }
+        assert_eq!(decoder.get_ref(), &sent);
+    }
+
+    client.shutdown(Shutdown::Write).unwrap();
+    decoder.write_all(&read_to_end(&mut client)).unwrap();
+    assert_eq!(decoder.finish().unwrap(), sent);
+}
+
+// Needs a file descriptor limit of twice the connection count, since both
+// ends of every connection live in this process. Run it with
+// `cargo test --release -- --ignored`.
+#[cfg(target_os = "linux")]
+#[test]
+#[ignore]
+fn event_loop_holds_thousands_of_connections_on_a_few_threads() {
+    const CONNECTIONS: usize = 5_000;
+    let server = TestServer::start(ServerConfig::builder().runtime(Runtime::EventLoop));
+
+    let mut clients: Vec<_> = (0..CONNECTIONS)
+        .map(|i| {
+            let mut client = server.connect();
+            let payload = i.to_string();
+            assert_eq!(
+                round_trip(&mut client, payload.as_bytes()),
+                payload.as_bytes()
+            );
+            client
+        })
+        .collect();
+    assert_eq!(server.stats.active(), CONNECTIONS as u64);
+
+    // A thread per connection would put thousands of stacks here.
+    let status = std::fs::read_to_string("/proc/self/status").unwrap();
+    let threads: usize = status
+        .lines()
+        .find_map(|line| line.strip_prefix("Threads:"))
+        .and_then(|count| count.trim().parse().ok())
+        .unwrap();
+    assert!(
+        threads < 100,
+        "{} threads for {} connections",
+        threads,
+        CONNECTIONS
+    );
+
+    // Every connection is still served after the others were opened.
+    for client in &mut clients {
+        assert_eq!(round_trip(client, b"again"), b"again");
+    }
+    drop(clients);
+    eventually("every connection to close", || server.stats.active() == 0);
+}
+
+// Median time for sending two bytes a millisecond apart and reading both
+// echoes back, from a server that echoes each after 5ms. The second echo
+// goes out while the first is still unacknowledged, since the client has no
+// data of its own to carry the ACK, which is what Nagle's algorithm holds
+// back.
+fn two_echo_round_trip(nodelay: bool) -> Duration {
+    let server = TestServer::start(
+        ServerConfig::builder()
+            .nodelay(nodelay)
+            .echo_delay(Duration::from_millis(5)),
+    );
+    let mut client = server.connect();
+    client.set_nodelay(true).unwrap();
+    let mut times: Vec<_> = (0..10)
+        .map(|_| {
+            let start = Instant::now();
+            client.write_all(b"a").unwrap();
+            thread::sleep(Duration::from_millis(1));
+            client.write_all(b"b").unwrap();
+            let mut echoed = [0; 2];
+            client.read_exact(&mut echoed).unwrap();
+            start.elapsed()
+        })
+        .collect();
+    times.sort();
+    times[times.len() / 2]
+}
+
+// On Linux loopback the medians come out around 10ms with `TCP_NODELAY` and
+// 48ms without, the second echo waiting out the client's delayed ACK.
+#[cfg(target_os = "linux")]
+#[test]
+fn nodelay_keeps_a_second_echo_from_waiting_for_an_ack() {
+    let on = two_echo_round_trip(true);
+    let off = two_echo_round_trip(false);
+
+    assert!(
+        on + Duration::from_millis(20) < off,
+        "{:?} with nodelay, {:?} without",
+        on,
+        off
+    );
+}
+
+#[test]
+fn silent_client_is_closed_once_the_read_timeout_passes() {
+    let timeout = Duration::from_millis(200);
+    let server = TestServer::start(ServerConfig::builder().read_timeout(Some(timeout)));
+    let mut client = server.connect();
+
+    let connected = Instant::now();
+    assert!(read_to_end(&mut client).is_empty());
+    let elapsed = connected.elapsed();
+
+    assert!(elapsed >= timeout, "closed after {:?}", elapsed);
+    assert!(elapsed < timeout * 5, "closed after {:?}", elapsed);
+}
+
+#[test]
+fn udp_echoes_each_datagram_whole_and_truncates_oversized_ones() {
+    let server = TestServer::start(
+        ServerConfig::builder()
+            .protocol(Protocol::Udp)
+            .buffer_size(8),
+    );
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(common::TIMEOUT)).unwrap();
+    client.connect(server.addr).unwrap();
+    let mut received = [0; 64];
+
+    for datagram in &[&b"hello"[..], b"world!"] {
+        client.send(datagram).unwrap();
+        let len = client.recv(&mut received).unwrap();
+        assert_eq!(&received[..len], *datagram);
+    }
+    client.send(b"longer than eight").unwrap();
+    let len = client.recv(&mut received).unwrap();
+    assert_eq!(&received[..len], b"longer t");
+}
+
+#[test]
+fn connections_beyond_the_limit_are_turned_away() {
+    let server = TestServer::start(
+        ServerConfig::builder()
+            .max_connections(2)
+            .busy_message(String::from("server at capacity")),
+    );
+    let mut admitted: Vec<_> = (0..2).map(|_| server.connect()).collect();
+    for client in &mut admitted {
+        assert_eq!(round_trip(client, b"hello"), b"hello");
+    }
+
+    for _ in 0..3 {
+        let mut excess = server.connect();
+        let turned_away = Instant::now();
+        assert_eq!(read_to_end(&mut excess), b"server at capacity\r\n");
+        assert!(turned_away.elapsed() < Duration::from_secs(1));
+    }
+
+    // Closing one frees its place for the next client.
+    drop(admitted.pop());
+    eventually("the closed connection to end", || {
+        server.stats.active() == 1
+    });
+    let mut next = server.connect();
+    assert_eq!(round_trip(&mut next, b"hello"), b"hello");
+}
+
+#[test]
+fn connections_beyond_the_per_address_limit_are_closed() {
+    let server = TestServer::start(ServerConfig::builder().max_connections_per_ip(2));
+    let mut admitted: Vec<_> = (0..2).map(|_| server.connect()).collect();
+    for client in &mut admitted {
+        assert_eq!(round_trip(client, b"hello"), b"hello");
+    }
+
+    let mut excess = server.connect();
+    let _ = excess.write_all(b"hello");
+    assert!(!matches!(excess.read(&mut [0; 5]), Ok(read) if read > 0));
+
+    drop(admitted.pop());
+    eventually("the closed connection to end", || {
+        server.stats.active() == 1
+    });
+    let mut next = server.connect();
+    assert_eq!(round_trip(&mut next, b"hello"), b"hello");
+}
+
+#[test]
+fn connections_faster_than_the_rate_limit_are_closed() {
+    let server = TestServer::start(ServerConfig::builder().max_connection_rate(5));
+
+    let start = Instant::now();
+    let clients: Vec<_> = (0..30).map(|_| server.connect()).collect();
+    let window = start.elapsed();
+    let echoed = clients
+        .iter()
+        .filter(|client| {
+            let mut client: &TcpStream = client;
+            // Rejected connections are closed without reading, so writing
+            // to them either fails or is answered by a reset or end of stream.
+            client.write_all(b"x").is_ok() && matches!(client.read(&mut [0]), Ok(1))
+        })
+        .count();
+
+    // A full bucket admits a burst of one second's worth of connections, and
+    // it refills at the steady rate while the rest are being opened.
+    let refilled = (window.as_secs_f64() * 5.0).ceil() as usize;
+    assert!(
+        (5..=5 + refilled).contains(&echoed),
+        "{} of 30 connections were admitted in {:?}",
+        echoed,
+        window
+    );
+    assert_eq!(server.stats.accepted(), echoed as u64);
+}
+
+#[test]
+fn loopback_peer_in_the_denylist_is_refused() {
+    let loopback: IpNet = "127.0.0.0/8".parse().unwrap();
+    let refused = |server: &TestServer| {
+        let mut client = server.connect();
+        // A refused connection is closed without reading what was sent.
+        let _ = client.write_all(b"hello");
+        !matches!(client.read(&mut [0; 5]), Ok(read) if read > 0)
+    };
+
+    let denied = TestServer::start(ServerConfig::builder().deny(loopback));
+    assert!(refused(&denied));
+    // Denying takes precedence over allowing the same peer.
+    let both = TestServer::start(
+        ServerConfig::builder()
+            .allow(loopback)
+            .deny("127.0.0.1/32".parse().unwrap()),
+    );
+    assert!(refused(&both));
+    let elsewhere = TestServer::start(ServerConfig::builder().allow("10.0.0.0/8".parse().unwrap()));
+    assert!(refused(&elsewhere));
+    for server in &[denied, both, elsewhere] {
+        assert_eq!(server.stats.accepted(), 0);
+    }
+
+    let allowed = TestServer::start(ServerConfig::builder().allow(loopback));
+    let mut client = allowed.connect();
+    assert_eq!(round_trip(&mut client, b"hello"), b"hello");
+}
+
+#[test]
+fn throttled_connections_are_each_held_to_the_byte_rate() {
+    const RATE: u64 = 10_000;
+    const PAYLOAD: usize = 3_000;
+    let server = TestServer::start(ServerConfig::builder().max_bytes_per_second(RATE));
+    let min = Duration::from_secs_f64(PAYLOAD as f64 / RATE as f64);
+
+    // Reading to the end includes the sleep after the last echo, which the
+    // server finishes before it sees the client's end of stream.
+    let start = Instant::now();
+    let clients: Vec<_> = (0..2)
+        .map(|_| {
+            let mut client = server.connect();
+            thread::spawn(move || {
+                client.write_all(&[b'x'; PAYLOAD]).unwrap();
+                client.shutdown(Shutdown::Write).unwrap();
+                assert_eq!(read_to_end(&mut client).len(), PAYLOAD);
+                start.elapsed()
+            })
+        })
+        .collect();
+    for client in clients {
+        let elapsed = client.join().unwrap();
+        assert!(elapsed >= min, "echoed in {:?}", elapsed);
+    }
+
+    // A cap shared by both would have taken twice as long.
+    assert!(start.elapsed() < min * 2 - Duration::from_millis(50));
+}
+
+#[test]
+fn on_accept_fires_once_per_connection_and_a_panicking_callback_is_contained() {
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&accepted);
+    let server = TestServer::start(
+        ServerConfig::builder()
+            .on_accept(Arc::new(move |_: &SocketAddr| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }))
+            .on_close(Arc::new(|peer: &SocketAddr| {
+                panic!("on_close failed for {}", peer)
+            })),
+    );
+
+    for _ in 0..3 {
+        let mut client = server.connect();
+        assert_eq!(round_trip(&mut client, b"hello"), b"hello");
+    }
+    // Every closed connection panicked in on_close, and the workers still
+    // serve the next one.
+    eventually("the connections to close", || server.stats.closed() == 3);
+    let mut client = server.connect();
+    assert_eq!(round_trip(&mut client, b"hello"), b"hello");
+
+    assert_eq!(accepted.load(Ordering::SeqCst), 4);
+}
+
+// Starts a server in `mode` that takes 300ms for every echo, has a client
+// send it a message and requests shutdown while the echo is in flight.
+// Returns the client and the thread waiting for the server to stop.
+fn shut_down_mid_echo(mode: ShutdownMode) -> (TcpStream, JoinHandle<io::Result<()>>) {
+    let server = TestServer::start(
+        ServerConfig::builder()
+            .shutdown_mode(mode)
+            .echo_delay(Duration::from_millis(300)),
+    );
+    let mut client = server.connect();
+    client.write_all(b"slow").unwrap();
+    eventually("the echo to start", || server.stats.active() == 1);
+    // Once the server is asleep in the delay the read has happened.
+    thread::sleep(Duration::from_millis(50));
+    (client, thread::spawn(move || server.stop()))
+}
+
+#[test]
+fn drain_lets_an_in_flight_echo_finish_before_stopping() {
+    let (mut client, stopping) = shut_down_mid_echo(ShutdownMode::Drain);
+
+    let mut echoed = [0; 4];
+    client.read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b"slow");
+    // The connection is still open, so the server keeps waiting for it.
+    thread::sleep(Duration::from_millis(50));
+    assert!(!stopping.is_finished());
+
+    drop(client);
+    stopping.join().unwrap().unwrap();
+}
+
+#[test]
+fn immediate_shutdown_closes_an_in_flight_echo() {
+    let (mut client, stopping) = shut_down_mid_echo(ShutdownMode::Immediate);
+
+    let mut echoed = Vec::new();
+    let _ = client.read_to_end(&mut echoed);
+    assert!(echoed.is_empty());
+    stopping.join().unwrap().unwrap();
+}
+
+// Echoes what it reads, unless that is `panic`.
+struct PanicOnRequest;
+
+impl EchoHandler for PanicOnRequest {
+    fn process(&mut self, input: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        assert_ne!(input, b"panic", "handler failed");
+        out.extend_from_slice(input);
+        Ok(())
+    }
+}
+
+#[test]
+fn connection_that_panics_gives_its_place_back() {
+    let server = TestServer::start(
+        ServerConfig::builder()
+            .max_connections(1)
+            .handler(Arc::new(|| Box::new(PanicOnRequest))),
+    );
+
+    for _ in 0..3 {
+        let mut client = server.connect();
+        client.write_all(b"panic").unwrap();
+        assert!(read_to_end(&mut client).is_empty());
+    }
+    // Only admitted once every panicking connection gave its place back.
+    eventually("the panicking connections to end", || {
+        server.stats.active() == 0
+    });
+    let mut client = server.connect();
+    assert_eq!(round_trip(&mut client, b"hello"), b"hello");
+}
+
+#[cfg(unix)]
+#[test]
+fn unix_socket_replaces_a_stale_file_echoes_and_is_removed_on_shutdown() {
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    let path = std::env::temp_dir().join(format!("echo-server-rs-{}.sock", std::process::id()));
+    // A socket file left behind by a server that did not shut down cleanly.
+    drop(UnixListener::bind(&path).unwrap());
+    assert!(path.exists());
+
+    let server = Server::bind(ServerConfig::builder().unix(path.clone()).build()).unwrap();
+    let shutdown = server.shutdown();
+    let serving = thread::spawn(move || server.run());
+
+    let mut client = UnixStream::connect(&path).unwrap();
+    client.set_read_timeout(Some(common::TIMEOUT)).unwrap();
+    client.write_all(b"hello").unwrap();
+    let mut echoed = [0; 5];
+    client.read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b"hello");
+    drop(client);
+
+    shutdown.request();
+    serving.join().unwrap().unwrap();
+    assert!(!path.exists());
+}
+
+#[test]
+fn recorded_session_is_appended_to_the_file_with_its_peer_in_the_header() {
+    let path = std::env::temp_dir().join(format!("echo-server-rs-{}.rec", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let server = TestServer::start(ServerConfig::builder().record(path.clone()));
+
+    let mut client = server.connect();
+    let peer = client.local_addr().unwrap();
+    assert_eq!(round_trip(&mut client, b"hello"), b"hello");
+    assert_eq!(round_trip(&mut client, b" recorder"), b" recorder");
+    drop(client);
+
+    // The block is written when the server is done with the connection.
+    let mut recorded = String::new();
+    eventually("the session to be recorded", || {
+        recorded = std::fs::read_to_string(&path).unwrap_or_default();
+        !recorded.is_empty()
+    });
+    server.stop().unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let (header, bytes) = recorded.split_once('\n').unwrap();
+    assert!(header.starts_with("=== connection "), "{}", header);
+    assert!(
+        header.contains(&format!(" from {} at ", peer)),
+        "{}",
+        header
+    );
+    assert!(header.ends_with(", 14 bytes ==="), "{}", header);
+    assert_eq!(bytes, "hello recorder\n");
+}
+
+#[test]
+fn busy_connection_is_closed_once_it_reaches_its_maximum_lifetime() {
+    let lifetime = Duration::from_millis(300);
+    let server = TestServer::start(ServerConfig::builder().max_lifetime(Some(lifetime)));
+    // Taken before connecting, so the server's clock starts after it.
+    let connecting = Instant::now();
+    let mut client = server.connect();
+
+    let mut echoes = 0;
+    let closed_after = loop {
+        let _ = client.write_all(b"still here");
+        let mut echoed = [0; 10];
+        match client.read_exact(&mut echoed) {
+            Ok(()) => echoes += 1,
+            Err(_) => break connecting.elapsed(),
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    assert!(echoes > 5, "only {} echoes before the close", echoes);
+    assert!(closed_after >= lifetime, "closed after {:?}", closed_after);
+    assert!(
+        closed_after < lifetime * 4,
+        "closed after {:?}",
+        closed_after
+    );
+}
+
+#[test]
+fn discard_mode_reads_everything_and_echoes_nothing() {
+    let server = TestServer::start(ServerConfig::builder().mode(Mode::Discard));
+    let mut client = server.connect();
+
+    client.write_all(b"into the void").unwrap();
+    client.write_all(&[b'x'; 64 * 1024]).unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+
+    assert_eq!(read_to_end(&mut client), b"");
+}
+
+#[test]
+fn chargen_mode_streams_the_rotating_pattern_until_the_client_leaves() {
+    let server = TestServer::start(ServerConfig::builder().mode(Mode::Chargen));
+    let mut client = server.connect();
+    // Input is ignored rather than echoed into the pattern.
+    client.write_all(b"ignored").unwrap();
+
+    // Line `n` is 72 printable characters starting at the `n`-th, wrapping
+    // around after `~`, so line 95 repeats line 0.
+    let expected: Vec<u8> = (0..100)
+        .flat_map(|line| {
+            (0..72)
+                .map(move |column| b' ' + ((line + column) % 95) as u8)
+                .chain(*b"\r\n")
+        })
+        .collect();
+    let mut received = vec![0; expected.len()];
+    client.read_exact(&mut received).unwrap();
+    assert_eq!(
+        &received[..74],
+        &b" !\"#$%&'()*+,-./0123456789:;<=>?@ABCDEFGHIJKLMNOPQRSTUVWXYZ[\\]^_`abcdefg\r\n"[..]
+    );
+    assert_eq!(received, expected);
+
+    drop(client);
+    eventually("the generator to stop once the client left", || {
+        server.stats.active() == 0
+    });
+}
+
+// Echoes once and waits for the server to close the connection after its
+// read timeout, returning what the client's next read saw.
+fn closed_by_the_server(linger: Option<Duration>) -> io::Result<usize> {
+    let mut builder = ServerConfig::builder().read_timeout(Some(Duration::from_millis(100)));
+    if let Some(linger) = linger {
+        builder = builder.linger(linger);
+    }
+    let server = TestServer::start(builder);
+    let mut client = server.connect();
+    assert_eq!(round_trip(&mut client, b"hello"), b"hello");
+    client.read(&mut [0; 16])
+}
+
+#[test]
+fn zero_linger_resets_the_connection_instead_of_closing_it() {
+    assert_eq!(closed_by_the_server(None).unwrap(), 0);
+    let reset = closed_by_the_server(Some(Duration::ZERO)).unwrap_err();
+    assert_eq!(reset.kind(), io::ErrorKind::ConnectionReset);
+}
+
+#[test]
+fn connection_queued_past_the_queue_timeout_is_closed_without_an_echo() {
+    let server = TestServer::start(
+        ServerConfig::builder()
+            .pool_size(1)
+            .queue_timeout(Some(Duration::from_millis(100))),
+    );
+    // Holds the only worker until it leaves.
+    let mut busy = server.connect();
+    assert_eq!(round_trip(&mut busy, b"hello"), b"hello");
+
+    let mut queued = server.connect();
+    queued.write_all(b"anyone there?").unwrap();
+    thread::sleep(Duration::from_millis(300));
+    drop(busy);
+
+    // Dropped with its bytes unread, so it may be reset rather than closed.
+    assert!(!matches!(queued.read(&mut [0; 16]), Ok(read) if read > 0));
+    let mut fresh = server.connect();
+    assert_eq!(round_trip(&mut fresh, b"hello"), b"hello");
+}
+
+#[test]
+fn trickling_client_below_the_minimum_throughput_is_disconnected() {
+    let window = Duration::from_millis(200);
+    let server = TestServer::start(
+        ServerConfig::builder()
+            .min_throughput(100)
+            .min_throughput_window(window),
+    );
+
+    // Well above 100 bytes per window, so it outlasts several windows.
+    let mut fast = server.connect();
+    for _ in 0..12 {
+        assert_eq!(round_trip(&mut fast, &[b'f'; 64]), &[b'f'; 64][..]);
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    // A byte every 20ms is about 10 bytes per window, never idle for long.
+    let mut trickle = server.connect();
+    let connected = Instant::now();
+    let closed_after = loop {
+        let _ = trickle.write_all(b"t");
+        let mut echoed = [0];
+        if trickle.read_exact(&mut echoed).is_err() {
+            break connected.elapsed();
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+    assert!(closed_after >= window, "closed after {:?}", closed_after);
+    assert!(closed_after < window * 4, "closed after {:?}", closed_after);
+}
+
+#[test]
+fn broadcast_mode_fans_a_chunk_out_to_every_connected_client() {
+    let read = |client: &mut TcpStream, len| {
+        let mut received = vec![0; len];
+        client.read_exact(&mut received).unwrap();
+        received
+    };
+    let server = TestServer::start(ServerConfig::builder().mode(Mode::Broadcast));
+    // A client gets its own chunks too, which shows it joined the room.
+    let mut alice = server.connect();
+    assert_eq!(round_trip(&mut alice, b"alice here"), b"alice here");
+    let mut bob = server.connect();
+    assert_eq!(round_trip(&mut bob, b"bob here"), b"bob here");
+    assert_eq!(read(&mut alice, 8), b"bob here");
+
+    alice.write_all(b"hello, bob").unwrap();
+    assert_eq!(read(&mut bob, 10), b"hello, bob");
+    assert_eq!(read(&mut alice, 10), b"hello, bob");
+
+    // Once bob leaves, broadcasting carries on without him.
+    drop(bob);
+    eventually("bob to leave the room", || server.stats.active() == 1);
+    assert_eq!(round_trip(&mut alice, b"anyone?"), b"anyone?");
+}
+
+#[test]
+fn self_test_clients_measure_a_nonzero_throughput() {
+    let server = TestServer::start(ServerConfig::builder());
+    let config = BenchConfig {
+        connections: 3,
+        payload_size: 512,
+        duration: Duration::from_millis(300),
+    };
+
+    let report = echo_server_rs::bench::run(server.addr, &config).unwrap();
+
+    assert_eq!(report.connections, 3);
+    assert!(report.round_trips >= 3, "{}", report);
+    assert_eq!(report.bytes, report.round_trips * 512);
+    assert!(report.megabytes_per_second() > 0.0, "{}", report);
+    assert!(report.p50 <= report.p99, "{}", report);
+    assert!(report.elapsed >= config.duration, "{}", report);
+}
+
+#[test]
+fn connection_over_capacity_reads_the_busy_message_before_the_close() {
+    let server = TestServer::start(
+        ServerConfig::builder()
+            .max_connections(1)
+            .busy_message(String::from("ERR server at capacity")),
+    );
+    let mut admitted = server.connect();
+    assert_eq!(round_trip(&mut admitted, b"hello"), b"hello");
+
+    let mut rejected = server.connect();
+    assert_eq!(read_to_end(&mut rejected), b"ERR server at capacity\r\n");
+    assert_eq!(round_trip(&mut admitted, b"still here"), b"still here");
+}
+
+#[test]
+fn duplex_connection_echoes_a_flood_read_at_the_same_time_in_full() {
+    const TOTAL: usize = 8 * 1024 * 1024;
+    let server = TestServer::start(ServerConfig::builder().duplex(true));
+    let client = server.connect();
+    let mut writer = client.try_clone().unwrap();
+
+    // Far more than the socket buffers hold, read back while it is still
+    // being sent, so both directions are busy at once.
+    let payload: Vec<u8> = (0..TOTAL).map(|i| (i % 251) as u8).collect();
+    let flooding = {
+        let payload = payload.clone();
+        thread::spawn(move || {
+            for chunk in payload.chunks(64 * 1024) {
+                writer.write_all(chunk).unwrap();
+            }
+            writer.shutdown(Shutdown::Write).unwrap();
+        })
+    };
+    let mut reader = client;
+    let echoed = read_to_end(&mut reader);
+    flooding.join().unwrap();
+
+    assert_eq!(echoed.len(), TOTAL);
+    assert!(echoed == payload, "the echo differs from what was sent");
+    eventually("the echoed bytes to be counted", || {
+        server.stats.bytes_echoed() == TOTAL as u64
+    });
+}
+
+#[test]
+fn mirror_receives_a_copy_of_everything_the_client_sent() {
+    let upstream = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let server = TestServer::start(ServerConfig::builder().mirror(upstream.local_addr().unwrap()));
+
+    let mut client = server.connect();
+    assert_eq!(round_trip(&mut client, b"hello"), b"hello");
+    assert_eq!(round_trip(&mut client, b" mirror"), b" mirror");
+    drop(client);
+
+    // The mirror connection closes once the client's has ended.
+    let (mut mirrored, _) = upstream.accept().unwrap();
+    mirrored.set_read_timeout(Some(common::TIMEOUT)).unwrap();
+    assert_eq!(read_to_end(&mut mirrored), b"hello mirror");
+}
+
+#[test]
+fn unreachable_mirror_leaves_the_echo_alone() {
+    let closed = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let server = TestServer::start(ServerConfig::builder().mirror(closed));
+
+    let mut client = server.connect();
+    for _ in 0..3 {
+        assert_eq!(round_trip(&mut client, b"hello"), b"hello");
+    }
+}
+
+#[test]
+fn reply_mode_answers_every_chunk_with_the_canned_reply() {
+    let reply = b"\x00\xffOK\r\n";
+    let server = TestServer::start(
+        ServerConfig::builder()
+            .mode(Mode::Reply)
+            .reply(reply.to_vec()),
+    );
+    let mut client = server.connect();
+
+    for input in &[&b"GET / HTTP/1.0\r\n\r\n"[..], b"anything at all"] {
+        client.write_all(input).unwrap();
+        let mut answered = [0; 6];
+        client.read_exact(&mut answered).unwrap();
+        assert_eq!(&answered, reply);
+    }
+}
+
+#[test]
+fn reply_once_answers_only_the_first_chunk() {
+    let server = TestServer::start(
+        ServerConfig::builder()
+            .mode(Mode::Reply)
+            .reply(b"welcome\r\n".to_vec())
+            .reply_once(true),
+    );
+    let mut client = server.connect();
+
+    client.write_all(b"hello").unwrap();
+    let mut greeting = [0; 9];
+    client.read_exact(&mut greeting).unwrap();
+    assert_eq!(&greeting, b"welcome\r\n");
+    client.write_all(b"hello again").unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(read_to_end(&mut client), b"");
+}
+
+#[test]
+fn each_port_echoes_on_its_own_and_a_taken_one_is_skipped() {
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let any: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let config = ServerConfig::builder()
+        .addrs(vec![any, taken.local_addr().unwrap(), any])
+        .build();
+    let server = Server::bind(config).expect("the free ports should still be bound");
+    let addrs = server.local_addrs();
+    assert_eq!(addrs.len(), 2);
+    assert_ne!(addrs[0].port(), addrs[1].port());
+    let server = TestServer::bind(server);
+
+    let mut first = common::connect(addrs[0]);
+    let mut second = common::connect(addrs[1]);
+    assert_eq!(round_trip(&mut first, b"first"), b"first");
+    assert_eq!(round_trip(&mut second, b"second"), b"second");
+    // Closing a connection on one port leaves the other one served.
+    drop(first);
+    eventually("the first connection to close", || {
+        server.stats.closed() == 1
+    });
+    assert_eq!(round_trip(&mut second, b"again"), b"again");
+    assert_eq!(server.stats.accepted(), 2);
+}
+
+#[cfg(unix)]
+#[test]
+fn restarted_server_rebinds_its_port_despite_time_wait() {
+    let first = TestServer::start(ServerConfig::builder().max_connection_bytes(5));
+    let addr = first.addr;
+    // Closing first at the byte limit leaves the server's end in TIME_WAIT.
+    let mut client = first.connect();
+    client.write_all(b"hello").unwrap();
+    assert_eq!(read_to_end(&mut client), b"hello");
+    drop(client);
+    first.stop().unwrap();
+
+    let second = TestServer::bind(
+        Server::bind(ServerConfig::builder().addr(addr).build())
+            .expect("could not rebind the port right away"),
+    );
+    let mut client = second.connect();
+    assert_eq!(round_trip(&mut client, b"hello"), b"hello");
+}
+
+#[test]
+fn echoes_over_ipv6_loopback_and_dual_stack() {
+    let bind = |addr: &str, dual_stack: bool| {
+        let listener = ListenerOptions {
+            dual_stack,
+            ..ListenerOptions::default()
+        };
+        let config = ServerConfig::builder()
+            .addr(addr.parse().unwrap())
+            .listener(listener)
+            .build();
+        Server::bind(config).ok().map(TestServer::bind)
+    };
+    // Nothing to test on a host without IPv6.
+    let v6 = match bind("[::1]:0", false) {
+        Some(server) => server,
+        None => return,
+    };
+    assert!(v6.addr.is_ipv6());
+    let mut client = v6.connect();
+    assert_eq!(round_trip(&mut client, b"hello"), b"hello");
+
+    let dual = bind("[::]:0", true).expect("could not bind a dual-stack listener");
+    for ip in &["::1", "127.0.0.1"] {
+        let addr = SocketAddr::new(ip.parse().unwrap(), dual.addr.port());
+        let mut client = common::connect(addr);
+        assert_eq!(round_trip(&mut client, b"hello"), b"hello");
+    }
+}
+
+#[test]
+fn each_small_line_is_echoed_before_the_next_is_sent() {
+    let server = TestServer::start(ServerConfig::builder());
+    let mut client = server.connect();
+
+    // Blocking on every echo in turn only finishes if the server flushes
+    // each one before waiting for more input.
+    for i in 0..1_000 {
+        let line = format!("line {}\n", i);
+        assert_eq!(round_trip(&mut client, line.as_bytes()), line.as_bytes());
+    }
+}
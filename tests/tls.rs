@@ -0,0 +1,120 @@
+//! Tests serving over TLS, with the test CA in `tests/fixtures`, a
+//! certificate it issued for `localhost` and one it issued to a client.
+
+mod common;
+
+use common::{eventually, read_to_end, TestServer};
+use echo_server_rs::ServerConfig;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name)
+}
+
+fn start() -> TestServer {
+    TestServer::start(ServerConfig::builder().tls(fixture("cert.pem"), fixture("key.pem")))
+}
+
+fn roots() -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(fixture("ca.pem")).unwrap() {
+        roots.add(cert.unwrap()).unwrap();
+    }
+    roots
+}
+
+fn client_config() -> Arc<ClientConfig> {
+    Arc::new(
+        ClientConfig::builder()
+            .with_root_certificates(roots())
+            .with_no_client_auth(),
+    )
+}
+
+// A client presenting the certificate and key in the fixtures named
+// `<name>-cert.pem` and `<name>-key.pem`.
+fn client_config_with_cert(name: &str) -> Arc<ClientConfig> {
+    let certs = CertificateDer::pem_file_iter(fixture(&format!("{}-cert.pem", name)))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    let key = PrivateKeyDer::from_pem_file(fixture(&format!("{}-key.pem", name))).unwrap();
+    Arc::new(
+        ClientConfig::builder()
+            .with_root_certificates(roots())
+            .with_client_auth_cert(certs, key)
+            .unwrap(),
+    )
+}
+
+// Echoes `payload` over a TLS connection to `server` made with `config`.
+fn tls_round_trip(
+    server: &TestServer,
+    config: Arc<ClientConfig>,
+    payload: &[u8],
+) -> io::Result<Vec<u8>> {
+    let name = ServerName::try_from("localhost").unwrap();
+    let connection = ClientConnection::new(config, name).unwrap();
+    let mut client = StreamOwned::new(connection, server.connect());
+    client.write_all(payload)?;
+    let mut echoed = vec![0; payload.len()];
+    client.read_exact(&mut echoed)?;
+    Ok(echoed)
+}
+
+#[test]
+fn bytes_round_trip_encrypted() {
+    let server = start();
+    let name = ServerName::try_from("localhost").unwrap();
+    let connection = ClientConnection::new(client_config(), name).unwrap();
+    let mut client = StreamOwned::new(connection, server.connect());
+
+    client.write_all(b"hello over tls").unwrap();
+    let mut echoed = [0; 14];
+    client.read_exact(&mut echoed).unwrap();
+
+    assert_eq!(&echoed, b"hello over tls");
+    assert!(client.conn.peer_certificates().is_some());
+}
+
+#[test]
+fn failed_handshake_drops_the_connection() {
+    let server = start();
+    let mut client = server.connect();
+
+    client.write_all(b"not a client hello\r\n").unwrap();
+
+    assert!(!read_to_end(&mut client).starts_with(b"not a client hello"));
+    eventually("the handshake failure to be counted", || {
+        server.stats.errors() == 1
+    });
+}
+
+#[test]
+fn client_certificates_are_required_and_verified_against_the_client_ca() {
+    let server = TestServer::start(
+        ServerConfig::builder()
+            .tls(fixture("cert.pem"), fixture("key.pem"))
+            .client_ca(fixture("ca.pem")),
+    );
+
+    let trusted = tls_round_trip(&server, client_config_with_cert("client"), b"hello");
+    assert_eq!(trusted.unwrap(), b"hello");
+
+    // In TLS 1.3 the server only checks the client's certificate after the
+    // client's side of the handshake is done, so the rejection shows up on
+    // reading the echo.
+    assert!(tls_round_trip(&server, client_config(), b"hello").is_err());
+    assert!(tls_round_trip(&server, client_config_with_cert("untrusted"), b"hello").is_err());
+    eventually("both rejected handshakes to be counted", || {
+        server.stats.errors() == 2
+    });
+}
@@ -0,0 +1,37 @@
+//! Tests of `Mode::WebSocket` against an independent client implementation.
+
+mod common;
+
+use common::TestServer;
+use echo_server_rs::{Mode, ServerConfig};
+use tungstenite::{Error, Message};
+
+#[test]
+fn text_message_echoes_back_and_close_is_answered() {
+    let server = TestServer::start(ServerConfig::builder().mode(Mode::WebSocket));
+    let url = format!("ws://{}/", server.addr);
+    let (mut socket, _) = tungstenite::client(url.as_str(), server.connect()).unwrap();
+
+    socket.send(Message::text("hello over websocket")).unwrap();
+    assert_eq!(
+        socket.read().unwrap(),
+        Message::text("hello over websocket")
+    );
+
+    socket
+        .send(Message::Ping(b"are you there".to_vec()))
+        .unwrap();
+    assert_eq!(
+        socket.read().unwrap(),
+        Message::Pong(b"are you there".to_vec())
+    );
+
+    socket.close(None).unwrap();
+    loop {
+        match socket.read() {
+            Ok(Message::Close(_)) => continue,
+            Err(Error::ConnectionClosed) => break,
+            other => panic!("expected the close to be answered, got {:?}", other),
+        }
+    }
+}